@@ -4,6 +4,13 @@
 //! alternative to flatbuffers using a very simple list of int32s to lay out
 //! messages. The first i32 is used to determine if a message a flatbuffer
 //! message or a "minimal" message.
+//!
+//! Requests are three i32s: promise id, argument and a zero. Responses start
+//! with a 16 byte header: promise id, argument and the result as a
+//! little-endian i64 split into two i32s, so byte counts and offsets beyond
+//! 2 GiB are not truncated. For errors the argument is -1, the third i32 is
+//! the length of the error class and the header is followed by the class and
+//! the message.
 use crate::errors::get_error_class;
 use deno_core::Buf;
 use deno_core::CoreIsolateState;
@@ -13,13 +20,11 @@ use deno_core::ZeroCopyBuf;
 use futures::future::FutureExt;
 use std::future::Future;
 use std::iter::repeat;
-use std::mem::size_of_val;
 use std::pin::Pin;
-use std::slice;
 
 pub enum MinimalOp {
-  Sync(Result<i32, ErrBox>),
-  Async(Pin<Box<dyn Future<Output = Result<i32, ErrBox>>>>),
+  Sync(Result<i64, ErrBox>),
+  Async(Pin<Box<dyn Future<Output = Result<i64, ErrBox>>>>),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -27,18 +32,31 @@ pub enum MinimalOp {
 pub struct Record {
   pub promise_id: i32,
   pub arg: i32,
-  pub result: i32,
+  pub result: i64,
 }
 
 impl Into<Buf> for Record {
   fn into(self) -> Buf {
-    let vec = vec![self.promise_id, self.arg, self.result];
-    let buf32 = vec.into_boxed_slice();
-    let ptr = Box::into_raw(buf32) as *mut [u8; 3 * 4];
-    unsafe { Box::from_raw(ptr) }
+    let mut buf = Vec::with_capacity(16);
+    buf.extend_from_slice(&self.promise_id.to_le_bytes());
+    buf.extend_from_slice(&self.arg.to_le_bytes());
+    buf.extend_from_slice(&self.result.to_le_bytes());
+    buf.into_boxed_slice()
   }
 }
 
+#[test]
+fn test_record() {
+  let record = Record {
+    promise_id: 1,
+    arg: 3,
+    result: (5 << 32) + 4,
+  };
+  let buf: Buf = record.into();
+  let expected = vec![1, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0];
+  assert_eq!(buf, expected.into_boxed_slice());
+}
+
 pub struct ErrorRecord {
   pub promise_id: i32,
   pub arg: i32,
@@ -57,19 +75,16 @@ impl Into<Buf> for ErrorRecord {
       error_message,
       ..
     } = self;
-    let header_i32 = [promise_id, arg, error_len];
-    let header_u8 = unsafe {
-      slice::from_raw_parts(
-        &header_i32 as *const _ as *const u8,
-        size_of_val(&header_i32),
-      )
-    };
+    let mut header = Vec::with_capacity(16);
+    header.extend_from_slice(&promise_id.to_le_bytes());
+    header.extend_from_slice(&arg.to_le_bytes());
+    header.extend_from_slice(&error_len.to_le_bytes());
+    header.extend_from_slice(&0i32.to_le_bytes());
     let padded_len =
-      (header_u8.len() + error_class.len() + error_message.len() + 3usize)
+      (header.len() + error_class.len() + error_message.len() + 3usize)
         & !3usize;
-    header_u8
-      .iter()
-      .cloned()
+    header
+      .into_iter()
       .chain(error_class.iter().cloned())
       .chain(error_message.into_iter())
       .chain(repeat(b' '))
@@ -81,8 +96,8 @@ impl Into<Buf> for ErrorRecord {
 #[test]
 fn test_error_record() {
   let expected = vec![
-    1, 0, 0, 0, 255, 255, 255, 255, 11, 0, 0, 0, 66, 97, 100, 82, 101, 115,
-    111, 117, 114, 99, 101, 69, 114, 114, 111, 114,
+    1, 0, 0, 0, 255, 255, 255, 255, 11, 0, 0, 0, 0, 0, 0, 0, 66, 97, 100, 82,
+    101, 115, 111, 117, 114, 99, 101, 69, 114, 114, 111, 114,
   ];
   let err_record = ErrorRecord {
     promise_id: 1,
@@ -112,7 +127,7 @@ pub fn parse_min_record(bytes: &[u8]) -> Option<Record> {
   Some(Record {
     promise_id: ints[0],
     arg: ints[1],
-    result: ints[2],
+    result: ints[2] as i64,
   })
 }
 
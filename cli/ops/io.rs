@@ -259,7 +259,7 @@ pub fn op_read(
           use std::io::Read;
           std_file
             .read(&mut zero_copy[0])
-            .map(|n: usize| n as i32)
+            .map(|n: usize| n as i64)
             .map_err(ErrBox::from)
         }
         Err(_) => {
@@ -290,7 +290,7 @@ pub fn op_read(
             return Poll::Pending;
           }
         }?;
-        Poll::Ready(Ok(nread as i32))
+        Poll::Ready(Ok(nread as i64))
       })
       .boxed_local(),
     )
@@ -380,7 +380,7 @@ pub fn op_write(
           use std::io::Write;
           std_file
             .write(&zero_copy[0])
-            .map(|nwritten: usize| nwritten as i32)
+            .map(|nwritten: usize| nwritten as i64)
             .map_err(ErrBox::from)
         }
         Err(_) => {
@@ -415,7 +415,7 @@ pub fn op_write(
         })
         .await?;
 
-        Ok(nwritten as i32)
+        Ok(nwritten as i64)
       }
       .boxed_local(),
     )
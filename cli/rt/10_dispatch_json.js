@@ -19,14 +19,6 @@
     return core.encode(JSON.stringify(args));
   }
 
-  function unwrapResponse(res) {
-    if (res.err != null) {
-      throw new (core.getErrorClass(res.err.className))(res.err.message);
-    }
-    util.assert(res.ok != null);
-    return res.ok;
-  }
-
   function asyncMsgFromRust(resUi8) {
     const res = decode(resUi8);
     util.assert(res.promiseId != null);
@@ -44,11 +36,13 @@
   ) {
     util.log("sendSync", opName);
     const argsUi8 = encode(args);
-    const resUi8 = core.dispatchByName(opName, argsUi8, ...zeroCopy);
+    const resUi8 = core.syncResponseBuf(
+      core.dispatchByName(opName, argsUi8, ...zeroCopy),
+    );
     util.assert(resUi8 != null);
     const res = decode(resUi8);
     util.assert(res.promiseId == null);
-    return unwrapResponse(res);
+    return core.unwrapOpResult(res);
   }
 
   async function sendAsync(
@@ -61,7 +55,9 @@
     args = Object.assign(args, { promiseId });
     const promise = util.createResolvable();
     const argsUi8 = encode(args);
-    const buf = core.dispatchByName(opName, argsUi8, ...zeroCopy);
+    const buf = core.syncResponseBuf(
+      core.dispatchByName(opName, argsUi8, ...zeroCopy),
+    );
     if (buf != null) {
       // Sync result.
       const res = decode(buf);
@@ -72,7 +68,7 @@
     }
 
     const res = await promise;
-    return unwrapResponse(res);
+    return core.unwrapOpResult(res);
   }
 
   window.__bootstrap.dispatchJson = {
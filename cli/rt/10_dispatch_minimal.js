@@ -19,16 +19,14 @@
   }
 
   function recordFromBufMinimal(ui8) {
-    const headerLen = 12;
-    const header = ui8.subarray(0, headerLen);
-    const buf32 = new Int32Array(
-      header.buffer,
-      header.byteOffset,
-      header.byteLength / 4,
-    );
-    const promiseId = buf32[0];
-    const arg = buf32[1];
-    const result = buf32[2];
+    const headerLen = 16;
+    const header = new DataView(ui8.buffer, ui8.byteOffset, headerLen);
+    const promiseId = header.getInt32(0, true);
+    const arg = header.getInt32(4, true);
+    // The result is an i64. Reading it as two halves keeps it a number, which
+    // is exact for every value below 2 ** 53.
+    const result = header.getUint32(8, true) +
+      header.getInt32(12, true) * 2 ** 32;
     let err;
 
     if (arg < 0) {
@@ -36,7 +34,7 @@
         className: decoder.decode(ui8.subarray(headerLen, headerLen + result)),
         message: decoder.decode(ui8.subarray(headerLen + result)),
       };
-    } else if (ui8.length != 12) {
+    } else if (ui8.length != headerLen) {
       throw new TypeError("Malformed response message");
     }
 
@@ -78,7 +76,9 @@
     scratch32[1] = arg;
     scratch32[2] = 0; // result
     const promise = util.createResolvable();
-    const buf = core.dispatchByName(opName, scratchBytes, zeroCopy);
+    const buf = core.syncResponseBuf(
+      core.dispatchByName(opName, scratchBytes, zeroCopy),
+    );
     if (buf != null) {
       const record = recordFromBufMinimal(buf);
       // Sync result.
@@ -95,7 +95,9 @@
   function sendSync(opName, arg, zeroCopy) {
     scratch32[0] = 0; // promiseId 0 indicates sync
     scratch32[1] = arg;
-    const res = core.dispatchByName(opName, scratchBytes, zeroCopy);
+    const res = core.syncResponseBuf(
+      core.dispatchByName(opName, scratchBytes, zeroCopy),
+    );
     const resRecord = recordFromBufMinimal(res);
     return unwrapResponse(resRecord);
   }
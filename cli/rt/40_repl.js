@@ -17,11 +17,11 @@
   }
 
   function replLog(...args) {
-    core.print(inspectArgs(args) + "\n");
+    core.print(inspectArgs(args), false, true);
   }
 
   function replError(...args) {
-    core.print(inspectArgs(args) + "\n", true);
+    core.print(inspectArgs(args), true, true);
   }
 
   // Error messages that allow users to continue input
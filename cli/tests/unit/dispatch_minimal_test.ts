@@ -36,14 +36,15 @@ declare global {
 unitTest(function malformedMinimalControlBuffer(): void {
   const readOpId = Deno.core.ops()["op_read"];
   const res = Deno.core.send(readOpId, new Uint8Array([1, 2, 3, 4, 5]));
-  const header = res.slice(0, 12);
+  const headerLen = 16;
+  const header = res.slice(0, headerLen);
   const buf32 = new Int32Array(
     header.buffer,
     header.byteOffset,
     header.byteLength / 4,
   );
   const arg = buf32[1];
-  const codeAndMessage = new TextDecoder().decode(res.slice(12)).trim();
+  const codeAndMessage = new TextDecoder().decode(res.slice(headerLen)).trim();
   assert(arg < 0);
   assertEquals(codeAndMessage, "TypeErrorUnparsable control buffer");
 });
@@ -1,9 +1,22 @@
 // Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
 
+use crate::core_isolate::HeapStats;
+use crate::core_isolate::RealmId;
+use crate::core_isolate::ScriptOriginOptions;
+use crate::core_isolate::DEFAULT_V8_STACK_SIZE;
+use crate::core_isolate::MAIN_REALM;
+use crate::errors::get_property;
+use crate::extensions::Extension;
+use crate::flags::is_gc_exposed;
+use crate::object_graph;
+use crate::print;
+use crate::shared_queue::SharedQueue;
 use crate::CoreIsolate;
 use crate::CoreIsolateState;
 use crate::EsIsolate;
+use crate::EsIsolateState;
 use crate::JSError;
+use crate::OpId;
 use crate::ZeroCopyBuf;
 
 use rusty_v8 as v8;
@@ -11,101 +24,205 @@ use v8::MapFnTo;
 
 use smallvec::SmallVec;
 use std::cell::Cell;
+use std::cell::RefCell;
 use std::convert::TryFrom;
 use std::option::Option;
+use std::rc::Rc;
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 use url::Url;
 
 lazy_static! {
   pub static ref EXTERNAL_REFERENCES: v8::ExternalReferences =
-    v8::ExternalReferences::new(&[
-      v8::ExternalReference {
-        function: print.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: recv.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: send.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: set_macrotask_callback.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: eval_context.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: format_error.map_fn_to()
-      },
-      v8::ExternalReference {
-        getter: shared_getter.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: queue_microtask.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: encode.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: decode.map_fn_to()
-      },
-      v8::ExternalReference {
-        function: get_promise_details.map_fn_to(),
-      }
-    ]);
+    v8::ExternalReferences::new(&builtin_external_references());
+}
+
+fn builtin_external_references() -> Vec<v8::ExternalReference<'static>> {
+  vec![
+    v8::ExternalReference {
+      function: print.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: recv.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: set_op_response_handler.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: send.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: set_macrotask_callback.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: set_idle_callback.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: set_unhandled_rejection_callback.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: eval_context.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: format_error.map_fn_to(),
+    },
+    v8::ExternalReference {
+      getter: shared_getter.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: queue_microtask.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: encode.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: decode.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: get_promise_details.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: context_count.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: view_bounds.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: bench_context_switch.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: op_meta.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: minor_gc.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: major_gc.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: low_memory_notification.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: op_latency.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: has_cycle.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: set_task_hooks.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: module_dependencies.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: deep_freeze.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: stack_limit.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: drain_op_metrics.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: describe_thrown.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: import_meta_resolve.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: heap_stats.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: metrics.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: resources.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: modules.map_fn_to(),
+    },
+    v8::ExternalReference {
+      function: apply_source_map.map_fn_to(),
+    },
+  ]
+}
+
+/// Returns the external references of the built-in bindings followed by
+/// those of `extensions`, or `None` if there are no extensions and the
+/// shared `EXTERNAL_REFERENCES` can be used.
+pub fn external_references(
+  extensions: &[Extension],
+) -> Option<Box<v8::ExternalReferences>> {
+  if extensions.is_empty() {
+    return None;
+  }
+  let mut references = builtin_external_references();
+  for extension in extensions {
+    references.extend(extension.external_references());
+  }
+  Some(Box::new(v8::ExternalReferences::new(&references)))
 }
 
 pub fn script_origin<'a>(
   s: &mut v8::HandleScope<'a>,
   resource_name: v8::Local<'a, v8::String>,
+  options: &ScriptOriginOptions,
 ) -> v8::ScriptOrigin<'a> {
-  let resource_line_offset = v8::Integer::new(s, 0);
-  let resource_column_offset = v8::Integer::new(s, 0);
-  let resource_is_shared_cross_origin = v8::Boolean::new(s, false);
-  let script_id = v8::Integer::new(s, 123);
-  let source_map_url = v8::String::new(s, "").unwrap();
-  let resource_is_opaque = v8::Boolean::new(s, true);
-  let is_wasm = v8::Boolean::new(s, false);
-  let is_module = v8::Boolean::new(s, false);
-  v8::ScriptOrigin::new(
-    resource_name.into(),
-    resource_line_offset,
-    resource_column_offset,
-    resource_is_shared_cross_origin,
-    script_id,
-    source_map_url.into(),
-    resource_is_opaque,
-    is_wasm,
-    is_module,
-  )
+  new_script_origin(s, resource_name, options, false)
 }
 
 pub fn module_origin<'a>(
   s: &mut v8::HandleScope<'a>,
   resource_name: v8::Local<'a, v8::String>,
+  options: &ScriptOriginOptions,
+) -> v8::ScriptOrigin<'a> {
+  new_script_origin(s, resource_name, options, true)
+}
+
+/// Ids given to V8 for every script and module compiled by core, unique
+/// within the process.
+static NEXT_SCRIPT_ID: AtomicI32 = AtomicI32::new(1);
+
+fn new_script_origin<'a>(
+  s: &mut v8::HandleScope<'a>,
+  resource_name: v8::Local<'a, v8::String>,
+  options: &ScriptOriginOptions,
+  is_module: bool,
 ) -> v8::ScriptOrigin<'a> {
-  let resource_line_offset = v8::Integer::new(s, 0);
-  let resource_column_offset = v8::Integer::new(s, 0);
+  let resource_line_offset = v8::Integer::new(s, options.line_offset);
+  let resource_column_offset = v8::Integer::new(s, options.column_offset);
   let resource_is_shared_cross_origin = v8::Boolean::new(s, false);
-  let script_id = v8::Integer::new(s, 123);
-  let source_map_url = v8::String::new(s, "").unwrap();
-  let resource_is_opaque = v8::Boolean::new(s, true);
+  let script_id =
+    v8::Integer::new(s, NEXT_SCRIPT_ID.fetch_add(1, Ordering::Relaxed));
+  let source_map_url: v8::Local<v8::Value> = match &options.source_map_url {
+    Some(url) => v8::String::new(s, url).unwrap().into(),
+    None => v8::undefined(s).into(),
+  };
+  let resource_is_opaque = v8::Boolean::new(s, false);
   let is_wasm = v8::Boolean::new(s, false);
-  let is_module = v8::Boolean::new(s, true);
+  let is_module = v8::Boolean::new(s, is_module);
   v8::ScriptOrigin::new(
     resource_name.into(),
     resource_line_offset,
     resource_column_offset,
     resource_is_shared_cross_origin,
     script_id,
-    source_map_url.into(),
+    source_map_url,
     resource_is_opaque,
     is_wasm,
     is_module,
   )
 }
 
+/// Creates a context with the `Deno.core` bindings installed. For the global
+/// context `realm` is `None`; a child realm passes its id, which its
+/// `Deno.core.send()` and `Deno.core.recv()` carry so op responses find their
+/// way back to it, and the shared queue exposed as its `Deno.core.shared`.
 pub fn initialize_context<'s>(
   scope: &mut v8::HandleScope<'s, ()>,
+  extensions: &[Extension],
+  realm: Option<(RealmId, &mut SharedQueue)>,
 ) -> v8::Local<'s, v8::Context> {
   let scope = &mut v8::EscapableHandleScope::new(scope);
 
@@ -128,13 +245,41 @@ pub fn initialize_context<'s>(
   core_val.set(scope, print_key.into(), print_val.into());
 
   let recv_key = v8::String::new(scope, "recv").unwrap();
-  let recv_tmpl = v8::FunctionTemplate::new(scope, recv);
-  let recv_val = recv_tmpl.get_function(scope).unwrap();
+  let recv_val = match &realm {
+    Some((realm_id, _)) => {
+      let realm_id = v8::Integer::new_from_unsigned(scope, *realm_id);
+      v8::Function::new_with_data(scope, realm_id.into(), recv).unwrap()
+    }
+    None => {
+      let recv_tmpl = v8::FunctionTemplate::new(scope, recv);
+      recv_tmpl.get_function(scope).unwrap()
+    }
+  };
   core_val.set(scope, recv_key.into(), recv_val.into());
 
+  let set_op_response_handler_key =
+    v8::String::new(scope, "setOpResponseHandler").unwrap();
+  let set_op_response_handler_tmpl =
+    v8::FunctionTemplate::new(scope, set_op_response_handler);
+  let set_op_response_handler_val =
+    set_op_response_handler_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    set_op_response_handler_key.into(),
+    set_op_response_handler_val.into(),
+  );
+
   let send_key = v8::String::new(scope, "send").unwrap();
-  let send_tmpl = v8::FunctionTemplate::new(scope, send);
-  let send_val = send_tmpl.get_function(scope).unwrap();
+  let send_val = match &realm {
+    Some((realm_id, _)) => {
+      let realm_id = v8::Integer::new_from_unsigned(scope, *realm_id);
+      v8::Function::new_with_data(scope, realm_id.into(), send).unwrap()
+    }
+    None => {
+      let send_tmpl = v8::FunctionTemplate::new(scope, send);
+      send_tmpl.get_function(scope).unwrap()
+    }
+  };
   core_val.set(scope, send_key.into(), send_val.into());
 
   let set_macrotask_callback_key =
@@ -149,6 +294,32 @@ pub fn initialize_context<'s>(
     set_macrotask_callback_val.into(),
   );
 
+  let set_idle_callback_key =
+    v8::String::new(scope, "setIdleCallback").unwrap();
+  let set_idle_callback_tmpl =
+    v8::FunctionTemplate::new(scope, set_idle_callback);
+  let set_idle_callback_val =
+    set_idle_callback_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    set_idle_callback_key.into(),
+    set_idle_callback_val.into(),
+  );
+
+  let set_unhandled_rejection_callback_key =
+    v8::String::new(scope, "setUnhandledRejectionCallback").unwrap();
+  let set_unhandled_rejection_callback_tmpl =
+    v8::FunctionTemplate::new(scope, set_unhandled_rejection_callback);
+  let set_unhandled_rejection_callback_val =
+    set_unhandled_rejection_callback_tmpl
+      .get_function(scope)
+      .unwrap();
+  core_val.set(
+    scope,
+    set_unhandled_rejection_callback_key.into(),
+    set_unhandled_rejection_callback_val.into(),
+  );
+
   let eval_context_key = v8::String::new(scope, "evalContext").unwrap();
   let eval_context_tmpl = v8::FunctionTemplate::new(scope, eval_context);
   let eval_context_val = eval_context_tmpl.get_function(scope).unwrap();
@@ -181,8 +352,154 @@ pub fn initialize_context<'s>(
     get_promise_details_val.into(),
   );
 
+  let context_count_key = v8::String::new(scope, "contextCount").unwrap();
+  let context_count_tmpl = v8::FunctionTemplate::new(scope, context_count);
+  let context_count_val = context_count_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, context_count_key.into(), context_count_val.into());
+
+  let view_bounds_key = v8::String::new(scope, "viewBounds").unwrap();
+  let view_bounds_tmpl = v8::FunctionTemplate::new(scope, view_bounds);
+  let view_bounds_val = view_bounds_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, view_bounds_key.into(), view_bounds_val.into());
+
+  let bench_context_switch_key =
+    v8::String::new(scope, "benchContextSwitch").unwrap();
+  let bench_context_switch_tmpl =
+    v8::FunctionTemplate::new(scope, bench_context_switch);
+  let bench_context_switch_val =
+    bench_context_switch_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    bench_context_switch_key.into(),
+    bench_context_switch_val.into(),
+  );
+
+  let op_meta_key = v8::String::new(scope, "opMeta").unwrap();
+  let op_meta_tmpl = v8::FunctionTemplate::new(scope, op_meta);
+  let op_meta_val = op_meta_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, op_meta_key.into(), op_meta_val.into());
+
+  let minor_gc_key = v8::String::new(scope, "minorGc").unwrap();
+  let minor_gc_tmpl = v8::FunctionTemplate::new(scope, minor_gc);
+  let minor_gc_val = minor_gc_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, minor_gc_key.into(), minor_gc_val.into());
+
+  let major_gc_key = v8::String::new(scope, "majorGc").unwrap();
+  let major_gc_tmpl = v8::FunctionTemplate::new(scope, major_gc);
+  let major_gc_val = major_gc_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, major_gc_key.into(), major_gc_val.into());
+
+  let low_memory_notification_key =
+    v8::String::new(scope, "lowMemoryNotification").unwrap();
+  let low_memory_notification_tmpl =
+    v8::FunctionTemplate::new(scope, low_memory_notification);
+  let low_memory_notification_val =
+    low_memory_notification_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    low_memory_notification_key.into(),
+    low_memory_notification_val.into(),
+  );
+
+  let op_latency_key = v8::String::new(scope, "opLatency").unwrap();
+  let op_latency_tmpl = v8::FunctionTemplate::new(scope, op_latency);
+  let op_latency_val = op_latency_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, op_latency_key.into(), op_latency_val.into());
+
+  let has_cycle_key = v8::String::new(scope, "hasCycle").unwrap();
+  let has_cycle_tmpl = v8::FunctionTemplate::new(scope, has_cycle);
+  let has_cycle_val = has_cycle_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, has_cycle_key.into(), has_cycle_val.into());
+
+  let set_task_hooks_key = v8::String::new(scope, "setTaskHooks").unwrap();
+  let set_task_hooks_tmpl = v8::FunctionTemplate::new(scope, set_task_hooks);
+  let set_task_hooks_val = set_task_hooks_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, set_task_hooks_key.into(), set_task_hooks_val.into());
+
+  let module_dependencies_key =
+    v8::String::new(scope, "moduleDependencies").unwrap();
+  let module_dependencies_tmpl =
+    v8::FunctionTemplate::new(scope, module_dependencies);
+  let module_dependencies_val =
+    module_dependencies_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    module_dependencies_key.into(),
+    module_dependencies_val.into(),
+  );
+
+  let modules_key = v8::String::new(scope, "modules").unwrap();
+  let modules_tmpl = v8::FunctionTemplate::new(scope, modules);
+  let modules_val = modules_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, modules_key.into(), modules_val.into());
+
+  let deep_freeze_key = v8::String::new(scope, "deepFreeze").unwrap();
+  let deep_freeze_tmpl = v8::FunctionTemplate::new(scope, deep_freeze);
+  let deep_freeze_val = deep_freeze_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, deep_freeze_key.into(), deep_freeze_val.into());
+
+  let stack_limit_key = v8::String::new(scope, "stackLimit").unwrap();
+  let stack_limit_tmpl = v8::FunctionTemplate::new(scope, stack_limit);
+  let stack_limit_val = stack_limit_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, stack_limit_key.into(), stack_limit_val.into());
+
+  let drain_op_metrics_key = v8::String::new(scope, "drainOpMetrics").unwrap();
+  let drain_op_metrics_tmpl =
+    v8::FunctionTemplate::new(scope, drain_op_metrics);
+  let drain_op_metrics_val = drain_op_metrics_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    drain_op_metrics_key.into(),
+    drain_op_metrics_val.into(),
+  );
+
+  let metrics_key = v8::String::new(scope, "metrics").unwrap();
+  let metrics_tmpl = v8::FunctionTemplate::new(scope, metrics);
+  let metrics_val = metrics_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, metrics_key.into(), metrics_val.into());
+
+  let resources_key = v8::String::new(scope, "resources").unwrap();
+  let resources_tmpl = v8::FunctionTemplate::new(scope, resources);
+  let resources_val = resources_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, resources_key.into(), resources_val.into());
+
+  let apply_source_map_key = v8::String::new(scope, "applySourceMap").unwrap();
+  let apply_source_map_tmpl =
+    v8::FunctionTemplate::new(scope, apply_source_map);
+  let apply_source_map_val = apply_source_map_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    apply_source_map_key.into(),
+    apply_source_map_val.into(),
+  );
+
+  let heap_stats_key = v8::String::new(scope, "heapStats").unwrap();
+  let heap_stats_tmpl = v8::FunctionTemplate::new(scope, heap_stats);
+  let heap_stats_val = heap_stats_tmpl.get_function(scope).unwrap();
+  core_val.set(scope, heap_stats_key.into(), heap_stats_val.into());
+
+  let describe_thrown_key = v8::String::new(scope, "describeThrown").unwrap();
+  let describe_thrown_tmpl = v8::FunctionTemplate::new(scope, describe_thrown);
+  let describe_thrown_val = describe_thrown_tmpl.get_function(scope).unwrap();
+  core_val.set(
+    scope,
+    describe_thrown_key.into(),
+    describe_thrown_val.into(),
+  );
+
   let shared_key = v8::String::new(scope, "shared").unwrap();
-  core_val.set_accessor(scope, shared_key.into(), shared_getter);
+  match realm {
+    Some((_, shared)) => {
+      let shared_ab = v8::SharedArrayBuffer::with_backing_store(
+        scope,
+        shared.get_backing_store(),
+      );
+      core_val.set(scope, shared_key.into(), shared_ab.into());
+    }
+    None => {
+      core_val.set_accessor(scope, shared_key.into(), shared_getter);
+    }
+  }
 
   // Direct bindings on `window`.
   let queue_microtask_key = v8::String::new(scope, "queueMicrotask").unwrap();
@@ -194,6 +511,10 @@ pub fn initialize_context<'s>(
     queue_microtask_val.into(),
   );
 
+  for extension in extensions {
+    extension.install(scope, core_val);
+  }
+
   scope.escape(context)
 }
 
@@ -217,29 +538,57 @@ pub extern "C" fn host_import_module_dynamically_callback(
 ) -> *mut v8::Promise {
   let scope = &mut unsafe { v8::CallbackScope::new(context) };
 
-  // NOTE(bartlomieju): will crash for non-UTF-8 specifier
-  let specifier_str = specifier
-    .to_string(scope)
-    .unwrap()
-    .to_rust_string_lossy(scope);
+  let resolver = v8::PromiseResolver::new(scope).unwrap();
+  let promise = resolver.get_promise(scope);
+
+  let specifier_str = match decode_specifier(scope, specifier.into()) {
+    Ok(specifier_str) => specifier_str,
+    Err(message) => {
+      reject_with_type_error(scope, resolver, &message);
+      return &*promise as *const _ as *mut _;
+    }
+  };
   let referrer_name = referrer.get_resource_name();
-  let referrer_name_str = referrer_name
-    .to_string(scope)
-    .unwrap()
-    .to_rust_string_lossy(scope);
+  let referrer_name_str = match decode_specifier(scope, referrer_name) {
+    Ok(referrer_name_str) => referrer_name_str,
+    Err(message) => {
+      let message = format!("Invalid referrer: {}", message);
+      reject_with_type_error(scope, resolver, &message);
+      return &*promise as *const _ as *mut _;
+    }
+  };
 
-  // TODO(ry) I'm not sure what HostDefinedOptions is for or if we're ever going
-  // to use it. For now we check that it is not used. This check may need to be
-  // changed in the future.
-  let host_defined_options = referrer.get_host_defined_options();
-  assert_eq!(host_defined_options.length(), 0);
+  // HostDefinedOptions are never set by deno_core, but scripts compiled by
+  // embedders through rusty_v8 directly may carry them. They carry nothing
+  // dynamic imports depend on, so they are ignored.
 
-  let resolver = v8::PromiseResolver::new(scope).unwrap();
-  let promise = resolver.get_promise(scope);
+  // Dynamically imported modules are loaded into the global context's module
+  // map, which child realms must not reach.
+  let global_context = {
+    let state_rc = CoreIsolate::state(scope);
+    let state = state_rc.borrow();
+    v8::Local::new(scope, state.global_context.as_ref().unwrap())
+  };
+  if context != global_context {
+    let message = format!(
+      "Cannot import \"{}\" from \"{}\": dynamic import is not supported in child realms",
+      specifier_str, referrer_name_str
+    );
+    reject_with_type_error(scope, resolver, &message);
+    return &*promise as *const _ as *mut _;
+  }
+
+  let state_rc = EsIsolate::state(scope);
+  let policy_result = state_rc
+    .borrow()
+    .check_dyn_import(&specifier_str, &referrer_name_str);
+  if let Err(message) = policy_result {
+    reject_with_type_error(scope, resolver, &message);
+    return &*promise as *const _ as *mut _;
+  }
 
   let resolver_handle = v8::Global::new(scope, resolver);
   {
-    let state_rc = EsIsolate::state(scope);
     let mut state = state_rc.borrow_mut();
     state.dyn_import_cb(resolver_handle, &specifier_str, &referrer_name_str);
   }
@@ -247,6 +596,37 @@ pub extern "C" fn host_import_module_dynamically_callback(
   &*promise as *const _ as *mut _
 }
 
+fn reject_with_type_error(
+  scope: &mut v8::HandleScope,
+  resolver: v8::Local<v8::PromiseResolver>,
+  message: &str,
+) {
+  let message = v8::String::new(scope, message).unwrap();
+  let exception = v8::Exception::type_error(scope, message);
+  resolver.reject(scope, exception).unwrap();
+}
+
+/// Converts a module specifier or referrer name to a Rust string. Unlike
+/// `to_rust_string_lossy()`, strings that are not valid UTF-16 (e.g. containing
+/// lone surrogates) are rejected instead of being silently mangled.
+fn decode_specifier(
+  scope: &mut v8::HandleScope,
+  value: v8::Local<v8::Value>,
+) -> Result<String, String> {
+  let string = match value.to_string(scope) {
+    Some(string) => string,
+    None => return Err("Specifier could not be converted to a string".into()),
+  };
+  let mut buf = vec![0u16; string.length()];
+  string.write(scope, &mut buf, 0, v8::WriteOptions::NO_NULL_TERMINATION);
+  String::from_utf16(&buf).map_err(|_| {
+    format!(
+      "Invalid module specifier \"{}\": not valid UTF-16",
+      String::from_utf16_lossy(&buf)
+    )
+  })
+}
+
 pub extern "C" fn host_initialize_import_meta_object_callback(
   context: v8::Local<v8::Context>,
   module: v8::Local<v8::Module>,
@@ -259,7 +639,7 @@ pub extern "C" fn host_initialize_import_meta_object_callback(
   let id = module.get_identity_hash();
   assert_ne!(id, 0);
 
-  let info = state.modules.get_info(id).expect("Module not found");
+  let info = state.modules_of(id).get_info(id).expect("Module not found");
 
   let url_key = v8::String::new(scope, "url").unwrap();
   let url_val = v8::String::new(scope, &info.name).unwrap();
@@ -268,6 +648,40 @@ pub extern "C" fn host_initialize_import_meta_object_callback(
   let main_key = v8::String::new(scope, "main").unwrap();
   let main_val = v8::Boolean::new(scope, info.main);
   meta.create_data_property(scope, main_key.into(), main_val.into());
+
+  // The referrer's module id is passed to import_meta_resolve() as the
+  // function's data, so it keeps working when detached from import.meta.
+  let resolve_key = v8::String::new(scope, "resolve").unwrap();
+  let referrer_id = v8::Integer::new(scope, id);
+  let resolve_val =
+    v8::Function::new_with_data(scope, referrer_id.into(), import_meta_resolve)
+      .unwrap();
+  meta.create_data_property(scope, resolve_key.into(), resolve_val.into());
+}
+
+fn import_meta_resolve(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let referrer_id = v8::Local::<v8::Int32>::try_from(args.data().unwrap())
+    .unwrap()
+    .value();
+
+  let specifier = match decode_specifier(scope, args.get(0)) {
+    Ok(specifier) => specifier,
+    Err(msg) => return throw_type_error(scope, msg),
+  };
+
+  let state_rc = EsIsolate::state(scope);
+  let result = state_rc.borrow().resolve(&specifier, referrer_id);
+  match result {
+    Ok(resolved) => {
+      let resolved = v8::String::new(scope, resolved.as_str()).unwrap();
+      rv.set(resolved.into())
+    }
+    Err(err) => throw_type_error(scope, err.to_string()),
+  }
 }
 
 pub extern "C" fn promise_reject_callback(message: v8::PromiseRejectMessage) {
@@ -282,10 +696,11 @@ pub extern "C" fn promise_reject_callback(message: v8::PromiseRejectMessage) {
   match message.get_event() {
     v8::PromiseRejectEvent::PromiseRejectWithNoHandler => {
       let error = message.get_value();
+      let promise_global = v8::Global::new(scope, promise);
       let error_global = v8::Global::new(scope, error);
       state
         .pending_promise_exceptions
-        .insert(promise_id, error_global);
+        .insert(promise_id, (promise_global, error_global));
     }
     v8::PromiseRejectEvent::PromiseHandlerAddedAfterReject => {
       state.pending_promise_exceptions.remove(&promise_id);
@@ -320,33 +735,70 @@ pub(crate) unsafe fn get_backing_store_slice_mut(
   &mut *bytes
 }
 
+/// `Deno.core.print(msg, isErr = false, newline = false)` writes `msg` to
+/// stdout, or to stderr if `isErr` is true, followed by a newline if
+/// `newline` is true. `msg` may also be an array, in which case its elements
+/// are converted to strings one by one and written separated by spaces; an
+/// array is not converted as a whole, so `["a", "b"]` prints as `a b` rather
+/// than `a,b`. If reading or converting a value throws, nothing is written
+/// and the exception is rethrown to the caller.
 fn print(
   scope: &mut v8::HandleScope,
   args: v8::FunctionCallbackArguments,
   _rv: v8::ReturnValue,
 ) {
   let arg_len = args.length();
-  assert!(arg_len >= 0 && arg_len <= 2);
+  if !(1..=3).contains(&arg_len) {
+    return throw_type_error(scope, "Deno.core.print() takes 1 to 3 arguments");
+  }
 
-  let obj = args.get(0);
-  let is_err_arg = args.get(1);
+  let is_err = args.get(1).is_true();
+  let newline = args.get(2).is_true();
 
-  let mut is_err = false;
-  if arg_len == 2 {
-    let int_val = is_err_arg
-      .integer_value(scope)
-      .expect("Unable to convert to integer");
-    is_err = int_val != 0;
-  };
-  let tc_scope = &mut v8::TryCatch::new(scope);
-  let str_ = match obj.to_string(tc_scope) {
-    Some(s) => s,
-    None => v8::String::new(tc_scope, "").unwrap(),
-  };
+  let array = v8::Local::<v8::Array>::try_from(args.get(0)).ok();
+  let len = array.map_or(1, |array| array.length());
+
+  let mut text = String::new();
+  for i in 0..len {
+    if i > 0 {
+      text.push(' ');
+    }
+    // Both reading an element (e.g. through a getter or a proxy) and
+    // converting it to a string can run user code that throws.
+    let result = {
+      let tc_scope = &mut v8::TryCatch::new(scope);
+      let value = match array {
+        Some(array) => array.get_index(tc_scope, i),
+        None => Some(args.get(0)),
+      };
+      match value.and_then(|value| value.to_string(tc_scope)) {
+        Some(s) => Ok(s.to_rust_string_lossy(tc_scope)),
+        None => Err(tc_scope.exception().unwrap()),
+      }
+    };
+    match result {
+      Ok(s) => text.push_str(&s),
+      Err(exception) => {
+        scope.throw_exception(exception);
+        return;
+      }
+    }
+  }
+  if newline {
+    text.push('\n');
+  }
+
+  let state_rc = CoreIsolate::state(scope);
+  let mut state = state_rc.borrow_mut();
+  let state = &mut *state;
+  let encoding = state.print_encoding;
+  let rate_limit = state.print_rate_limit.as_mut();
   if is_err {
-    eprint!("{}", str_.to_rust_string_lossy(tc_scope));
+    print::write(&mut *state.stderr, &text, encoding, rate_limit)
+      .expect("failed printing to stderr");
   } else {
-    print!("{}", str_.to_rust_string_lossy(tc_scope));
+    print::write(&mut *state.stdout, &text, encoding, rate_limit)
+      .expect("failed printing to stdout");
   }
 }
 
@@ -357,18 +809,58 @@ fn recv(
 ) {
   let state_rc = CoreIsolate::state(scope);
   let mut state = state_rc.borrow_mut();
+  let state = &mut *state;
 
   let cb = match v8::Local::<v8::Function>::try_from(args.get(0)) {
     Ok(cb) => cb,
     Err(err) => return throw_type_error(scope, err.to_string()),
   };
 
-  let slot = match &mut state.js_recv_cb {
-    slot @ None => slot,
-    _ => return throw_type_error(scope, "Deno.core.recv() already called"),
+  let allow_recv_replacement = state.allow_recv_replacement;
+  let js_recv_cb = match state.js_recv_cb_mut(realm_id(&args)) {
+    Some(js_recv_cb) => js_recv_cb,
+    None => return throw_type_error(scope, "realm has been dropped"),
   };
+  if js_recv_cb.is_some() && !allow_recv_replacement {
+    return throw_type_error(scope, "Deno.core.recv() already called");
+  }
 
-  slot.replace(v8::Global::new(scope, cb));
+  // Replacing the callback drops, and thereby resets, the previous handle.
+  js_recv_cb.replace(v8::Global::new(scope, cb));
+}
+
+/// Returns the realm a `Deno.core.send()` or `Deno.core.recv()` function was
+/// created for. Those of the global context carry no realm id.
+fn realm_id(args: &v8::FunctionCallbackArguments) -> RealmId {
+  args
+    .data()
+    .and_then(|data| v8::Local::<v8::Integer>::try_from(data).ok())
+    .map(|realm_id| realm_id.value() as RealmId)
+    .unwrap_or(MAIN_REALM)
+}
+
+/// `Deno.core.setOpResponseHandler(opId, cb)` makes async responses of the op
+/// go straight to `cb`, bypassing the shared queue and the handlers set with
+/// `Deno.core.setAsyncHandler()`. Every response costs a call from Rust into
+/// JavaScript, so high-volume ops are better served by the batched path.
+fn set_op_response_handler(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  let op_id = match v8::Local::<v8::Uint32>::try_from(args.get(0)) {
+    Ok(op_id) => op_id.value() as OpId,
+    Err(err) => return throw_type_error(scope, err.to_string()),
+  };
+  let cb = match v8::Local::<v8::Function>::try_from(args.get(1)) {
+    Ok(cb) => cb,
+    Err(err) => return throw_type_error(scope, err.to_string()),
+  };
+
+  let cb = v8::Global::new(scope, cb);
+  let state_rc = CoreIsolate::state(scope);
+  let mut state = state_rc.borrow_mut();
+  state.js_op_response_cbs.insert(op_id, cb);
 }
 
 fn send(
@@ -403,7 +895,9 @@ fn send(
   // If response is empty then it's either async op or exception was thrown.
   let maybe_response =
     match buf_iter.collect::<Result<SmallVec<[ZeroCopyBuf; 2]>, _>>() {
-      Ok(mut bufs) => state.dispatch_op(scope, op_id, &mut bufs),
+      Ok(mut bufs) => {
+        state.dispatch_op_in_realm(scope, realm_id(&args), op_id, &mut bufs)
+      }
       Err(exc) => {
         scope.throw_exception(exc);
         return;
@@ -412,12 +906,27 @@ fn send(
 
   if let Some(response) = maybe_response {
     // Synchronous response.
-    // Note op_id is not passed back in the case of synchronous response.
-    let (_op_id, buf) = response;
+    // Note op_id is not passed back in the case of synchronous response,
+    // unless enabled with CoreIsolate::set_op_id_in_sync_response().
+    let (op_id, buf) = response;
+
+    let buf: Option<v8::Local<v8::Value>> = if buf.is_empty() {
+      None
+    } else {
+      Some(boxed_slice_to_uint8array(scope, buf).into())
+    };
 
-    if !buf.is_empty() {
-      let ui8 = boxed_slice_to_uint8array(scope, buf);
-      rv.set(ui8.into());
+    if state.op_id_in_sync_response {
+      let op_id = v8::Integer::new_from_unsigned(scope, op_id);
+      let buf = buf.unwrap_or_else(|| v8::null(scope).into());
+      let response = v8::Array::new(scope, 2);
+      let js_zero = v8::Integer::new(scope, 0);
+      let js_one = v8::Integer::new(scope, 1);
+      response.set(scope, js_zero.into(), op_id.into());
+      response.set(scope, js_one.into(), buf);
+      rv.set(response.into());
+    } else if let Some(buf) = buf {
+      rv.set(buf);
     }
   }
 }
@@ -448,6 +957,92 @@ fn set_macrotask_callback(
   slot.replace(v8::Global::new(scope, cb));
 }
 
+// Registers `cb(promise, reason)`, called for every promise rejection that is
+// still unhandled when the isolate checks for them. Returning true marks the
+// rejection as handled, like calling preventDefault() on an
+// `unhandledrejection` event; otherwise it is fatal as before.
+fn set_unhandled_rejection_callback(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  let state_rc = CoreIsolate::state(scope);
+  let mut state = state_rc.borrow_mut();
+
+  let cb = match v8::Local::<v8::Function>::try_from(args.get(0)) {
+    Ok(cb) => cb,
+    Err(err) => return throw_type_error(scope, err.to_string()),
+  };
+
+  let slot = match &mut state.js_unhandled_rejection_cb {
+    slot @ None => slot,
+    _ => {
+      return throw_type_error(
+        scope,
+        "Deno.core.setUnhandledRejectionCallback() already called",
+      );
+    }
+  };
+
+  slot.replace(v8::Global::new(scope, cb));
+}
+
+fn set_task_hooks(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  let state_rc = CoreIsolate::state(scope);
+  let mut state = state_rc.borrow_mut();
+
+  let before = match v8::Local::<v8::Function>::try_from(args.get(0)) {
+    Ok(cb) => cb,
+    Err(err) => return throw_type_error(scope, err.to_string()),
+  };
+  let after = match v8::Local::<v8::Function>::try_from(args.get(1)) {
+    Ok(cb) => cb,
+    Err(err) => return throw_type_error(scope, err.to_string()),
+  };
+
+  let slot = match &mut state.js_task_hooks {
+    slot @ None => slot,
+    _ => {
+      return throw_type_error(scope, "Deno.core.setTaskHooks() already called")
+    }
+  };
+
+  slot.replace((
+    v8::Global::new(scope, before),
+    v8::Global::new(scope, after),
+  ));
+}
+
+fn set_idle_callback(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  let state_rc = CoreIsolate::state(scope);
+  let mut state = state_rc.borrow_mut();
+
+  let cb = match v8::Local::<v8::Function>::try_from(args.get(0)) {
+    Ok(cb) => cb,
+    Err(err) => return throw_type_error(scope, err.to_string()),
+  };
+
+  let slot = match &mut state.js_idle_cb {
+    slot @ None => slot,
+    _ => {
+      return throw_type_error(
+        scope,
+        "Deno.core.setIdleCallback() already called",
+      );
+    }
+  };
+
+  slot.replace(v8::Global::new(scope, cb));
+}
+
 fn eval_context(
   scope: &mut v8::HandleScope,
   args: v8::FunctionCallbackArguments,
@@ -463,8 +1058,11 @@ fn eval_context(
     }
   };
 
-  let url = v8::Local::<v8::String>::try_from(args.get(1))
-    .map(|n| Url::from_file_path(n.to_rust_string_lossy(scope)).unwrap());
+  let (filename, origin_options) =
+    match eval_context_options(scope, args.get(1)) {
+      Ok(options) => options,
+      Err(msg) => return throw_type_error(scope, msg),
+    };
 
   let output = v8::Array::new(scope, 2);
   /*
@@ -474,101 +1072,231 @@ fn eval_context(
        thrown: Error | any,
        isNativeError: boolean,
        isCompileError: boolean,
+       fileName: string | null,
+       lineNumber: number | null,
+       columnNumber: number | null,
      }
   */
   let tc_scope = &mut v8::TryCatch::new(scope);
   let name =
-    v8::String::new(tc_scope, url.as_ref().map_or("<unknown>", Url::as_str))
+    v8::String::new(tc_scope, filename.as_deref().unwrap_or("<unknown>"))
       .unwrap();
-  let origin = script_origin(tc_scope, name);
+  let origin = script_origin(tc_scope, name, &origin_options);
   let maybe_script = v8::Script::compile(tc_scope, source, Some(&origin));
 
-  if maybe_script.is_none() {
-    assert!(tc_scope.has_caught());
-    let exception = tc_scope.exception().unwrap();
+  let maybe_result = match maybe_script {
+    Some(script) => script.run(tc_scope),
+    None => None,
+  };
+  let result = match maybe_result {
+    Some(result) => result,
+    None => {
+      assert!(tc_scope.has_caught());
+      let exception = tc_scope.exception().unwrap();
+      let is_compile_error = maybe_script.is_none();
+      let message = tc_scope.message();
+      let errinfo_obj =
+        eval_error_info(tc_scope, exception, message, is_compile_error);
+
+      let js_zero = v8::Integer::new(tc_scope, 0);
+      let js_null = v8::null(tc_scope);
+      output.set(tc_scope, js_zero.into(), js_null.into());
+      let js_one = v8::Integer::new(tc_scope, 1);
+      output.set(tc_scope, js_one.into(), errinfo_obj.into());
+
+      rv.set(output.into());
+      return;
+    }
+  };
+
+  let js_zero = v8::Integer::new(tc_scope, 0);
+  let js_one = v8::Integer::new(tc_scope, 1);
+  let js_null = v8::null(tc_scope);
+  output.set(tc_scope, js_zero.into(), result);
+  output.set(tc_scope, js_one.into(), js_null.into());
+  rv.set(output.into());
+}
 
-    let js_zero = v8::Integer::new(tc_scope, 0);
-    let js_null = v8::null(tc_scope);
-    output.set(tc_scope, js_zero.into(), js_null.into());
+// Parses the second argument of Deno.core.evalContext(), which is either an
+// absolute file path or an options object of the form
+// `{ filename?, lineOffset?, columnOffset?, sourceMapUrl? }`.
+fn eval_context_options(
+  scope: &mut v8::HandleScope,
+  value: v8::Local<v8::Value>,
+) -> Result<(Option<String>, ScriptOriginOptions), String> {
+  let mut origin_options = ScriptOriginOptions::default();
+  if value.is_undefined() {
+    return Ok((None, origin_options));
+  }
+  if let Ok(path) = v8::Local::<v8::String>::try_from(value) {
+    let path = path.to_rust_string_lossy(scope);
+    let url = Url::from_file_path(&path).map_err(|_| {
+      format!("Invalid filename \"{}\": not an absolute path", path)
+    })?;
+    return Ok((Some(url.into_string()), origin_options));
+  }
+  let options = v8::Local::<v8::Object>::try_from(value)
+    .map_err(|_| "evalContext() options must be an object".to_string())?;
+
+  let mut filename = None;
+  let keys = options
+    .get_own_property_names(scope)
+    .ok_or_else(|| "evalContext() options could not be read".to_string())?;
+  for i in 0..keys.length() {
+    let key = keys.get_index(scope, i).unwrap();
+    let key = key.to_rust_string_lossy(scope);
+    let value = get_property(scope, options, &key).ok_or_else(|| {
+      format!("evalContext() option \"{}\" could not be read", key)
+    })?;
+    let invalid = |expected: &str| {
+      format!("evalContext() option \"{}\" must be {}", key, expected)
+    };
+    match key.as_str() {
+      "filename" => {
+        let value = v8::Local::<v8::String>::try_from(value)
+          .map_err(|_| invalid("a string"))?;
+        filename = Some(value.to_rust_string_lossy(scope));
+      }
+      "lineOffset" => {
+        let value = v8::Local::<v8::Int32>::try_from(value)
+          .map_err(|_| invalid("an integer"))?;
+        origin_options.line_offset = value.value();
+      }
+      "columnOffset" => {
+        let value = v8::Local::<v8::Int32>::try_from(value)
+          .map_err(|_| invalid("an integer"))?;
+        origin_options.column_offset = value.value();
+      }
+      "sourceMapUrl" => {
+        let value = v8::Local::<v8::String>::try_from(value)
+          .map_err(|_| invalid("a string"))?;
+        origin_options.source_map_url = Some(value.to_rust_string_lossy(scope));
+      }
+      _ => return Err(format!("Unknown evalContext() option \"{}\"", key)),
+    }
+  }
+  Ok((filename, origin_options))
+}
 
-    let errinfo_obj = v8::Object::new(tc_scope);
+// Builds the ErrorInfo object returned by Deno.core.evalContext() for an
+// exception thrown while compiling or running the evaluated source.
+fn eval_error_info<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  exception: v8::Local<'s, v8::Value>,
+  message: Option<v8::Local<'s, v8::Message>>,
+  is_compile_error: bool,
+) -> v8::Local<'s, v8::Object> {
+  let errinfo_obj = v8::Object::new(scope);
+
+  let is_compile_error_key = v8::String::new(scope, "isCompileError").unwrap();
+  let is_compile_error_val = v8::Boolean::new(scope, is_compile_error);
+  errinfo_obj.set(
+    scope,
+    is_compile_error_key.into(),
+    is_compile_error_val.into(),
+  );
 
-    let is_compile_error_key =
-      v8::String::new(tc_scope, "isCompileError").unwrap();
-    let is_compile_error_val = v8::Boolean::new(tc_scope, true);
-    errinfo_obj.set(
-      tc_scope,
-      is_compile_error_key.into(),
-      is_compile_error_val.into(),
-    );
+  let is_native_error_key = v8::String::new(scope, "isNativeError").unwrap();
+  let is_native_error_val =
+    v8::Boolean::new(scope, exception.is_native_error());
+  errinfo_obj.set(
+    scope,
+    is_native_error_key.into(),
+    is_native_error_val.into(),
+  );
 
-    let is_native_error_key =
-      v8::String::new(tc_scope, "isNativeError").unwrap();
-    let is_native_error_val =
-      v8::Boolean::new(tc_scope, exception.is_native_error());
-    errinfo_obj.set(
-      tc_scope,
-      is_native_error_key.into(),
-      is_native_error_val.into(),
-    );
+  let thrown_key = v8::String::new(scope, "thrown").unwrap();
+  errinfo_obj.set(scope, thrown_key.into(), exception);
+
+  // Where the exception was thrown, or where compilation failed. Unlike the
+  // stack of `thrown`, this is also known when a non-Error value is thrown.
+  // Line and column numbers are 1-based.
+  let null: v8::Local<v8::Value> = v8::null(scope).into();
+  let (file_name_val, line_number_val, column_number_val) = match message {
+    Some(message) => {
+      let file_name_val = message
+        .get_script_resource_name(scope)
+        .filter(|name| name.is_string())
+        .unwrap_or(null);
+      let line_number_val = match message.get_line_number(scope) {
+        Some(line) => v8::Integer::new(scope, line as i32).into(),
+        None => null,
+      };
+      let column = message.get_start_column() as i32 + 1;
+      let column_number_val = v8::Integer::new(scope, column).into();
+      (file_name_val, line_number_val, column_number_val)
+    }
+    None => (null, null, null),
+  };
 
-    let thrown_key = v8::String::new(tc_scope, "thrown").unwrap();
-    errinfo_obj.set(tc_scope, thrown_key.into(), exception);
+  let file_name_key = v8::String::new(scope, "fileName").unwrap();
+  errinfo_obj.set(scope, file_name_key.into(), file_name_val);
 
-    let js_one = v8::Integer::new(tc_scope, 1);
-    output.set(tc_scope, js_one.into(), errinfo_obj.into());
+  let line_number_key = v8::String::new(scope, "lineNumber").unwrap();
+  errinfo_obj.set(scope, line_number_key.into(), line_number_val);
 
-    rv.set(output.into());
-    return;
-  }
+  let column_number_key = v8::String::new(scope, "columnNumber").unwrap();
+  errinfo_obj.set(scope, column_number_key.into(), column_number_val);
 
-  let result = maybe_script.unwrap().run(tc_scope);
+  errinfo_obj
+}
 
-  if result.is_none() {
-    assert!(tc_scope.has_caught());
-    let exception = tc_scope.exception().unwrap();
+// Normalizes any thrown value into `{ isError, name, message, stack }`. Name,
+// message and stack are read from the value if it is an object and they are
+// strings; otherwise name and stack are null and the message is the value
+// converted to a string, e.g. `{ isError: false, message: "42" }` for 42.
+pub fn describe_thrown_value<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  value: v8::Local<'s, v8::Value>,
+) -> v8::Local<'s, v8::Object> {
+  let description = v8::Object::new(scope);
+  let is_error = value.is_native_error();
 
-    let js_zero = v8::Integer::new(tc_scope, 0);
-    let js_null = v8::null(tc_scope);
-    output.set(tc_scope, js_zero.into(), js_null.into());
+  // Getters and toString() may throw; such properties are treated as absent.
+  let tc_scope = &mut v8::TryCatch::new(scope);
+  let get_string = |scope: &mut v8::HandleScope<'s>, key: &str| {
+    let object = v8::Local::<v8::Object>::try_from(value).ok()?;
+    let value = get_property(scope, object, key)?;
+    v8::Local::<v8::String>::try_from(value).ok()
+  };
+  let name = get_string(tc_scope, "name");
+  let message = get_string(tc_scope, "message");
+  let stack = get_string(tc_scope, "stack");
+  let message = match message {
+    Some(message) => message,
+    None if is_error => v8::String::new(tc_scope, "").unwrap(),
+    None => value
+      .to_string(tc_scope)
+      .unwrap_or_else(|| v8::String::new(tc_scope, "").unwrap()),
+  };
 
-    let errinfo_obj = v8::Object::new(tc_scope);
+  let null: v8::Local<v8::Value> = v8::null(tc_scope).into();
 
-    let is_compile_error_key =
-      v8::String::new(tc_scope, "isCompileError").unwrap();
-    let is_compile_error_val = v8::Boolean::new(tc_scope, false);
-    errinfo_obj.set(
-      tc_scope,
-      is_compile_error_key.into(),
-      is_compile_error_val.into(),
-    );
+  let is_error_key = v8::String::new(tc_scope, "isError").unwrap();
+  let is_error_val = v8::Boolean::new(tc_scope, is_error);
+  description.set(tc_scope, is_error_key.into(), is_error_val.into());
 
-    let is_native_error_key =
-      v8::String::new(tc_scope, "isNativeError").unwrap();
-    let is_native_error_val =
-      v8::Boolean::new(tc_scope, exception.is_native_error());
-    errinfo_obj.set(
-      tc_scope,
-      is_native_error_key.into(),
-      is_native_error_val.into(),
-    );
+  let name_key = v8::String::new(tc_scope, "name").unwrap();
+  let name_val = name.map_or(null, Into::into);
+  description.set(tc_scope, name_key.into(), name_val);
 
-    let thrown_key = v8::String::new(tc_scope, "thrown").unwrap();
-    errinfo_obj.set(tc_scope, thrown_key.into(), exception);
+  let message_key = v8::String::new(tc_scope, "message").unwrap();
+  description.set(tc_scope, message_key.into(), message.into());
 
-    let js_one = v8::Integer::new(tc_scope, 1);
-    output.set(tc_scope, js_one.into(), errinfo_obj.into());
+  let stack_key = v8::String::new(tc_scope, "stack").unwrap();
+  let stack_val = stack.map_or(null, Into::into);
+  description.set(tc_scope, stack_key.into(), stack_val);
 
-    rv.set(output.into());
-    return;
-  }
+  description
+}
 
-  let js_zero = v8::Integer::new(tc_scope, 0);
-  let js_one = v8::Integer::new(tc_scope, 1);
-  let js_null = v8::null(tc_scope);
-  output.set(tc_scope, js_zero.into(), result.unwrap());
-  output.set(tc_scope, js_one.into(), js_null.into());
-  rv.set(output.into());
+fn describe_thrown(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let description = describe_thrown_value(scope, args.get(0));
+  rv.set(description.into())
 }
 
 fn format_error(
@@ -576,8 +1304,13 @@ fn format_error(
   args: v8::FunctionCallbackArguments,
   mut rv: v8::ReturnValue,
 ) {
-  let e = JSError::from_v8_exception(scope, args.get(0));
   let state_rc = CoreIsolate::state(scope);
+  let source_line_mode = state_rc.borrow().source_line_mode;
+  let e = JSError::from_v8_exception_with_source_line_mode(
+    scope,
+    args.get(0),
+    source_line_mode,
+  );
   let state = state_rc.borrow();
   let e = (state.js_error_create_fn)(e);
   let e = e.to_string();
@@ -689,6 +1422,72 @@ fn shared_getter(
   rv.set(shared_ab.into())
 }
 
+fn module_dependencies(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  // Module bookkeeping only exists when running in an EsIsolate.
+  let state_rc = match scope.get_slot::<Rc<RefCell<EsIsolateState>>>() {
+    Some(state_rc) => state_rc.clone(),
+    None => {
+      return throw_type_error(
+        scope,
+        "Deno.core.moduleDependencies() requires module support",
+      )
+    }
+  };
+  let id = match v8::Local::<v8::Int32>::try_from(args.get(0)) {
+    Ok(id) => id.value(),
+    Err(_) => return throw_type_error(scope, "Invalid argument"),
+  };
+
+  let deps = state_rc.borrow().module_dependencies(scope, id);
+  let deps_arr = v8::Array::new(scope, deps.len() as i32);
+  for (i, (specifier, dep_id)) in deps.into_iter().enumerate() {
+    let dep = v8::Object::new(scope);
+
+    let specifier_key = v8::String::new(scope, "specifier").unwrap();
+    let specifier_val = v8::String::new(scope, &specifier).unwrap();
+    dep.set(scope, specifier_key.into(), specifier_val.into());
+
+    let id_key = v8::String::new(scope, "id").unwrap();
+    let id_val: v8::Local<v8::Value> = match dep_id {
+      Some(dep_id) => v8::Integer::new(scope, dep_id).into(),
+      None => v8::null(scope).into(),
+    };
+    dep.set(scope, id_key.into(), id_val);
+
+    let index = v8::Integer::new(scope, i as i32);
+    deps_arr.set(scope, index.into(), dep.into());
+  }
+  rv.set(deps_arr.into())
+}
+
+fn modules(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  // Module bookkeeping only exists when running in an EsIsolate.
+  let state_rc = match scope.get_slot::<Rc<RefCell<EsIsolateState>>>() {
+    Some(state_rc) => state_rc.clone(),
+    None => {
+      return throw_type_error(
+        scope,
+        "Deno.core.modules() requires module support",
+      )
+    }
+  };
+
+  let graph = state_rc.borrow().module_graph(scope);
+  let graph_json: Vec<_> = graph.iter().map(|entry| entry.to_json()).collect();
+  let graph_json = serde_json::Value::from(graph_json).to_string();
+  let graph_json = v8::String::new(scope, &graph_json).unwrap();
+  let graph = v8::json::parse(scope, graph_json).unwrap();
+  rv.set(graph)
+}
+
 pub fn module_resolve_callback<'s>(
   context: v8::Local<'s, v8::Context>,
   specifier: v8::Local<'s, v8::String>,
@@ -701,7 +1500,7 @@ pub fn module_resolve_callback<'s>(
 
   let referrer_id = referrer.get_identity_hash();
   let referrer_name = state
-    .modules
+    .modules_of(referrer_id)
     .get_info(referrer_id)
     .expect("ModuleInfo not found")
     .name
@@ -716,7 +1515,7 @@ pub fn module_resolve_callback<'s>(
 
     if req_str == specifier_str {
       let id = state.module_resolve_cb(&req_str, referrer_id);
-      match state.modules.get_info(id) {
+      match state.modules_of(referrer_id).get_info(id) {
         Some(info) => return Some(v8::Local::new(scope, &info.handle)),
         None => {
           let msg = format!(
@@ -781,6 +1580,424 @@ fn get_promise_details(
   }
 }
 
+fn context_count(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let state_rc = CoreIsolate::state(scope);
+  let count = state_rc.borrow().live_contexts.get();
+  let count = v8::Integer::new_from_unsigned(scope, count as u32);
+  rv.set(count.into())
+}
+
+// Returns the bounds `send` uses when it slices the backing store of a view:
+// { bufferStart, byteOffset, byteLength, withinBounds }
+// `bufferStart` is the offset into the backing store at which the slice
+// starts, and `withinBounds` tells whether the whole view fits inside the
+// backing store. V8 reports a view on a detached buffer as empty, so it is
+// within bounds with a `byteLength` of 0.
+fn view_bounds(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let view = match v8::Local::<v8::ArrayBufferView>::try_from(args.get(0)) {
+    Ok(view) => view,
+    Err(_) => return throw_type_error(scope, "Invalid argument"),
+  };
+
+  let byte_offset = view.byte_offset();
+  let byte_length = view.byte_length();
+  let backing_store_length =
+    view.buffer(scope).map_or(0, |buffer| buffer.byte_length());
+  let buffer_start = byte_offset.min(backing_store_length);
+  let within_bounds = byte_offset
+    .checked_add(byte_length)
+    .map_or(false, |end| end <= backing_store_length);
+
+  let bounds = v8::Object::new(scope);
+
+  let buffer_start_key = v8::String::new(scope, "bufferStart").unwrap();
+  let buffer_start_val = v8::Number::new(scope, buffer_start as f64);
+  bounds.set(scope, buffer_start_key.into(), buffer_start_val.into());
+
+  let byte_offset_key = v8::String::new(scope, "byteOffset").unwrap();
+  let byte_offset_val = v8::Number::new(scope, byte_offset as f64);
+  bounds.set(scope, byte_offset_key.into(), byte_offset_val.into());
+
+  let byte_length_key = v8::String::new(scope, "byteLength").unwrap();
+  let byte_length_val = v8::Number::new(scope, byte_length as f64);
+  bounds.set(scope, byte_length_key.into(), byte_length_val.into());
+
+  let within_bounds_key = v8::String::new(scope, "withinBounds").unwrap();
+  let within_bounds_val = v8::Boolean::new(scope, within_bounds);
+  bounds.set(scope, within_bounds_key.into(), within_bounds_val.into());
+
+  rv.set(bounds.into())
+}
+
+// Enters and exits the isolate's global context `iterations` times and
+// returns the average cost of a single enter/exit pair in nanoseconds.
+fn bench_context_switch(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let iterations = match v8::Local::<v8::Uint32>::try_from(args.get(0)) {
+    Ok(iterations) if iterations.value() > 0 => iterations.value(),
+    _ => {
+      return throw_type_error(
+        scope,
+        "iterations must be a positive 32-bit integer",
+      )
+    }
+  };
+
+  let context = {
+    let state_rc = CoreIsolate::state(scope);
+    let state = state_rc.borrow();
+    v8::Local::new(scope, state.global_context.as_ref().unwrap())
+  };
+
+  let start = Instant::now();
+  for _ in 0..iterations {
+    let _context_scope = v8::ContextScope::new(scope, context);
+  }
+  let elapsed = start.elapsed().as_nanos() as f64;
+
+  let per_iteration = v8::Number::new(scope, elapsed / iterations as f64);
+  rv.set(per_iteration.into())
+}
+
+fn op_meta(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let state_rc = CoreIsolate::state(scope);
+  let meta_json = state_rc.borrow().op_registry.meta_json().to_string();
+  let meta_json = v8::String::new(scope, &meta_json).unwrap();
+  let meta = v8::json::parse(scope, meta_json).unwrap();
+  rv.set(meta)
+}
+
+fn op_latency(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let op_id = match v8::Local::<v8::Uint32>::try_from(args.get(0)) {
+    Ok(op_id) => op_id.value() as u32,
+    Err(_) => return throw_type_error(scope, "Invalid argument"),
+  };
+
+  let state_rc = CoreIsolate::state(scope);
+  let maybe_latency = state_rc
+    .borrow()
+    .op_metrics
+    .as_ref()
+    .and_then(|op_metrics| op_metrics.latency(op_id));
+  let latency = match maybe_latency {
+    Some(latency) => latency,
+    None => return rv.set(v8::null(scope).into()),
+  };
+
+  let obj = v8::Object::new(scope);
+  for (name, nanos) in &[
+    ("p50", latency.p50),
+    ("p90", latency.p90),
+    ("p99", latency.p99),
+    ("max", latency.max),
+  ] {
+    let key = v8::String::new(scope, name).unwrap();
+    let val = v8::Number::new(scope, *nanos as f64);
+    obj.set(scope, key.into(), val.into());
+  }
+  rv.set(obj.into())
+}
+
+// Deno.core.hasCycle(value) returns whether any object reachable from `value`
+// through own enumerable properties refers back to one of its ancestors.
+// Deno.core.hasCycle(value, true) instead returns the property keys leading to
+// the first such reference, or null if there is none.
+fn has_cycle(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let with_path = args.get(1).is_true();
+  let cycle_path =
+    match object_graph::walk(scope, args.get(0), true, &mut |_, _| Ok(())) {
+      Ok(cycle_path) => cycle_path,
+      Err(()) => return,
+    };
+
+  if !with_path {
+    let has_cycle = v8::Boolean::new(scope, cycle_path.is_some());
+    return rv.set(has_cycle.into());
+  }
+  let cycle_path = match cycle_path {
+    Some(cycle_path) => cycle_path,
+    None => return rv.set(v8::null(scope).into()),
+  };
+  let path = v8::Array::new(scope, cycle_path.len() as i32);
+  for (i, key) in cycle_path.into_iter().enumerate() {
+    let key = match key.to_string(scope) {
+      Some(key) => key,
+      None => return,
+    };
+    let index = v8::Integer::new(scope, i as i32);
+    path.set(scope, index.into(), key.into());
+  }
+  rv.set(path.into())
+}
+
+// Freezes `value` and every object reachable from it through own enumerable
+// properties, then returns `value`.
+fn deep_freeze(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let value = args.get(0);
+  let result = object_graph::walk(scope, value, false, &mut |scope, obj| {
+    let frozen = obj.set_integrity_level(scope, v8::IntegrityLevel::Frozen);
+    match frozen {
+      Some(true) => Ok(()),
+      Some(false) => {
+        throw_type_error(scope, "Cannot freeze object");
+        Err(())
+      }
+      // An exception is pending, e.g. thrown by a proxy trap.
+      None => Err(()),
+    }
+  });
+  if result.is_err() {
+    return;
+  }
+  rv.set(value)
+}
+
+// Returns `{ size, headroom }`: the stack size in bytes V8 is enforcing, and
+// the approximate number of bytes left before the limit is hit. Headroom is
+// only known while running under `CoreIsolate::run_with_stack_size()` and is
+// null otherwise.
+fn stack_limit(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let (size, maybe_limit) = {
+    let state_rc = CoreIsolate::state(scope);
+    let state = state_rc.borrow();
+    let size = state.stack_size.unwrap_or(DEFAULT_V8_STACK_SIZE);
+    (size, state.stack_limit)
+  };
+  // The position of this native frame approximates the stack depth of the
+  // JS caller.
+  let stack_position = &size as *const _ as usize;
+
+  let limit = v8::Object::new(scope);
+
+  let size_key = v8::String::new(scope, "size").unwrap();
+  let size_val = v8::Number::new(scope, size as f64);
+  limit.set(scope, size_key.into(), size_val.into());
+
+  let headroom_key = v8::String::new(scope, "headroom").unwrap();
+  let headroom_val: v8::Local<v8::Value> = match maybe_limit {
+    Some(stack_limit) => {
+      let headroom = stack_position.saturating_sub(stack_limit);
+      v8::Number::new(scope, headroom as f64).into()
+    }
+    None => v8::null(scope).into(),
+  };
+  limit.set(scope, headroom_key.into(), headroom_val);
+
+  rv.set(limit.into())
+}
+
+fn heap_stats(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let stats = HeapStats::get(scope);
+  let fields = [
+    ("totalHeapSize", stats.total_heap_size),
+    ("usedHeapSize", stats.used_heap_size),
+    ("heapSizeLimit", stats.heap_size_limit),
+    ("externalMemory", stats.external_memory),
+    ("numberOfNativeContexts", stats.number_of_native_contexts),
+    (
+      "numberOfDetachedContexts",
+      stats.number_of_detached_contexts,
+    ),
+  ];
+
+  let stats_obj = v8::Object::new(scope);
+  for (name, value) in fields.iter() {
+    let key = v8::String::new(scope, name).unwrap();
+    let val = v8::Number::new(scope, *value as f64);
+    stats_obj.set(scope, key.into(), val.into());
+  }
+  rv.set(stats_obj.into())
+}
+
+fn metrics(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let maybe_metrics_json = {
+    let state_rc = CoreIsolate::state(scope);
+    let state = state_rc.borrow();
+    state
+      .op_metrics
+      .as_ref()
+      .map(|op_metrics| op_metrics.to_json(&state.op_registry))
+  };
+  let metrics_json = match maybe_metrics_json {
+    Some(metrics_json) => metrics_json.to_string(),
+    None => return rv.set(v8::null(scope).into()),
+  };
+  let metrics_json = v8::String::new(scope, &metrics_json).unwrap();
+  let metrics = v8::json::parse(scope, metrics_json).unwrap();
+  rv.set(metrics)
+}
+
+/// Returns an object mapping the rid of every open resource to its name,
+/// e.g. for detecting resources leaked by a test.
+fn resources(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let resources_json = {
+    let state_rc = CoreIsolate::state(scope);
+    let state = state_rc.borrow();
+    let resource_table = state.resource_table.borrow();
+    let map: serde_json::Map<String, serde_json::Value> = resource_table
+      .entries()
+      .into_iter()
+      .map(|(rid, name)| (rid.to_string(), name.into()))
+      .collect();
+    serde_json::Value::Object(map).to_string()
+  };
+  let resources_json = v8::String::new(scope, &resources_json).unwrap();
+  let resources = v8::json::parse(scope, resources_json).unwrap();
+  rv.set(resources)
+}
+
+// Maps a position through the script's source map or the function set with
+// CoreIsolate::set_source_map_fn(). Returns `{ fileName, lineNumber,
+// columnNumber }`, unchanged if the position has no mapping.
+fn apply_source_map(
+  scope: &mut v8::HandleScope,
+  args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let file_name = match v8::Local::<v8::String>::try_from(args.get(0)) {
+    Ok(file_name) => file_name.to_rust_string_lossy(scope),
+    Err(_) => return throw_type_error(scope, "Invalid file name"),
+  };
+  let (line_number, column_number) = match (
+    v8::Local::<v8::Integer>::try_from(args.get(1)),
+    v8::Local::<v8::Integer>::try_from(args.get(2)),
+  ) {
+    (Ok(line), Ok(column)) => (line.value(), column.value()),
+    _ => return throw_type_error(scope, "Invalid line or column number"),
+  };
+
+  let maybe_mapped = CoreIsolate::state(scope).borrow().map_location(
+    &file_name,
+    line_number,
+    column_number,
+  );
+  let (file_name, line_number, column_number) =
+    maybe_mapped.unwrap_or((file_name, line_number, column_number));
+
+  let location = v8::Object::new(scope);
+  let file_name_key = v8::String::new(scope, "fileName").unwrap();
+  let file_name_val = v8::String::new(scope, &file_name).unwrap();
+  location.set(scope, file_name_key.into(), file_name_val.into());
+  let line_number_key = v8::String::new(scope, "lineNumber").unwrap();
+  let line_number_val = v8::Number::new(scope, line_number as f64);
+  location.set(scope, line_number_key.into(), line_number_val.into());
+  let column_number_key = v8::String::new(scope, "columnNumber").unwrap();
+  let column_number_val = v8::Number::new(scope, column_number as f64);
+  location.set(scope, column_number_key.into(), column_number_val.into());
+  rv.set(location.into())
+}
+
+fn drain_op_metrics(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  mut rv: v8::ReturnValue,
+) {
+  let maybe_metrics_json = {
+    let state_rc = CoreIsolate::state(scope);
+    let mut state = state_rc.borrow_mut();
+    let state = &mut *state;
+    state
+      .op_metrics
+      .as_mut()
+      .map(|op_metrics| op_metrics.drain().to_json(&state.op_registry))
+  };
+  let metrics_json = match maybe_metrics_json {
+    Some(metrics_json) => metrics_json.to_string(),
+    None => return rv.set(v8::null(scope).into()),
+  };
+  let metrics_json = v8::String::new(scope, &metrics_json).unwrap();
+  let metrics = v8::json::parse(scope, metrics_json).unwrap();
+  rv.set(metrics)
+}
+
+fn minor_gc(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  request_gc(scope, v8::GarbageCollectionType::Minor, "minorGc");
+}
+
+fn major_gc(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  request_gc(scope, v8::GarbageCollectionType::Full, "majorGc");
+}
+
+// Unlike minorGc() and majorGc() this doesn't need --expose-gc: it is meant
+// for runtimes that just finished a large one-shot workload and want the
+// memory back, at the cost of a few full collections.
+fn low_memory_notification(
+  scope: &mut v8::HandleScope,
+  _args: v8::FunctionCallbackArguments,
+  _rv: v8::ReturnValue,
+) {
+  scope.low_memory_notification();
+}
+
+fn request_gc(
+  scope: &mut v8::HandleScope,
+  gc_type: v8::GarbageCollectionType,
+  binding_name: &str,
+) {
+  // V8 aborts the process when a collection is requested for testing without
+  // --expose-gc. Scripts can define a `gc` global of their own, so the flag
+  // itself is checked rather than the global it installs.
+  if !is_gc_exposed() {
+    let msg = format!("Deno.core.{}() requires --expose-gc", binding_name);
+    let msg = v8::String::new(scope, &msg).unwrap();
+    let exception = v8::Exception::error(scope, msg);
+    scope.throw_exception(exception);
+    return;
+  }
+  scope.request_garbage_collection_for_testing(gc_type);
+}
+
 fn throw_type_error<'s>(
   scope: &mut v8::HandleScope<'s>,
   message: impl AsRef<str>,
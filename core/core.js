@@ -59,9 +59,15 @@ SharedQueue Binary Layout
     recv(handleAsyncMsgFromRust);
   }
 
+  // Sync responses are `[opId, buf]` pairs if the embedder enabled
+  // CoreIsolate::set_op_id_in_sync_response(), plain buffers otherwise.
+  function syncResponseBuf(res) {
+    return Array.isArray(res) ? res[1] : res;
+  }
+
   function ops() {
     // op id 0 is a special value to retrieve the map of registered ops.
-    const opsMapBytes = send(0);
+    const opsMapBytes = syncResponseBuf(send(0));
     const opsMapJson = String.fromCharCode.apply(null, opsMapBytes);
     opsCache = JSON.parse(opsMapJson);
     return { ...opsCache };
@@ -201,6 +207,19 @@ SharedQueue Binary Layout
     return className;
   }
 
+  // Returns the value of a `{ ok }` op result, or throws an instance of the
+  // error class registered for the `className` of an `{ err }` result.
+  // Classes that were never registered fall back to Error rather than
+  // asserting like getErrorClass() does, so the op's error still reaches the
+  // caller, with its message, as an exception it can catch.
+  function unwrapOpResult(res) {
+    if ("ok" in res) {
+      return res.ok;
+    }
+    const ErrorClass = errorMap[res.err.className] || Error;
+    throw new ErrorClass(res.err.message);
+  }
+
   // Returns Uint8Array
   function encodeJson(args) {
     const s = JSON.stringify(args);
@@ -215,45 +234,35 @@ SharedQueue Binary Layout
   let nextPromiseId = 1;
   const promiseTable = {};
 
-  function jsonOpAsync(opName, args, ...zeroCopy) {
+  async function jsonOpAsync(opName, args, ...zeroCopy) {
     setAsyncHandler(opsCache[opName], jsonOpAsyncHandler);
 
     args.promiseId = nextPromiseId++;
     const argsBuf = encodeJson(args);
     dispatch(opName, argsBuf, ...zeroCopy);
-    let resolve, reject;
-    const promise = new Promise((resolve_, reject_) => {
+    let resolve;
+    const promise = new Promise((resolve_) => {
       resolve = resolve_;
-      reject = reject_;
     });
     promise.resolve = resolve;
-    promise.reject = reject;
     promiseTable[args.promiseId] = promise;
-    return promise;
+    // The error is thrown after the await rather than rejected from the
+    // handler, so its async stack trace leads back to the caller.
+    return unwrapOpResult(await promise);
   }
 
   function jsonOpSync(opName, args, ...zeroCopy) {
     const argsBuf = encodeJson(args);
-    const res = dispatch(opName, argsBuf, ...zeroCopy);
-    const r = decodeJson(res);
-    if ("ok" in r) {
-      return r.ok;
-    } else {
-      throw r.err;
-    }
+    const res = syncResponseBuf(dispatch(opName, argsBuf, ...zeroCopy));
+    return unwrapOpResult(decodeJson(res));
   }
 
   function jsonOpAsyncHandler(buf) {
     // Json Op.
     const msg = decodeJson(buf);
-    const { ok, err, promiseId } = msg;
-    const promise = promiseTable[promiseId];
-    delete promiseTable[promiseId];
-    if (ok) {
-      promise.resolve(ok);
-    } else {
-      promise.reject(err);
-    }
+    const promise = promiseTable[msg.promiseId];
+    delete promiseTable[msg.promiseId];
+    promise.resolve(msg);
   }
 
   Object.assign(window.Deno.core, {
@@ -265,6 +274,8 @@ SharedQueue Binary Layout
     ops,
     registerErrorClass,
     getErrorClass,
+    unwrapOpResult,
+    syncResponseBuf,
     // sharedQueue is private but exposed for testing.
     sharedQueue: {
       MAX_RECORDS,
@@ -8,8 +8,19 @@
 use rusty_v8 as v8;
 
 use crate::bindings;
+use crate::errors::ExecutionTerminated;
+use crate::errors::SourceLineMode;
+use crate::errors::SourceMapFn;
+use crate::extensions::Extension;
+use crate::json_op::json_op;
+use crate::json_op::JsonOp;
+use crate::metrics::OpCounters;
+use crate::metrics::OpMetrics;
 use crate::ops::*;
+use crate::print::PrintEncoding;
+use crate::print::PrintRateLimit;
 use crate::shared_queue::SharedQueue;
+use crate::shared_queue::SharedQueueStats;
 use crate::shared_queue::RECOMMENDED_SIZE;
 use crate::ErrBox;
 use crate::JSError;
@@ -20,24 +31,39 @@ use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
 use futures::task::AtomicWaker;
 use futures::Future;
-use serde_json::json;
-use serde_json::Value;
+use sourcemap::SourceMap;
 use std::any::Any;
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::From;
+use std::convert::TryFrom;
 use std::ffi::c_void;
+use std::io::Write;
 use std::mem::forget;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::option::Option;
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::rc::Weak;
 use std::sync::Once;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Instant;
 
-type PendingOpFuture = Pin<Box<dyn Future<Output = (OpId, Buf)>>>;
+/// Resolves to the op's response, or to the panic message if polling the
+/// op's future panicked, along with the realm that dispatched it.
+type PendingOpFuture =
+  Pin<Box<dyn Future<Output = (RealmId, OpId, Result<Buf, String>)>>>;
+
+/// Identifies a context of the isolate that ops are dispatched from. The
+/// global context is `MAIN_REALM`; every `ChildContext` has its own id.
+pub type RealmId = u32;
+
+pub(crate) const MAIN_REALM: RealmId = 0;
 
 /// Stores a script used to initialize a Isolate
 pub struct Script<'a> {
@@ -61,6 +87,20 @@ impl From<Script<'_>> for OwnedScript {
   }
 }
 
+/// Locates a script within the resource it was taken from, e.g. a script
+/// extracted from the middle of a larger file, so that stack traces and error
+/// messages report positions in that resource.
+#[derive(Clone, Debug, Default)]
+pub struct ScriptOriginOptions {
+  /// Zero-based line of the resource the script starts at.
+  pub line_offset: i32,
+  /// Zero-based column of the resource the script's first line starts at.
+  pub column_offset: i32,
+  /// URL of the script's source map, as if the script ended with a
+  /// `//# sourceMappingURL=` comment.
+  pub source_map_url: Option<String>,
+}
+
 pub enum Snapshot {
   Static(&'static [u8]),
   JustCreated(v8::StartupData),
@@ -87,11 +127,16 @@ impl StartupData<'_> {
 
 type JSErrorCreateFn = dyn Fn(JSError) -> ErrBox;
 
+type UnhandledRejectionFn = dyn Fn(serde_json::Value);
+
+type MacrotaskFn = dyn FnMut(&mut v8::HandleScope) -> bool;
+
 pub type GetErrorClassFn = &'static dyn for<'e> Fn(&'e ErrBox) -> &'static str;
 
 /// Objects that need to live as long as the isolate
 #[derive(Default)]
 struct IsolateAllocations {
+  external_references: Option<Box<v8::ExternalReferences>>,
   near_heap_limit_callback_data:
     Option<(Box<RefCell<dyn Any>>, v8::NearHeapLimitCallback)>,
 }
@@ -111,6 +156,9 @@ pub struct CoreIsolate {
   snapshot_creator: Option<v8::SnapshotCreator>,
   has_snapshotted: bool,
   needs_init: bool,
+  /// Whether the isolate was restored from a snapshot, which already holds
+  /// the effects of the extensions' JavaScript.
+  snapshot_loaded: bool,
   startup_script: Option<OwnedScript>,
   allocations: IsolateAllocations,
 }
@@ -122,18 +170,116 @@ pub struct CoreIsolateState {
   pub global_context: Option<v8::Global<v8::Context>>,
   pub(crate) shared_ab: Option<v8::Global<v8::SharedArrayBuffer>>,
   pub(crate) js_recv_cb: Option<v8::Global<v8::Function>>,
+  /// Set with `CoreIsolate::set_allow_recv_replacement()`.
+  pub(crate) allow_recv_replacement: bool,
+  /// Set with `Deno.core.setOpResponseHandler()`, keyed by op id.
+  pub(crate) js_op_response_cbs: HashMap<OpId, v8::Global<v8::Function>>,
   pub(crate) js_macrotask_cb: Option<v8::Global<v8::Function>>,
-  pub(crate) pending_promise_exceptions: HashMap<i32, v8::Global<v8::Value>>,
+  pub(crate) js_idle_cb: Option<v8::Global<v8::Function>>,
+  pub(crate) js_unhandled_rejection_cb: Option<v8::Global<v8::Function>>,
+  /// Functions invoked before and after each macrotask, registered with
+  /// `Deno.core.setTaskHooks()`.
+  pub(crate) js_task_hooks:
+    Option<(v8::Global<v8::Function>, v8::Global<v8::Function>)>,
+  next_task_id: u64,
+  stack_trace_limit: usize,
+  extensions: Rc<[Extension]>,
+  /// Number of contexts currently alive in this isolate, including the
+  /// global context.
+  pub(crate) live_contexts: Rc<Cell<usize>>,
+  /// Rejected promises without a handler and their rejection reasons, keyed
+  /// by the promise's identity hash.
+  pub(crate) pending_promise_exceptions:
+    HashMap<i32, (v8::Global<v8::Promise>, v8::Global<v8::Value>)>,
   pub(crate) js_error_create_fn: Box<JSErrorCreateFn>,
+  unhandled_rejection_cb: Option<Rc<UnhandledRejectionFn>>,
   pub get_error_class_fn: GetErrorClassFn,
   pub(crate) shared: SharedQueue,
+  /// Stack size in bytes set by `CoreIsolate::run_with_stack_size()` while
+  /// it is running, `None` when V8's default stack limit is in effect.
+  pub(crate) stack_size: Option<usize>,
+  /// Address of the stack limit V8 enforces while `run_with_stack_size()`
+  /// is running.
+  pub(crate) stack_limit: Option<usize>,
+  /// Per-op metrics, `None` unless enabled with
+  /// `CoreIsolate::set_op_metrics_enabled()`.
+  pub(crate) op_metrics: Option<OpMetrics>,
+  /// Sinks `Deno.core.print()` writes to, stdout and stderr by default.
+  pub(crate) stdout: Box<dyn Write + Send>,
+  pub(crate) stderr: Box<dyn Write + Send>,
+  pub(crate) print_encoding: PrintEncoding,
+  pub(crate) print_rate_limit: Option<PrintRateLimit>,
+  pub(crate) source_line_mode: SourceLineMode,
+  /// Source maps added with `CoreIsolate::add_source_map()`, keyed by the
+  /// name of the script or module they belong to.
+  source_maps: HashMap<String, SourceMap>,
+  /// Applied to the location of every `JSError` and exposed to JavaScript as
+  /// `Deno.core.applySourceMap()`.
+  source_map_fn: Option<Rc<SourceMapFn>>,
+  /// Whether sync responses are returned as `[opId, buf]` instead of `buf`.
+  pub(crate) op_id_in_sync_response: bool,
   pending_ops: FuturesUnordered<PendingOpFuture>,
   pending_unref_ops: FuturesUnordered<PendingOpFuture>,
+  /// Set with `CoreIsolate::set_max_pending_ops()`.
+  max_pending_ops: Option<usize>,
   have_unpolled_ops: bool,
+  is_idle: bool,
+  gc_on_idle: bool,
+  /// Set by `exception_to_err_result()` when the error it reports was caused
+  /// by `terminate_execution()`.
+  terminated: bool,
   pub op_registry: OpRegistry,
+  /// Interceptors added with `CoreIsolate::add_op_interceptor()`, in the
+  /// order they run.
+  op_interceptors: Vec<Rc<OpInterceptor>>,
+  /// Callbacks added with `CoreIsolate::add_macrotask_callback()`.
+  macrotask_cbs: Vec<Box<MacrotaskFn>>,
+  /// Addresses of the data stored with `set_host_data()` that has not been
+  /// taken back yet. Only these are ever dereferenced.
+  pub(crate) host_data: HashSet<usize>,
+  /// Op response delivery of the contexts created with `new_context()`. The
+  /// global context uses `js_recv_cb` and `shared` instead.
+  realms: HashMap<RealmId, Realm>,
+  next_realm_id: RealmId,
   waker: AtomicWaker,
 }
 
+/// The receive callback and shared queue of a child realm.
+struct Realm {
+  context: v8::Global<v8::Context>,
+  js_recv_cb: Option<v8::Global<v8::Function>>,
+  shared: SharedQueue,
+}
+
+/// An additional context created with `CoreIsolate::new_context()`. It counts
+/// towards `Deno.core.contextCount()` until it is dropped.
+pub struct ChildContext {
+  context: v8::Global<v8::Context>,
+  realm_id: RealmId,
+  state: Weak<RefCell<CoreIsolateState>>,
+  live_contexts: Rc<Cell<usize>>,
+}
+
+impl ChildContext {
+  pub fn get(&self) -> &v8::Global<v8::Context> {
+    &self.context
+  }
+
+  pub fn realm_id(&self) -> RealmId {
+    self.realm_id
+  }
+}
+
+impl Drop for ChildContext {
+  fn drop(&mut self) {
+    self.live_contexts.set(self.live_contexts.get() - 1);
+    // Responses to ops the realm still has pending are discarded.
+    if let Some(state_rc) = self.state.upgrade() {
+      state_rc.borrow_mut().realms.remove(&self.realm_id);
+    }
+  }
+}
+
 impl Deref for CoreIsolate {
   type Target = v8::Isolate;
   fn deref(&self) -> &v8::Isolate {
@@ -204,26 +350,81 @@ pub struct HeapLimits {
   pub max: usize,
 }
 
-pub(crate) struct IsolateOptions {
-  will_snapshot: bool,
-  startup_script: Option<OwnedScript>,
-  startup_snapshot: Option<Snapshot>,
-  heap_limits: Option<HeapLimits>,
+/// Heap usage of an isolate in bytes, as reported by V8.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeapStats {
+  pub total_heap_size: usize,
+  pub used_heap_size: usize,
+  pub heap_size_limit: usize,
+  /// Memory held outside the V8 heap by objects such as ArrayBuffers.
+  pub external_memory: usize,
+  pub number_of_native_contexts: usize,
+  /// Contexts that are no longer in use but have not been garbage collected
+  /// yet. A number that keeps growing points to a context leak.
+  pub number_of_detached_contexts: usize,
+}
+
+impl HeapStats {
+  pub(crate) fn get(isolate: &mut v8::Isolate) -> Self {
+    let mut stats = v8::HeapStatistics::default();
+    isolate.get_heap_statistics(&mut stats);
+    Self {
+      total_heap_size: stats.total_heap_size(),
+      used_heap_size: stats.used_heap_size(),
+      heap_size_limit: stats.heap_size_limit(),
+      external_memory: stats.external_memory(),
+      number_of_native_contexts: stats.number_of_native_contexts(),
+      number_of_detached_contexts: stats.number_of_detached_contexts(),
+    }
+  }
+}
+
+/// V8's default stack size (the `--stack-size` flag), in bytes.
+pub(crate) const DEFAULT_V8_STACK_SIZE: usize = 984 * 1024;
+
+/// Stack space kept free below V8's stack limit for native frames (ops,
+/// bindings, V8 runtime functions) that are not checked against the limit.
+const STACK_LIMIT_MARGIN: usize = 64 * 1024;
+
+/// Number of stack frames V8 captures for uncaught exceptions by default.
+const DEFAULT_STACK_TRACE_LIMIT: usize = 10;
+
+/// Settings for `CoreIsolate::with_options()`, for isolates that need more
+/// than one of them. The defaults are those of
+/// `CoreIsolate::new(StartupData::None, false)`.
+pub struct IsolateOptions<'a> {
+  /// The snapshot or script used at startup to initialize the isolate.
+  pub startup_data: StartupData<'a>,
+  pub will_snapshot: bool,
+  /// See `CoreIsolate::with_heap_limits()`.
+  pub heap_limits: Option<HeapLimits>,
+  /// See `CoreIsolate::with_stack_trace_limit()`.
+  pub stack_trace_limit: usize,
+  /// See `CoreIsolate::with_extensions()`.
+  pub extensions: Vec<Extension>,
+}
+
+impl Default for IsolateOptions<'_> {
+  fn default() -> Self {
+    Self {
+      startup_data: StartupData::None,
+      will_snapshot: false,
+      heap_limits: None,
+      stack_trace_limit: DEFAULT_STACK_TRACE_LIMIT,
+      extensions: Vec::new(),
+    }
+  }
 }
 
 impl CoreIsolate {
   /// startup_data defines the snapshot or script used at startup to initialize
   /// the isolate.
   pub fn new(startup_data: StartupData, will_snapshot: bool) -> Self {
-    let (startup_script, startup_snapshot) = startup_data.into_options();
-    let options = IsolateOptions {
+    Self::with_options(IsolateOptions {
+      startup_data,
       will_snapshot,
-      startup_script,
-      startup_snapshot,
-      heap_limits: None,
-    };
-
-    Self::from_options(options)
+      ..Default::default()
+    })
   }
 
   /// This is useful for controlling memory usage of scripts.
@@ -236,58 +437,100 @@ impl CoreIsolate {
     startup_data: StartupData,
     heap_limits: HeapLimits,
   ) -> Self {
-    let (startup_script, startup_snapshot) = startup_data.into_options();
-    let options = IsolateOptions {
-      will_snapshot: false,
-      startup_script,
-      startup_snapshot,
+    Self::with_options(IsolateOptions {
+      startup_data,
       heap_limits: Some(heap_limits),
-    };
+      ..Default::default()
+    })
+  }
+
+  /// Like `new()`, but captures up to `frames` stack frames for errors
+  /// instead of V8's default of 10, so that errors thrown from deep call
+  /// chains keep their full stack in `JSError::frames`. This sets both the
+  /// limit for uncaught exceptions and `Error.stackTraceLimit`.
+  pub fn with_stack_trace_limit(
+    startup_data: StartupData,
+    will_snapshot: bool,
+    frames: usize,
+  ) -> Self {
+    Self::with_options(IsolateOptions {
+      startup_data,
+      will_snapshot,
+      stack_trace_limit: frames,
+      ..Default::default()
+    })
+  }
 
-    Self::from_options(options)
+  /// Like `new()`, but installs the given extensions in addition to the
+  /// built-in `Deno.core` bindings.
+  pub fn with_extensions(
+    startup_data: StartupData,
+    will_snapshot: bool,
+    extensions: Vec<Extension>,
+  ) -> Self {
+    Self::with_options(IsolateOptions {
+      startup_data,
+      will_snapshot,
+      extensions,
+      ..Default::default()
+    })
   }
 
-  fn from_options(options: IsolateOptions) -> Self {
+  /// Creates an isolate with any combination of the settings the other
+  /// constructors take.
+  pub fn with_options(options: IsolateOptions) -> Self {
     static DENO_INIT: Once = Once::new();
     DENO_INIT.call_once(|| {
       unsafe { v8_init() };
     });
 
+    let extensions: Rc<[Extension]> = options.extensions.into();
+    let owned_external_references = bindings::external_references(&extensions);
+    let external_references: &'static v8::ExternalReferences =
+      match &owned_external_references {
+        // The references are kept in `allocations`, which is dropped after
+        // the isolate, so they outlive it as V8 requires.
+        Some(references) => unsafe { &*(&**references as *const _) },
+        None => &bindings::EXTERNAL_REFERENCES,
+      };
+
+    let (startup_script, startup_snapshot) =
+      options.startup_data.into_options();
+    let snapshot_loaded = startup_snapshot.is_some();
     let global_context;
     let (mut isolate, maybe_snapshot_creator) = if options.will_snapshot {
       // TODO(ry) Support loading snapshots before snapshotting.
-      assert!(options.startup_snapshot.is_none());
-      let mut creator =
-        v8::SnapshotCreator::new(Some(&bindings::EXTERNAL_REFERENCES));
+      assert!(startup_snapshot.is_none());
+      let mut creator = v8::SnapshotCreator::new(Some(external_references));
       let isolate = unsafe { creator.get_owned_isolate() };
-      let mut isolate = CoreIsolate::setup_isolate(isolate);
+      let mut isolate =
+        CoreIsolate::setup_isolate(isolate, options.stack_trace_limit);
       {
         let scope = &mut v8::HandleScope::new(&mut isolate);
-        let context = bindings::initialize_context(scope);
+        let context = bindings::initialize_context(scope, &extensions, None);
+        set_error_stack_trace_limit(scope, context, options.stack_trace_limit);
         global_context = v8::Global::new(scope, context);
         creator.set_default_context(context);
       }
       (isolate, Some(creator))
     } else {
       let mut params = v8::Isolate::create_params()
-        .external_references(&**bindings::EXTERNAL_REFERENCES);
-      let snapshot_loaded = if let Some(snapshot) = options.startup_snapshot {
+        .external_references(&**external_references);
+      if let Some(snapshot) = startup_snapshot {
         params = match snapshot {
           Snapshot::Static(data) => params.snapshot_blob(data),
           Snapshot::JustCreated(data) => params.snapshot_blob(data),
           Snapshot::Boxed(data) => params.snapshot_blob(data),
         };
-        true
-      } else {
-        false
-      };
+      }
 
       if let Some(heap_limits) = options.heap_limits {
         params = params.heap_limits(heap_limits.initial, heap_limits.max)
       }
 
       let isolate = v8::Isolate::new(params);
-      let mut isolate = CoreIsolate::setup_isolate(isolate);
+      let mut isolate =
+        CoreIsolate::setup_isolate(isolate, options.stack_trace_limit);
       {
         let scope = &mut v8::HandleScope::new(&mut isolate);
         let context = if snapshot_loaded {
@@ -295,8 +538,9 @@ impl CoreIsolate {
         } else {
           // If no snapshot is provided, we initialize the context with empty
           // main source code and source maps.
-          bindings::initialize_context(scope)
+          bindings::initialize_context(scope, &extensions, None)
         };
+        set_error_stack_trace_limit(scope, context, options.stack_trace_limit);
         global_context = v8::Global::new(scope, context);
       }
       (isolate, None)
@@ -308,14 +552,44 @@ impl CoreIsolate {
       pending_promise_exceptions: HashMap::new(),
       shared_ab: None,
       js_recv_cb: None,
+      allow_recv_replacement: false,
+      js_op_response_cbs: HashMap::new(),
       js_macrotask_cb: None,
+      js_idle_cb: None,
+      js_unhandled_rejection_cb: None,
+      js_task_hooks: None,
+      next_task_id: 0,
+      stack_trace_limit: options.stack_trace_limit,
+      extensions,
+      live_contexts: Rc::new(Cell::new(1)),
       js_error_create_fn: Box::new(JSError::create),
-      get_error_class_fn: &|_| "Error",
+      unhandled_rejection_cb: None,
+      get_error_class_fn: &crate::errors::get_error_class,
       shared: SharedQueue::new(RECOMMENDED_SIZE),
+      stack_size: None,
+      stack_limit: None,
+      op_metrics: None,
+      stdout: Box::new(std::io::stdout()),
+      stderr: Box::new(std::io::stderr()),
+      print_encoding: PrintEncoding::default(),
+      print_rate_limit: None,
+      source_line_mode: SourceLineMode::default(),
+      source_maps: HashMap::new(),
+      source_map_fn: None,
+      op_id_in_sync_response: false,
       pending_ops: FuturesUnordered::new(),
       pending_unref_ops: FuturesUnordered::new(),
+      max_pending_ops: None,
       have_unpolled_ops: false,
+      is_idle: false,
+      gc_on_idle: false,
+      terminated: false,
       op_registry: OpRegistry::new(),
+      op_interceptors: Vec::new(),
+      macrotask_cbs: Vec::new(),
+      host_data: HashSet::new(),
+      realms: HashMap::new(),
+      next_realm_id: MAIN_REALM + 1,
       waker: AtomicWaker::new(),
     })));
 
@@ -324,13 +598,23 @@ impl CoreIsolate {
       snapshot_creator: maybe_snapshot_creator,
       has_snapshotted: false,
       needs_init: true,
-      startup_script: options.startup_script,
-      allocations: IsolateAllocations::default(),
+      snapshot_loaded,
+      startup_script,
+      allocations: IsolateAllocations {
+        external_references: owned_external_references,
+        ..Default::default()
+      },
     }
   }
 
-  fn setup_isolate(mut isolate: v8::OwnedIsolate) -> v8::OwnedIsolate {
-    isolate.set_capture_stack_trace_for_uncaught_exceptions(true, 10);
+  fn setup_isolate(
+    mut isolate: v8::OwnedIsolate,
+    stack_trace_limit: usize,
+  ) -> v8::OwnedIsolate {
+    isolate.set_capture_stack_trace_for_uncaught_exceptions(
+      true,
+      stack_trace_limit.min(i32::MAX as usize) as i32,
+    );
     isolate.set_promise_reject_callback(bindings::promise_reject_callback);
     isolate
   }
@@ -340,11 +624,85 @@ impl CoreIsolate {
     s.clone()
   }
 
+  /// Creates a new context in this isolate, initialized with the same
+  /// `Deno.core` bindings and JavaScript as the global context. The context is
+  /// a realm of its own: responses to the async ops it dispatches go to its
+  /// own shared queue and `Deno.core.recv()` callback. The context is counted
+  /// as live until the returned `ChildContext` is dropped.
+  pub fn new_context(&mut self) -> ChildContext {
+    let state_rc = Self::state(self);
+    let realm_id = {
+      let mut state = state_rc.borrow_mut();
+      let realm_id = state.next_realm_id;
+      state.next_realm_id += 1;
+      realm_id
+    };
+    let mut shared = SharedQueue::new(RECOMMENDED_SIZE);
+
+    let scope = &mut v8::HandleScope::new(self.v8_isolate.as_mut().unwrap());
+    let extensions = state_rc.borrow().extensions.clone();
+    let context = bindings::initialize_context(
+      scope,
+      &extensions,
+      Some((realm_id, &mut shared)),
+    );
+    let stack_trace_limit = state_rc.borrow().stack_trace_limit;
+    set_error_stack_trace_limit(scope, context, stack_trace_limit);
+    state_rc.borrow_mut().realms.insert(
+      realm_id,
+      Realm {
+        context: v8::Global::new(scope, context),
+        js_recv_cb: None,
+        shared,
+      },
+    );
+
+    let scope = &mut v8::ContextScope::new(scope, context);
+    let origin = ScriptOriginOptions::default();
+    js_check(execute_script(
+      scope,
+      "core.js",
+      include_str!("core.js"),
+      &origin,
+    ));
+    for extension in extensions.iter() {
+      for (filename, source) in extension.js_files() {
+        js_check(execute_script(scope, filename, source, &origin));
+      }
+    }
+
+    let live_contexts = state_rc.borrow().live_contexts.clone();
+    live_contexts.set(live_contexts.get() + 1);
+    ChildContext {
+      context: v8::Global::new(scope, context),
+      realm_id,
+      state: Rc::downgrade(&state_rc),
+      live_contexts,
+    }
+  }
+
+  /// Returns the number of contexts currently alive in this isolate.
+  pub fn context_count(&self) -> usize {
+    let state_rc = Self::state(self);
+    let state = state_rc.borrow();
+    state.live_contexts.get()
+  }
+
   /// Executes a bit of built-in JavaScript to provide Deno.sharedQueue.
   pub(crate) fn shared_init(&mut self) {
     if self.needs_init {
       self.needs_init = false;
       js_check(self.execute("core.js", include_str!("core.js")));
+      // The effects of the extensions' JavaScript are part of a snapshot, so
+      // it is not run again in an isolate restored from one.
+      if !self.snapshot_loaded {
+        let extensions = Self::state(self).borrow().extensions.clone();
+        for extension in extensions.iter() {
+          for (filename, source) in extension.js_files() {
+            js_check(self.execute(filename, source));
+          }
+        }
+      }
       // Maybe execute the startup script.
       if let Some(s) = self.startup_script.take() {
         self.execute(&s.filename, &s.source).unwrap()
@@ -361,41 +719,91 @@ impl CoreIsolate {
     &mut self,
     js_filename: &str,
     js_source: &str,
+  ) -> Result<(), ErrBox> {
+    self.execute_with_origin(js_filename, js_source, &Default::default())
+  }
+
+  /// Same as `execute()`, but positions in stack traces and errors are
+  /// reported relative to the resource described by `origin`.
+  pub fn execute_with_origin(
+    &mut self,
+    js_filename: &str,
+    js_source: &str,
+    origin: &ScriptOriginOptions,
   ) -> Result<(), ErrBox> {
     self.shared_init();
 
     let state_rc = Self::state(self);
-    let state = state_rc.borrow();
+    let scope = &mut v8::HandleScope::new(self.v8_isolate.as_mut().unwrap());
+    let context =
+      v8::Local::new(scope, state_rc.borrow().global_context.as_ref().unwrap());
+    let scope = &mut v8::ContextScope::new(scope, context);
+    execute_script(scope, js_filename, js_source, origin)
+  }
+
+  /// Executes traditional JavaScript code in a context created with
+  /// `new_context()` rather than in the global context. The context shares
+  /// the isolate's heap and ops but has its own global object, so it can be
+  /// used to evaluate code in a separate realm without spawning an isolate.
+  pub fn execute_in_context(
+    &mut self,
+    context: &ChildContext,
+    js_filename: &str,
+    js_source: &str,
+  ) -> Result<(), ErrBox> {
+    self.shared_init();
+    let scope = &mut v8::HandleScope::with_context(
+      self.v8_isolate.as_mut().unwrap(),
+      context.get(),
+    );
+    execute_script(scope, js_filename, js_source, &Default::default())
+  }
+
+  /// Invokes the callback registered with `Deno.core.setMacrotaskCallback()`
+  /// once, for embedders that drive macrotasks from their own event loop
+  /// rather than through `poll()`. Returns whether more macrotasks remain,
+  /// i.e. `false` once the callback returns true or if none is registered.
+  pub fn run_macrotask(&mut self) -> Result<bool, ErrBox> {
+    self.shared_init();
 
+    let state_rc = Self::state(self);
     let scope = &mut v8::HandleScope::with_context(
       self.v8_isolate.as_mut().unwrap(),
-      state.global_context.as_ref().unwrap(),
+      state_rc.borrow().global_context.as_ref().unwrap(),
     );
 
-    drop(state);
+    let maybe_done = invoke_macrotask_cb(scope)?;
+    Ok(maybe_done == Some(false))
+  }
 
-    let source = v8::String::new(scope, js_source).unwrap();
-    let name = v8::String::new(scope, js_filename).unwrap();
-    let origin = bindings::script_origin(scope, name);
+  /// Sets when V8 runs queued microtasks. With the default,
+  /// `v8::MicrotasksPolicy::Auto`, they run whenever the JavaScript call
+  /// stack becomes empty. With `v8::MicrotasksPolicy::Explicit` they only
+  /// run when `run_microtasks()` is called, so embedders driving the isolate
+  /// from their own executor decide when they flush relative to op
+  /// responses.
+  pub fn set_microtasks_policy(&mut self, policy: v8::MicrotasksPolicy) {
+    self
+      .v8_isolate
+      .as_mut()
+      .unwrap()
+      .set_microtasks_policy(policy);
+  }
 
-    let tc_scope = &mut v8::TryCatch::new(scope);
+  /// Runs all queued microtasks, including ones they queue in turn. Promise
+  /// rejections left unhandled by them are reported as errors, like by
+  /// `poll()`.
+  pub fn run_microtasks(&mut self) -> Result<(), ErrBox> {
+    self.shared_init();
 
-    let script = match v8::Script::compile(tc_scope, source, Some(&origin)) {
-      Some(script) => script,
-      None => {
-        let exception = tc_scope.exception().unwrap();
-        return exception_to_err_result(tc_scope, exception);
-      }
-    };
+    let state_rc = Self::state(self);
+    let scope = &mut v8::HandleScope::with_context(
+      self.v8_isolate.as_mut().unwrap(),
+      state_rc.borrow().global_context.as_ref().unwrap(),
+    );
 
-    match script.run(tc_scope) {
-      Some(_) => Ok(()),
-      None => {
-        assert!(tc_scope.has_caught());
-        let exception = tc_scope.exception().unwrap();
-        exception_to_err_result(tc_scope, exception)
-      }
-    }
+    scope.perform_microtask_checkpoint();
+    check_promise_exceptions(scope)
   }
 
   /// Takes a snapshot. The isolate should have been created with will_snapshot
@@ -435,6 +843,67 @@ impl CoreIsolate {
     state.op_registry.register(name, op)
   }
 
+  /// Same as `register_op()` for an op that always completes asynchronously,
+  /// so it can be written as an `async` block or function without boxing the
+  /// future into an `Op::Async` by hand.
+  ///
+  /// As with every async op, a panic while polling the future fails the
+  /// isolate's poll with an error, and the future is dropped without
+  /// completing if the isolate is dropped first.
+  pub fn register_op_async<F, Fut>(&mut self, name: &str, op: F) -> OpId
+  where
+    Fut: Future<Output = Buf> + 'static,
+    F: Fn(&mut CoreIsolateState, &mut [ZeroCopyBuf]) -> Fut + 'static,
+  {
+    self.register_op(name, move |state, bufs| {
+      Op::Async(op(state, bufs).boxed_local())
+    })
+  }
+
+  /// Adds an interceptor that runs before every op is dispatched, e.g. to
+  /// check permissions, trace or rate-limit ops in one place instead of in
+  /// every op. Interceptors run in the order they were added; the first one
+  /// that doesn't return `OpDecision::Continue` decides the op's outcome.
+  pub fn add_op_interceptor<F>(&mut self, interceptor: F)
+  where
+    F: Fn(&mut CoreIsolateState, OpId, &[ZeroCopyBuf]) -> OpDecision + 'static,
+  {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    state.op_interceptors.push(Rc::new(interceptor));
+  }
+
+  /// Adds a callback that the event loop runs after the macrotasks of
+  /// `Deno.core.setMacrotaskCallback()`, e.g. to fire timers kept in Rust.
+  /// Like that callback it is invoked repeatedly until it returns true, with
+  /// a microtask checkpoint after each invocation. Callbacks run in the order
+  /// they were added. An exception left pending by a callback is returned
+  /// as an error from `poll()`.
+  pub fn add_macrotask_callback<F>(&mut self, cb: F)
+  where
+    F: FnMut(&mut v8::HandleScope) -> bool + 'static,
+  {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    state.macrotask_cbs.push(Box::new(cb));
+  }
+
+  /// Same as `register_op()`, but also records metadata about the op which
+  /// is exposed to JavaScript through `Deno.core.opMeta()`.
+  pub fn register_op_with_meta<F>(
+    &mut self,
+    name: &str,
+    meta: OpMetadata,
+    op: F,
+  ) -> OpId
+  where
+    F: Fn(&mut CoreIsolateState, &mut [ZeroCopyBuf]) -> Op + 'static,
+  {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    state.op_registry.register_with_meta(name, meta, op)
+  }
+
   pub fn register_op_json_sync<F>(&mut self, name: &str, op: F) -> OpId
   where
     F: 'static
@@ -445,12 +914,7 @@ impl CoreIsolate {
       ) -> Result<serde_json::Value, ErrBox>,
   {
     let core_op =
-      move |state: &mut CoreIsolateState, bufs: &mut [ZeroCopyBuf]| -> Op {
-        let value = serde_json::from_slice(&bufs[0]).unwrap();
-        let result = op(state, value, &mut bufs[1..]);
-        let buf = serialize_result(None, result, state.get_error_class_fn);
-        Op::Sync(buf)
-      };
+      json_op(move |state, args, bufs| op(state, args, bufs).map(JsonOp::Sync));
 
     let state_rc = Self::state(self);
     let mut state = state_rc.borrow_mut();
@@ -463,24 +927,215 @@ impl CoreIsolate {
     F: 'static
       + Fn(&mut CoreIsolateState, serde_json::Value, &mut [ZeroCopyBuf]) -> Fut,
   {
-    let core_op = move |state: &mut CoreIsolateState,
-                        bufs: &mut [ZeroCopyBuf]|
-          -> Op {
-      let get_error_class_fn = state.get_error_class_fn;
-      let value: serde_json::Value = serde_json::from_slice(&bufs[0]).unwrap();
-      let promise_id = value.get("promiseId").unwrap().as_u64().unwrap();
-      let fut = op(state, value, &mut bufs[1..]);
-      let fut2 = fut.map(move |result| {
-        serialize_result(Some(promise_id), result, get_error_class_fn)
-      });
-      Op::Async(Box::pin(fut2))
-    };
+    let core_op = json_op(move |state, args, bufs| {
+      Ok(JsonOp::Async(op(state, args, bufs).boxed_local()))
+    });
 
     let state_rc = Self::state(self);
     let mut state = state_rc.borrow_mut();
     state.op_registry.register(name, core_op)
   }
 
+  /// Forcefully terminates the JavaScript currently running in the isolate.
+  /// Use `thread_safe_handle()` to terminate from another thread.
+  ///
+  /// The isolate keeps refusing to run JavaScript until
+  /// `cancel_terminate_execution()` is called, so that termination propagates
+  /// through any native frames still on the stack. Returns false if the
+  /// isolate has already been disposed.
+  pub fn terminate_execution(&mut self) -> bool {
+    // TODO(piscisaureus): in rusty_v8, `terminate_execution()` should also
+    // be implemented on `struct Isolate`.
+    self
+      .v8_isolate
+      .as_mut()
+      .unwrap()
+      .thread_safe_handle()
+      .terminate_execution()
+  }
+
+  /// Resumes normal operation after `terminate_execution()`, whether it was
+  /// called on the isolate or through a thread-safe handle. Exceptions
+  /// thrown afterwards are reported as regular errors again.
+  pub fn cancel_terminate_execution(&mut self) -> bool {
+    // TODO(piscisaureus): in rusty_v8, `cancel_terminate_execution()` should
+    // also be implemented on `struct Isolate`.
+    self
+      .v8_isolate
+      .as_mut()
+      .unwrap()
+      .thread_safe_handle()
+      .cancel_terminate_execution()
+  }
+
+  /// Returns the current heap usage of the isolate, like
+  /// `Deno.core.heapStats()` but without running JavaScript.
+  pub fn heap_statistics(&mut self) -> HeapStats {
+    HeapStats::get(self)
+  }
+
+  /// When enabled, V8 is sent a low-memory notification each time the
+  /// isolate becomes idle, after the callback registered with
+  /// `Deno.core.setIdleCallback()`. V8 then runs full garbage collections and
+  /// returns as much memory as it can, which suits embedders that keep many
+  /// mostly quiescent isolates around. Use `low_memory_notification()`,
+  /// available through `Deref<Target = v8::Isolate>`, to do so on demand.
+  pub fn set_gc_on_idle(&mut self, enabled: bool) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().gc_on_idle = enabled;
+  }
+
+  /// Enables or disables collection of per-op metrics, such as the dispatch
+  /// latency reported by `Deno.core.opLatency()`. Disabling discards any
+  /// metrics collected so far.
+  pub fn set_op_metrics_enabled(&mut self, enabled: bool) {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    if !enabled {
+      state.op_metrics = None;
+    } else if state.op_metrics.is_none() {
+      state.op_metrics = Some(OpMetrics::default());
+    }
+  }
+
+  /// Returns the counters of every op dispatched since metrics were enabled
+  /// or last drained, keyed by op name, like `Deno.core.metrics()`. Returns
+  /// `None` if metrics are disabled.
+  pub fn op_metrics(&self) -> Option<HashMap<String, OpCounters>> {
+    let state_rc = Self::state(self);
+    let state = state_rc.borrow();
+    state
+      .op_metrics
+      .as_ref()
+      .map(|op_metrics| op_metrics.counters_by_name(&state.op_registry))
+  }
+
+  /// Redirects the output of `Deno.core.print()`, e.g. to capture it
+  /// in-process. Defaults to the process' stdout.
+  pub fn set_stdout(&mut self, stdout: Box<dyn Write + Send>) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().stdout = stdout;
+  }
+
+  /// Redirects the output of `Deno.core.print(msg, true)`. Defaults to the
+  /// process' stderr.
+  pub fn set_stderr(&mut self, stderr: Box<dyn Write + Send>) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().stderr = stderr;
+  }
+
+  /// Sets the encoding `Deno.core.print()` transcodes its output to before
+  /// writing it. Defaults to passing UTF-8 through unchanged.
+  pub fn set_print_encoding(&mut self, encoding: PrintEncoding) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().print_encoding = encoding;
+  }
+
+  /// Caps the number of bytes per second `Deno.core.print()` writes; output
+  /// beyond the cap is dropped and reported with a `[N bytes suppressed]`
+  /// notice. `None`, the default, means unlimited.
+  pub fn set_print_rate_limit(&mut self, bytes_per_second: Option<usize>) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().print_rate_limit =
+      bytes_per_second.map(PrintRateLimit::new);
+  }
+
+  /// Sets how much of the offending source line is included in the `JSError`s
+  /// created for exceptions. Defaults to the full line.
+  pub fn set_source_line_mode(&mut self, mode: SourceLineMode) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().source_line_mode = mode;
+  }
+
+  /// Sets a function mapping positions in generated code back to the original
+  /// source, e.g. through source maps. It is applied to the location and
+  /// frames of every `JSError` the isolate creates, and JavaScript can apply
+  /// it to call sites in `Error.prepareStackTrace()` with
+  /// `Deno.core.applySourceMap()`, so that `error.stack` agrees.
+  pub fn set_source_map_fn(
+    &mut self,
+    f: impl Fn(&str, i64, i64) -> Option<(String, i64, i64)> + 'static,
+  ) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().source_map_fn = Some(Rc::new(f));
+  }
+
+  /// Attaches a source map to the script or module named `script_name`, so
+  /// that positions in it are reported in terms of the original source, like
+  /// with `set_source_map_fn()`. For that script the source map takes
+  /// precedence over the function.
+  pub fn add_source_map(
+    &mut self,
+    script_name: &str,
+    source_map: &[u8],
+  ) -> Result<(), ErrBox> {
+    let source_map = SourceMap::from_slice(source_map)?;
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    state
+      .source_maps
+      .insert(script_name.to_string(), source_map);
+    Ok(())
+  }
+
+  /// When enabled, `Deno.core.send()` returns synchronous responses as an
+  /// `[opId, Uint8Array]` pair, with `null` in place of an empty response, so
+  /// that they carry their op id like asynchronous ones. By default only the
+  /// `Uint8Array` is returned. `Deno.core.syncResponseBuf()` returns the
+  /// buffer of a response in either form.
+  pub fn set_op_id_in_sync_response(&mut self, enabled: bool) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().op_id_in_sync_response = enabled;
+  }
+
+  /// Sets the number of bytes the shared queue holds for asynchronous
+  /// responses. Defaults to `RECOMMENDED_SIZE`.
+  ///
+  /// When the queue fills up, the responses in it are handed to JavaScript
+  /// before more are queued, so the size only bounds how many responses are
+  /// delivered per `recv` call. A response larger than the whole queue is
+  /// delivered individually.
+  ///
+  /// Panics if `Deno.core.shared` has already been accessed from JavaScript.
+  pub fn set_shared_queue_size(&mut self, size: usize) {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    assert!(state.shared_ab.is_none(), "shared queue is already in use");
+    state.shared = SharedQueue::new(size);
+  }
+
+  /// Lets `Deno.core.recv()` be called again to replace the callback, e.g.
+  /// to reload core.js or other runtime JavaScript during development.
+  /// Otherwise only the first call succeeds and later ones throw a
+  /// `TypeError`. Disabled by default.
+  pub fn set_allow_recv_replacement(&mut self, allow: bool) {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    state.allow_recv_replacement = allow;
+  }
+
+  /// Caps the number of async ops that may be in flight at once, counting
+  /// both `Op::Async` and `Op::AsyncUnref`, so a runaway script cannot queue
+  /// an unbounded number of futures. There is no limit by default.
+  ///
+  /// Dispatching an async op while the limit is reached drops its future
+  /// before it is polled and throws a `RangeError`. The dispatcher has run
+  /// by then, since only its result tells whether the op is async, but the
+  /// op is not counted in the op metrics. Sync ops are not affected.
+  pub fn set_max_pending_ops(&mut self, limit: Option<usize>) {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    state.max_pending_ops = limit;
+  }
+
+  /// Returns counters describing how the shared queue has been used so far,
+  /// for diagnosing a queue that is too small for the op traffic.
+  pub fn shared_queue_stats(&self) -> SharedQueueStats {
+    let state_rc = Self::state(self);
+    let state = state_rc.borrow();
+    state.shared.stats()
+  }
+
   /// Registers a callback on the isolate when the memory limits are approached.
   /// Use this to prevent V8 from crashing the process when reaching the limit.
   ///
@@ -522,43 +1177,140 @@ impl CoreIsolate {
         .remove_near_heap_limit_callback(cb, heap_limit);
     }
   }
-}
 
-extern "C" fn near_heap_limit_callback<F>(
-  data: *mut c_void,
-  current_heap_limit: usize,
-  initial_heap_limit: usize,
-) -> usize
-where
-  F: FnMut(usize, usize) -> usize,
-{
-  let callback = unsafe { &mut *(data as *mut F) };
-  callback(current_heap_limit, initial_heap_limit)
-}
+  /// Creates an isolate with `create` on a new thread with a stack of
+  /// `stack_size` bytes, and runs `f` on it under `run_with_stack_size()`.
+  /// This allows deeply recursive scripts that would overflow V8's default
+  /// stack. The calling thread is blocked until `f` returns; the isolate is
+  /// dropped on the new thread afterwards.
+  pub fn run_on_thread_with_stack_size<C, F, R>(
+    stack_size: usize,
+    create: C,
+    f: F,
+  ) -> R
+  where
+    C: FnOnce() -> CoreIsolate + Send + 'static,
+    F: FnOnce(&mut CoreIsolate) -> R + Send + 'static,
+    R: Send + 'static,
+  {
+    // The limit is set relative to a frame near the top of the new stack,
+    // which leaves the frames above it some room.
+    let thread_stack_size = stack_size + STACK_LIMIT_MARGIN;
+    let result = std::thread::Builder::new()
+      .stack_size(thread_stack_size)
+      .spawn(move || {
+        let mut isolate = create();
+        // Safety: this thread's stack is larger than `stack_size`.
+        unsafe { isolate.run_with_stack_size(stack_size, f) }
+      })
+      .expect("Failed to spawn thread")
+      .join();
+    match result {
+      Ok(r) => r,
+      Err(err) => std::panic::resume_unwind(err),
+    }
+  }
 
-fn serialize_result(
-  promise_id: Option<u64>,
-  result: Result<Value, ErrBox>,
-  get_error_class_fn: GetErrorClassFn,
-) -> Buf {
-  let value = match result {
-    Ok(v) => json!({ "ok": v, "promiseId": promise_id }),
-    Err(err) => json!({
-      "promiseId": promise_id ,
-      "err": {
-        "className": (get_error_class_fn)(&err),
-        "message": err.to_string(),
-      }
-    }),
-  };
-  serde_json::to_vec(&value).unwrap().into_boxed_slice()
+  /// Runs `f` with V8's stack limit moved to `stack_size` bytes below the
+  /// current stack position. Afterwards the limit of an enclosing call is
+  /// restored, or otherwise the limit V8 derives for the current position
+  /// by default, `DEFAULT_V8_STACK_SIZE` bytes below it.
+  ///
+  /// V8 only checks JS frames against its stack limit. Native frames pushed
+  /// by ops and bindings are not checked, so `STACK_LIMIT_MARGIN` bytes are
+  /// kept free below the limit for them; an op that needs more than that can
+  /// still crash the process with a real stack overflow.
+  ///
+  /// # Safety
+  ///
+  /// The current thread must have at least `stack_size` bytes of stack left
+  /// below the caller's frame. `run_on_thread_with_stack_size()` provides
+  /// such a thread.
+  pub unsafe fn run_with_stack_size<F, R>(
+    &mut self,
+    stack_size: usize,
+    f: F,
+  ) -> R
+  where
+    F: FnOnce(&mut CoreIsolate) -> R,
+  {
+    assert!(
+      stack_size > STACK_LIMIT_MARGIN,
+      "stack size must be larger than {} bytes",
+      STACK_LIMIT_MARGIN
+    );
+
+    let stack_top = &stack_size as *const _ as usize;
+    let stack_limit = stack_top
+      .checked_sub(stack_size - STACK_LIMIT_MARGIN)
+      .expect("stack size exceeds the address space");
+
+    let state_rc = Self::state(self);
+    let (prev_stack_size, prev_stack_limit) = {
+      let mut state = state_rc.borrow_mut();
+      let prev = (state.stack_size, state.stack_limit);
+      state.stack_size = Some(stack_size);
+      state.stack_limit = Some(stack_limit);
+      prev
+    };
+    self.set_stack_limit(stack_limit);
+
+    let result =
+      std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self)));
+
+    let restored_limit = prev_stack_limit
+      .unwrap_or(stack_top.saturating_sub(DEFAULT_V8_STACK_SIZE));
+    self.set_stack_limit(restored_limit);
+    let mut state = state_rc.borrow_mut();
+    state.stack_size = prev_stack_size;
+    state.stack_limit = prev_stack_limit;
+    drop(state);
+
+    match result {
+      Ok(r) => r,
+      Err(err) => std::panic::resume_unwind(err),
+    }
+  }
+}
+
+extern "C" fn near_heap_limit_callback<F>(
+  data: *mut c_void,
+  current_heap_limit: usize,
+  initial_heap_limit: usize,
+) -> usize
+where
+  F: FnMut(usize, usize) -> usize,
+{
+  let callback = unsafe { &mut *(data as *mut F) };
+  callback(current_heap_limit, initial_heap_limit)
 }
 
 impl Future for CoreIsolate {
   type Output = Result<(), ErrBox>;
 
+  /// Runs the event loop until no ops are pending. If the isolate's
+  /// execution is terminated meanwhile, resolves to an `ExecutionTerminated`
+  /// error and drops all pending ops; call `cancel_terminate_execution()`
+  /// before polling the isolate again.
   fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
     let core_isolate = self.get_mut();
+    let state_rc = Self::state(core_isolate);
+    state_rc.borrow_mut().terminated = false;
+    let poll = core_isolate.poll_event_loop(cx);
+    let terminated = std::mem::take(&mut state_rc.borrow_mut().terminated);
+    match poll {
+      Poll::Ready(Err(_)) if terminated => {
+        core_isolate.drop_pending_ops();
+        Poll::Ready(Err(ExecutionTerminated.into()))
+      }
+      poll => poll,
+    }
+  }
+}
+
+impl CoreIsolate {
+  fn poll_event_loop(&mut self, cx: &mut Context) -> Poll<Result<(), ErrBox>> {
+    let core_isolate = self;
     core_isolate.shared_init();
 
     let state_rc = Self::state(core_isolate);
@@ -574,71 +1326,58 @@ impl Future for CoreIsolate {
 
     check_promise_exceptions(scope)?;
 
-    let mut overflow_response: Option<(OpId, Buf)> = None;
-
     loop {
       let mut state = state_rc.borrow_mut();
       // Now handle actual ops.
       state.have_unpolled_ops = false;
 
       let pending_r = state.pending_ops.poll_next_unpin(cx);
-      match pending_r {
+      let (realm_id, op_id, result) = match pending_r {
         Poll::Ready(None) => break,
         Poll::Pending => break,
-        Poll::Ready(Some((op_id, buf))) => {
-          let successful_push = state.shared.push(op_id, &buf);
-          if !successful_push {
-            // If we couldn't push the response to the shared queue, because
-            // there wasn't enough size, we will return the buffer via the
-            // legacy route, using the argument of deno_respond.
-            overflow_response = Some((op_id, buf));
-            break;
-          }
-        }
+        Poll::Ready(Some(response)) => response,
       };
+      let buf = result.map_err(|msg| op_panicked(&state, op_id, msg))?;
+      if let Some(op_metrics) = &mut state.op_metrics {
+        op_metrics.record_completion(op_id, true, buf.len());
+      }
+      drop(state);
+      queue_async_response(scope, realm_id, op_id, buf)?;
     }
 
     loop {
       let mut state = state_rc.borrow_mut();
       let unref_r = state.pending_unref_ops.poll_next_unpin(cx);
-      #[allow(clippy::match_wild_err_arm)]
-      match unref_r {
+      let (realm_id, op_id, result) = match unref_r {
         Poll::Ready(None) => break,
         Poll::Pending => break,
-        Poll::Ready(Some((op_id, buf))) => {
-          let successful_push = state.shared.push(op_id, &buf);
-          if !successful_push {
-            // If we couldn't push the response to the shared queue, because
-            // there wasn't enough size, we will return the buffer via the
-            // legacy route, using the argument of deno_respond.
-            overflow_response = Some((op_id, buf));
-            break;
-          }
-        }
+        Poll::Ready(Some(response)) => response,
       };
+      let buf = result.map_err(|msg| op_panicked(&state, op_id, msg))?;
+      if let Some(op_metrics) = &mut state.op_metrics {
+        op_metrics.record_completion(op_id, true, buf.len());
+      }
+      drop(state);
+      queue_async_response(scope, realm_id, op_id, buf)?;
     }
 
-    {
-      let state = state_rc.borrow();
-      if state.shared.size() > 0 {
-        drop(state);
-        async_op_response(scope, None)?;
-        // The other side should have shifted off all the messages.
-        let state = state_rc.borrow();
-        assert_eq!(state.shared.size(), 0);
-      }
+    flush_shared_queue(scope, MAIN_REALM)?;
+    let realm_ids: Vec<RealmId> =
+      state_rc.borrow().realms.keys().copied().collect();
+    for realm_id in realm_ids {
+      flush_shared_queue(scope, realm_id)?;
     }
 
     {
-      if let Some((op_id, buf)) = overflow_response.take() {
-        async_op_response(scope, Some((op_id, buf)))?;
-      }
-
       drain_macrotasks(scope)?;
 
       check_promise_exceptions(scope)?;
     }
 
+    report_external_memory(scope);
+
+    notify_idle(scope)?;
+
     let state = state_rc.borrow();
     // We're idle if pending_ops is empty.
     if state.pending_ops.is_empty() {
@@ -650,9 +1389,86 @@ impl Future for CoreIsolate {
       Poll::Pending
     }
   }
+
+  /// Drops the futures of all pending ops, and with them the work they
+  /// represent; their responses are never delivered.
+  fn drop_pending_ops(&mut self) {
+    let state_rc = Self::state(self);
+    let mut state = state_rc.borrow_mut();
+    state.pending_ops = FuturesUnordered::new();
+    state.pending_unref_ops = FuturesUnordered::new();
+    state.have_unpolled_ops = false;
+  }
 }
 
 impl CoreIsolateState {
+  /// Returns the slot holding the `Deno.core.recv()` callback of a realm, or
+  /// `None` if the realm has been dropped.
+  pub(crate) fn js_recv_cb_mut(
+    &mut self,
+    realm_id: RealmId,
+  ) -> Option<&mut Option<v8::Global<v8::Function>>> {
+    if realm_id == MAIN_REALM {
+      Some(&mut self.js_recv_cb)
+    } else {
+      self
+        .realms
+        .get_mut(&realm_id)
+        .map(|realm| &mut realm.js_recv_cb)
+    }
+  }
+
+  /// Returns whether a realm exists, i.e. is the global context or belongs to
+  /// a `ChildContext` that has not been dropped.
+  pub(crate) fn has_realm(&self, realm_id: RealmId) -> bool {
+    realm_id == MAIN_REALM || self.realms.contains_key(&realm_id)
+  }
+
+  /// Returns the shared queue of a realm, or `None` if the realm has been
+  /// dropped.
+  fn shared_queue(&mut self, realm_id: RealmId) -> Option<&mut SharedQueue> {
+    if realm_id == MAIN_REALM {
+      Some(&mut self.shared)
+    } else {
+      self
+        .realms
+        .get_mut(&realm_id)
+        .map(|realm| &mut realm.shared)
+    }
+  }
+
+  /// Maps a position in generated code, with 1-based line and column
+  /// numbers, to the original source through the script's source map or the
+  /// function set with `CoreIsolate::set_source_map_fn()`. Positions that are
+  /// not 1-based are never mapped.
+  pub(crate) fn map_location(
+    &self,
+    file_name: &str,
+    line_number: i64,
+    column_number: i64,
+  ) -> Option<(String, i64, i64)> {
+    if line_number < 1 || column_number < 1 {
+      return None;
+    }
+    if let Some(source_map) = self.source_maps.get(file_name) {
+      // Lookups are 0-based.
+      let token = source_map.lookup_token(
+        u32::try_from(line_number - 1).ok()?,
+        u32::try_from(column_number - 1).ok()?,
+      )?;
+      let source = token.get_source()?;
+      return Some((
+        source.to_string(),
+        i64::from(token.get_src_line()) + 1,
+        i64::from(token.get_src_col()) + 1,
+      ));
+    }
+    self
+      .source_map_fn
+      .as_ref()
+      .and_then(|f| f(file_name, line_number, column_number))
+  }
+
   /// Defines the how Deno.core.dispatch() acts.
   /// Called whenever Deno.core.dispatch() is called in JavaScript. zero_copy_buf
   /// corresponds to the second argument of Deno.core.dispatch().
@@ -675,18 +1491,63 @@ impl CoreIsolateState {
     self.js_error_create_fn = Box::new(f);
   }
 
+  /// Sets the function naming the JavaScript error class of errors returned
+  /// by JSON ops. `Deno.core.jsonOpSync()` and `Deno.core.jsonOpAsync()`
+  /// throw an instance of the class registered under that name with
+  /// `Deno.core.registerErrorClass()`, or a plain `Error` if there is none.
+  /// Defaults to `deno_core::get_error_class()`.
   pub fn set_get_error_class_fn(&mut self, f: GetErrorClassFn) {
     self.get_error_class_fn = f;
   }
 
+  /// Sets a callback invoked with the JSON-encoded `JSError` of every promise
+  /// rejection that is still unhandled when the isolate reports it. Promises
+  /// that get a rejection handler before that point, or whose rejection is
+  /// handled by the callback registered with
+  /// `Deno.core.setUnhandledRejectionCallback()`, don't trigger it.
+  pub fn set_unhandled_rejection_cb(
+    &mut self,
+    f: impl Fn(serde_json::Value) + 'static,
+  ) {
+    self.unhandled_rejection_cb = Some(Rc::new(f));
+  }
+
+  fn intercept_op(
+    &mut self,
+    op_id: OpId,
+    zero_copy_bufs: &[ZeroCopyBuf],
+  ) -> OpDecision {
+    // Cloned so that the state can be passed to the interceptors mutably.
+    let interceptors = self.op_interceptors.clone();
+    for interceptor in interceptors {
+      match interceptor(self, op_id, zero_copy_bufs) {
+        OpDecision::Continue => {}
+        decision => return decision,
+      }
+    }
+    OpDecision::Continue
+  }
+
   pub fn dispatch_op<'s>(
     &mut self,
     scope: &mut v8::HandleScope<'s>,
     op_id: OpId,
     zero_copy_bufs: &mut [ZeroCopyBuf],
   ) -> Option<(OpId, Box<[u8]>)> {
-    let op = if let Some(dispatcher) = self.op_registry.get(op_id) {
-      dispatcher(self, zero_copy_bufs)
+    self.dispatch_op_in_realm(scope, MAIN_REALM, op_id, zero_copy_bufs)
+  }
+
+  /// Like `dispatch_op()`, but the response of an async op is delivered to
+  /// the realm `realm_id` rather than to the global context.
+  pub(crate) fn dispatch_op_in_realm<'s>(
+    &mut self,
+    scope: &mut v8::HandleScope<'s>,
+    realm_id: RealmId,
+    op_id: OpId,
+    zero_copy_bufs: &mut [ZeroCopyBuf],
+  ) -> Option<(OpId, Box<[u8]>)> {
+    let dispatcher = if let Some(dispatcher) = self.op_registry.get(op_id) {
+      dispatcher
     } else {
       let message =
         v8::String::new(scope, &format!("Unknown op id: {}", op_id)).unwrap();
@@ -695,40 +1556,190 @@ impl CoreIsolateState {
       return None;
     };
 
+    let start = self.op_metrics.as_ref().map(|_| Instant::now());
+    let bytes_received = zero_copy_bufs.iter().map(|buf| buf.len()).sum();
+    let op = match self.intercept_op(op_id, zero_copy_bufs) {
+      OpDecision::Continue => dispatcher(self, zero_copy_bufs),
+      OpDecision::Respond(op) => op,
+      OpDecision::Deny(message) => {
+        let message = v8::String::new(scope, &message).unwrap();
+        let exception = v8::Exception::type_error(scope, message);
+        scope.throw_exception(exception);
+        return None;
+      }
+    };
+    if let (Op::Async(_), Some(limit)) | (Op::AsyncUnref(_), Some(limit)) =
+      (&op, self.max_pending_ops)
+    {
+      if self.pending_ops.len() + self.pending_unref_ops.len() >= limit {
+        let message =
+          format!("Too many pending async ops (limit is {})", limit);
+        let message = v8::String::new(scope, &message).unwrap();
+        let exception = v8::Exception::range_error(scope, message);
+        scope.throw_exception(exception);
+        return None;
+      }
+    }
+
+    // Only the interceptors and the dispatcher call itself are measured; for
+    // async ops the time until their future resolves is not included.
+    if let (Some(op_metrics), Some(start)) = (&mut self.op_metrics, start) {
+      op_metrics.record_dispatch(op_id, start.elapsed(), bytes_received);
+    }
+
     debug_assert_eq!(self.shared.size(), 0);
     match op {
       Op::Sync(buf) => {
+        if let Some(op_metrics) = &mut self.op_metrics {
+          op_metrics.record_completion(op_id, false, buf.len());
+        }
         // For sync messages, we always return the response via Deno.core.send's
-        // return value. Sync messages ignore the op_id.
-        let op_id = 0;
+        // return value. The op_id is only passed back to JS if enabled with
+        // CoreIsolate::set_op_id_in_sync_response().
         Some((op_id, buf))
       }
       Op::Async(fut) => {
-        let fut2 = fut.map(move |buf| (op_id, buf));
-        self.pending_ops.push(fut2.boxed_local());
+        self.pending_ops.push(catch_op_panic(realm_id, op_id, fut));
         self.have_unpolled_ops = true;
+        self.is_idle = false;
         None
       }
       Op::AsyncUnref(fut) => {
-        let fut2 = fut.map(move |buf| (op_id, buf));
-        self.pending_unref_ops.push(fut2.boxed_local());
+        self
+          .pending_unref_ops
+          .push(catch_op_panic(realm_id, op_id, fut));
         self.have_unpolled_ops = true;
+        self.is_idle = false;
         None
       }
     }
   }
 }
 
+/// Wraps the future of an async op so that a panic while polling it is
+/// turned into an error returned from `CoreIsolate::poll()`, instead of
+/// unwinding through the embedder's executor.
+fn catch_op_panic(
+  realm_id: RealmId,
+  op_id: OpId,
+  fut: OpAsyncFuture,
+) -> PendingOpFuture {
+  AssertUnwindSafe(fut)
+    .catch_unwind()
+    .map(move |result| {
+      let result = result.map_err(|payload| {
+        if let Some(msg) = payload.downcast_ref::<&str>() {
+          msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+          msg.clone()
+        } else {
+          "Box<Any>".to_string()
+        }
+      });
+      (realm_id, op_id, result)
+    })
+    .boxed_local()
+}
+
+fn op_panicked(state: &CoreIsolateState, op_id: OpId, msg: String) -> ErrBox {
+  let name = state.op_registry.name(op_id).unwrap_or("<unknown>");
+  ErrBox::error(format!("op \"{}\" panicked: {}", name, msg))
+}
+
+/// Pushes an async op response onto the shared queue of the realm that
+/// dispatched the op. If the queue is full, the responses already in it are
+/// handed to JavaScript first so that a burst of completions is delivered in
+/// batches rather than one `recv` call each. Only a response too large for
+/// the empty queue is delivered on its own. Responses for a dropped realm are
+/// discarded.
+fn queue_async_response<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  realm_id: RealmId,
+  op_id: OpId,
+  buf: Buf,
+) -> Result<(), ErrBox> {
+  let state_rc = CoreIsolate::state(scope);
+  if !state_rc.borrow().has_realm(realm_id) {
+    return Ok(());
+  }
+
+  let js_op_response_cb = state_rc
+    .borrow()
+    .js_op_response_cbs
+    .get(&op_id)
+    .map(|cb| v8::Local::new(scope, cb));
+  if let Some(js_op_response_cb) = js_op_response_cb {
+    // Responses queued earlier are delivered first to keep completion order.
+    flush_shared_queue(scope, realm_id)?;
+    return op_response_cb_call(scope, js_op_response_cb, buf);
+  }
+
+  let push = |op_id: OpId, buf: &[u8]| {
+    let mut state = state_rc.borrow_mut();
+    state.shared_queue(realm_id).unwrap().push(op_id, buf)
+  };
+  if push(op_id, &buf) {
+    return Ok(());
+  }
+
+  let queue_size = state_rc.borrow_mut().shared_queue(realm_id).unwrap().size();
+  if queue_size > 0 {
+    state_rc
+      .borrow_mut()
+      .shared_queue(realm_id)
+      .unwrap()
+      .record_full();
+    flush_shared_queue(scope, realm_id)?;
+    if push(op_id, &buf) {
+      return Ok(());
+    }
+  }
+
+  state_rc
+    .borrow_mut()
+    .shared_queue(realm_id)
+    .unwrap()
+    .record_overflow();
+  async_op_response(scope, realm_id, Some((op_id, buf)))
+}
+
+/// Hands the responses in a realm's shared queue, if any, to JavaScript.
+fn flush_shared_queue<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  realm_id: RealmId,
+) -> Result<(), ErrBox> {
+  let state_rc = CoreIsolate::state(scope);
+  let queue_size = |state: &mut CoreIsolateState| {
+    state
+      .shared_queue(realm_id)
+      .map_or(0, |shared| shared.size())
+  };
+  if queue_size(&mut state_rc.borrow_mut()) == 0 {
+    return Ok(());
+  }
+  async_op_response(scope, realm_id, None)?;
+  // The other side should have shifted off all the messages.
+  assert_eq!(queue_size(&mut state_rc.borrow_mut()), 0);
+  Ok(())
+}
+
+/// Calls the `Deno.core.recv()` callback of a realm in the realm's context.
 fn async_op_response<'s>(
   scope: &mut v8::HandleScope<'s>,
+  realm_id: RealmId,
   maybe_buf: Option<(OpId, Box<[u8]>)>,
 ) -> Result<(), ErrBox> {
-  let context = scope.get_current_context();
+  let state_rc = CoreIsolate::state(scope);
+  let context = match state_rc.borrow().realms.get(&realm_id) {
+    Some(realm) => v8::Local::new(scope, &realm.context),
+    None => scope.get_current_context(),
+  };
+  let scope = &mut v8::ContextScope::new(scope, context);
   let global: v8::Local<v8::Value> = context.global(scope).into();
-  let js_recv_cb = CoreIsolate::state(scope)
-    .borrow()
-    .js_recv_cb
-    .as_ref()
+  let js_recv_cb = state_rc
+    .borrow_mut()
+    .js_recv_cb_mut(realm_id)
+    .and_then(|cb| cb.as_ref())
     .map(|cb| v8::Local::new(scope, cb))
     .expect("Deno.core.recv has not been called.");
 
@@ -751,7 +1762,107 @@ fn async_op_response<'s>(
   }
 }
 
+fn op_response_cb_call<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  js_op_response_cb: v8::Local<'s, v8::Function>,
+  buf: Buf,
+) -> Result<(), ErrBox> {
+  let context = scope.get_current_context();
+  let global: v8::Local<v8::Value> = context.global(scope).into();
+
+  let tc_scope = &mut v8::TryCatch::new(scope);
+  let ui8: v8::Local<v8::Value> =
+    bindings::boxed_slice_to_uint8array(tc_scope, buf).into();
+  js_op_response_cb.call(tc_scope, global, &[ui8]);
+
+  match tc_scope.exception() {
+    None => Ok(()),
+    Some(exception) => exception_to_err_result(tc_scope, exception),
+  }
+}
+
+/// Sets `Error.stackTraceLimit`, which bounds the call sites passed to
+/// `Error.prepareStackTrace()` and thereby `JSError::frames`.
+fn set_error_stack_trace_limit<'s>(
+  scope: &mut v8::HandleScope<'s, ()>,
+  context: v8::Local<'s, v8::Context>,
+  limit: usize,
+) {
+  if limit == DEFAULT_STACK_TRACE_LIMIT {
+    return;
+  }
+  let scope = &mut v8::ContextScope::new(scope, context);
+  let global = context.global(scope);
+  let error_key = v8::String::new(scope, "Error").unwrap();
+  let error_ctor = global.get(scope, error_key.into()).unwrap();
+  let error_ctor = v8::Local::<v8::Object>::try_from(error_ctor).unwrap();
+  let limit_key = v8::String::new(scope, "stackTraceLimit").unwrap();
+  let limit_val = v8::Number::new(scope, limit as f64);
+  error_ctor.set(scope, limit_key.into(), limit_val.into());
+}
+
+/// Tells V8 about external memory acquired or released by resources since
+/// the last poll, see `ResourceTable::add_with_external_memory()`.
+fn report_external_memory(scope: &mut v8::HandleScope) {
+  let resource_table =
+    CoreIsolate::state(scope).borrow().resource_table.clone();
+  let change = resource_table.borrow_mut().take_external_memory_change();
+  if change != 0 {
+    scope.adjust_amount_of_external_allocated_memory(change);
+  }
+}
+
 fn drain_macrotasks<'s>(scope: &mut v8::HandleScope<'s>) -> Result<(), ErrBox> {
+  // Repeatedly invoke macrotask callback until it returns true (done),
+  // such that ready microtasks would be automatically run before
+  // next macrotask is processed.
+  while let Some(false) = invoke_macrotask_cb(scope)? {}
+
+  // The callbacks are taken out of the state while they run, so that they
+  // can use the isolate and add more callbacks. The guard puts them back
+  // even if one of them throws or panics.
+  let state_rc = CoreIsolate::state(scope);
+  let cbs = std::mem::take(&mut state_rc.borrow_mut().macrotask_cbs);
+  let mut guard = MacrotaskCbsGuard { state_rc, cbs };
+  for cb in guard.cbs.iter_mut() {
+    loop {
+      let tc_scope = &mut v8::TryCatch::new(scope);
+      let is_done = cb(tc_scope);
+      if let Some(exception) = tc_scope.exception() {
+        return exception_to_err_result(tc_scope, exception);
+      }
+      tc_scope.perform_microtask_checkpoint();
+      if is_done {
+        break;
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Restores the callbacks added with `CoreIsolate::add_macrotask_callback()`
+/// after `drain_macrotasks()` ran them, ahead of any added meanwhile.
+struct MacrotaskCbsGuard {
+  state_rc: Rc<RefCell<CoreIsolateState>>,
+  cbs: Vec<Box<MacrotaskFn>>,
+}
+
+impl Drop for MacrotaskCbsGuard {
+  fn drop(&mut self) {
+    let mut state = self.state_rc.borrow_mut();
+    let mut cbs = std::mem::take(&mut self.cbs);
+    cbs.append(&mut state.macrotask_cbs);
+    state.macrotask_cbs = cbs;
+  }
+}
+
+/// Invokes the callback registered with `Deno.core.setMacrotaskCallback()`
+/// once, surrounded by the hooks registered with `Deno.core.setTaskHooks()`.
+/// Returns `None` if no callback is registered, otherwise whether the callback
+/// reported that all macrotasks are done.
+fn invoke_macrotask_cb<'s>(
+  scope: &mut v8::HandleScope<'s>,
+) -> Result<Option<bool>, ErrBox> {
   let context = scope.get_current_context();
   let global: v8::Local<v8::Value> = context.global(scope).into();
 
@@ -761,27 +1872,92 @@ fn drain_macrotasks<'s>(scope: &mut v8::HandleScope<'s>) -> Result<(), ErrBox> {
     .as_ref()
   {
     Some(cb) => v8::Local::new(scope, cb),
-    None => return Ok(()),
+    None => return Ok(None),
   };
 
-  // Repeatedly invoke macrotask callback until it returns true (done),
-  // such that ready microtasks would be automatically run before
-  // next macrotask is processed.
+  let task_hooks = CoreIsolate::state(scope)
+    .borrow()
+    .js_task_hooks
+    .as_ref()
+    .map(|(before, after)| {
+      (v8::Local::new(scope, before), v8::Local::new(scope, after))
+    });
+
   let tc_scope = &mut v8::TryCatch::new(scope);
 
-  loop {
-    let is_done = js_macrotask_cb.call(tc_scope, global, &[]);
+  // Each invocation of the macrotask callback counts as one task for the
+  // hooks registered with Deno.core.setTaskHooks().
+  let task_id = task_hooks.map(|_| {
+    let state_rc = CoreIsolate::state(tc_scope);
+    let mut state = state_rc.borrow_mut();
+    state.next_task_id += 1;
+    state.next_task_id
+  });
+  let task_id: Option<v8::Local<v8::Value>> =
+    task_id.map(|id| v8::Number::new(tc_scope, id as f64).into());
+
+  if let (Some((before, _)), Some(task_id)) = (task_hooks, task_id) {
+    before.call(tc_scope, global, &[task_id]);
+    if let Some(exception) = tc_scope.exception() {
+      return exception_to_err_result(tc_scope, exception);
+    }
+  }
+
+  let is_done = js_macrotask_cb.call(tc_scope, global, &[]);
+
+  if let Some(exception) = tc_scope.exception() {
+    return exception_to_err_result(tc_scope, exception);
+  }
 
+  if let (Some((_, after)), Some(task_id)) = (task_hooks, task_id) {
+    after.call(tc_scope, global, &[task_id]);
     if let Some(exception) = tc_scope.exception() {
       return exception_to_err_result(tc_scope, exception);
     }
+  }
+
+  Ok(Some(is_done.unwrap().is_true()))
+}
+
+/// The isolate is idle once a poll has drained the macrotask and microtask
+/// queues and no async op (unreffed ones included) is left in flight. The
+/// callback registered with `Deno.core.setIdleCallback()` is invoked once per
+/// transition into the idle state; dispatching another async op leaves it.
+fn notify_idle<'s>(scope: &mut v8::HandleScope<'s>) -> Result<(), ErrBox> {
+  let state_rc = CoreIsolate::state(scope);
+  let (maybe_js_idle_cb, gc_on_idle) = {
+    let mut state = state_rc.borrow_mut();
+    let is_idle = state.pending_ops.is_empty()
+      && state.pending_unref_ops.is_empty()
+      && !state.have_unpolled_ops;
+    if !is_idle {
+      state.is_idle = false;
+      return Ok(());
+    }
+    if state.is_idle {
+      return Ok(());
+    }
+    state.is_idle = true;
+    let maybe_js_idle_cb = state
+      .js_idle_cb
+      .as_ref()
+      .map(|cb| v8::Local::new(scope, cb));
+    (maybe_js_idle_cb, state.gc_on_idle)
+  };
 
-    let is_done = is_done.unwrap();
-    if is_done.is_true() {
-      break;
+  if let Some(js_idle_cb) = maybe_js_idle_cb {
+    let context = scope.get_current_context();
+    let global: v8::Local<v8::Value> = context.global(scope).into();
+    let tc_scope = &mut v8::TryCatch::new(scope);
+    js_idle_cb.call(tc_scope, global, &[]);
+    if let Some(exception) = tc_scope.exception() {
+      return exception_to_err_result(tc_scope, exception);
     }
   }
 
+  if gc_on_idle {
+    scope.low_memory_notification();
+  }
   Ok(())
 }
 
@@ -809,10 +1985,21 @@ pub(crate) fn exception_to_err_result<'s, T>(
     }
   }
 
-  let js_error = JSError::from_v8_exception(scope, exception);
-
   let state_rc = CoreIsolate::state(scope);
+  if is_terminating_exception {
+    state_rc.borrow_mut().terminated = true;
+  }
+  let source_line_mode = state_rc.borrow().source_line_mode;
+  let mut js_error = JSError::from_v8_exception_with_source_line_mode(
+    scope,
+    exception,
+    source_line_mode,
+  );
+
   let state = state_rc.borrow();
+  js_error.apply_source_map(&|file_name, line_number, column_number| {
+    state.map_location(file_name, line_number, column_number)
+  });
   let js_error = (state.js_error_create_fn)(js_error);
 
   if is_terminating_exception {
@@ -825,22 +2012,97 @@ pub(crate) fn exception_to_err_result<'s, T>(
   Err(js_error)
 }
 
+fn execute_script<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  js_filename: &str,
+  js_source: &str,
+  origin: &ScriptOriginOptions,
+) -> Result<(), ErrBox> {
+  let source = v8::String::new(scope, js_source).unwrap();
+  let name = v8::String::new(scope, js_filename).unwrap();
+  let origin = bindings::script_origin(scope, name, origin);
+
+  let tc_scope = &mut v8::TryCatch::new(scope);
+
+  let script = match v8::Script::compile(tc_scope, source, Some(&origin)) {
+    Some(script) => script,
+    None => {
+      let exception = tc_scope.exception().unwrap();
+      return exception_to_err_result(tc_scope, exception);
+    }
+  };
+
+  match script.run(tc_scope) {
+    Some(_) => Ok(()),
+    None => {
+      assert!(tc_scope.has_caught());
+      let exception = tc_scope.exception().unwrap();
+      exception_to_err_result(tc_scope, exception)
+    }
+  }
+}
+
 fn check_promise_exceptions<'s>(
   scope: &mut v8::HandleScope<'s>,
 ) -> Result<(), ErrBox> {
   let state_rc = CoreIsolate::state(scope);
-  let mut state = state_rc.borrow_mut();
 
-  if let Some(&key) = state.pending_promise_exceptions.keys().next() {
-    let handle = state.pending_promise_exceptions.remove(&key).unwrap();
+  loop {
+    let mut state = state_rc.borrow_mut();
+    let key = match state.pending_promise_exceptions.keys().next() {
+      Some(&key) => key,
+      None => return Ok(()),
+    };
+    let (promise, exception) =
+      state.pending_promise_exceptions.remove(&key).unwrap();
+    let maybe_js_cb = state
+      .js_unhandled_rejection_cb
+      .as_ref()
+      .map(|cb| v8::Local::new(scope, cb));
+    let maybe_cb = state.unhandled_rejection_cb.clone();
+    let source_line_mode = state.source_line_mode;
     drop(state);
-    let exception = v8::Local::new(scope, handle);
-    exception_to_err_result(scope, exception)
-  } else {
-    Ok(())
+    let promise = v8::Local::new(scope, promise);
+    let exception = v8::Local::new(scope, exception);
+
+    if let Some(js_cb) = maybe_js_cb {
+      if call_unhandled_rejection_cb(scope, js_cb, promise, exception)? {
+        continue;
+      }
+    }
+
+    if let Some(cb) = maybe_cb {
+      let js_error = JSError::from_v8_exception_with_source_line_mode(
+        scope,
+        exception,
+        source_line_mode,
+      );
+      cb(js_error.to_json());
+    }
+    return exception_to_err_result(scope, exception);
   }
 }
 
+/// Invokes the callback registered with
+/// `Deno.core.setUnhandledRejectionCallback()`. Returns true if it handled
+/// the rejection, which is then no longer fatal.
+fn call_unhandled_rejection_cb<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  js_cb: v8::Local<'s, v8::Function>,
+  promise: v8::Local<'s, v8::Promise>,
+  reason: v8::Local<'s, v8::Value>,
+) -> Result<bool, ErrBox> {
+  let context = scope.get_current_context();
+  let global: v8::Local<v8::Value> = context.global(scope).into();
+
+  let tc_scope = &mut v8::TryCatch::new(scope);
+  let handled = js_cb.call(tc_scope, global, &[promise.into(), reason]);
+  if let Some(exception) = tc_scope.exception() {
+    return exception_to_err_result(tc_scope, exception);
+  }
+  Ok(handled.map_or(false, |handled| handled.is_true()))
+}
+
 pub fn js_check<T>(r: Result<T, ErrBox>) -> T {
   if let Err(e) = r {
     panic!(e.to_string());
@@ -852,6 +2114,7 @@ pub fn js_check<T>(r: Result<T, ErrBox>) -> T {
 pub mod tests {
   use super::*;
   use futures::future::lazy;
+  use serde_json::json;
   use std::ops::FnOnce;
   use std::sync::atomic::{AtomicUsize, Ordering};
   use std::sync::Arc;
@@ -998,19 +2261,273 @@ pub mod tests {
   }
 
   #[test]
-  fn test_dispatch_no_zero_copy_buf() {
-    let (mut isolate, dispatch_count) = setup(Mode::AsyncZeroCopy(0));
+  fn test_sync_responses_do_not_alias() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    let counter = Rc::new(Cell::new(0u8));
+    isolate.register_op("op_counter", move |_state, _bufs| {
+      counter.set(counter.get() + 1);
+      Op::Sync(vec![counter.get(); 8].into_boxed_slice())
+    });
     js_check(isolate.execute(
-      "filename.js",
+      "sync_responses.js",
       r#"
-        Deno.core.send(1);
+        const counterId = Deno.core.ops()["op_counter"];
+        const first = Deno.core.dispatch(counterId);
+        const second = Deno.core.dispatch(counterId);
+        // Every sync response gets its own ArrayBuffer, so a response stays
+        // valid after later ops have run.
+        assert(first.buffer !== second.buffer);
+        assert(first.every((b) => b === 1));
+        assert(second.every((b) => b === 2));
         "#,
     ));
-    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
   }
 
   #[test]
-  fn test_dispatch_stack_zero_copy_bufs() {
+  fn test_op_id_in_sync_response() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    isolate.register_op("op_sync", |_state, _bufs| {
+      Op::Sync(vec![1, 2, 3].into_boxed_slice())
+    });
+    isolate.register_op("op_empty", |_state, _bufs| {
+      Op::Sync(vec![].into_boxed_slice())
+    });
+    js_check(isolate.execute(
+      "legacy.js",
+      r#"
+        const { op_sync: syncId, op_empty: emptyId } = Deno.core.ops();
+        const legacy = Deno.core.dispatch(syncId);
+        assert(legacy instanceof Uint8Array);
+        assert(legacy.join() === "1,2,3");
+        assert(Deno.core.syncResponseBuf(legacy) === legacy);
+        assert(Deno.core.dispatch(emptyId) === undefined);
+        "#,
+    ));
+
+    isolate.set_op_id_in_sync_response(true);
+    js_check(isolate.execute(
+      "framed.js",
+      r#"
+        const [opId, buf] = Deno.core.dispatch(syncId);
+        assert(opId === syncId);
+        assert(buf instanceof Uint8Array);
+        assert(buf.join() === "1,2,3");
+        const framed = Deno.core.dispatch(syncId);
+        assert(Deno.core.syncResponseBuf(framed).join() === "1,2,3");
+        const [emptyOpId, emptyBuf] = Deno.core.dispatch(emptyId);
+        assert(emptyOpId === emptyId);
+        assert(emptyBuf === null);
+        // Async ops still return nothing.
+        assert(Deno.core.dispatch(1, new Uint8Array([42])) === undefined);
+        // The ops map is still readable through Deno.core.ops().
+        assert(Deno.core.ops()["op_sync"] === syncId);
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_shared_queue_size() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    isolate.set_shared_queue_size(16);
+    isolate.register_op("op_large", |_state, _bufs| {
+      let buf = vec![7; 64].into_boxed_slice();
+      Op::Async(futures::future::ready(buf).boxed())
+    });
+    let expected_len = SharedQueue::new(16).bytes().len();
+    js_check(isolate.execute(
+      "shared_queue_size.js",
+      &format!(
+        r#"
+        assert(Deno.core.shared.byteLength === {});
+        let received = [];
+        Deno.core.setAsyncHandler(Deno.core.ops()["op_large"], (buf) => {{
+          received.push(buf.length);
+        }});
+        // The response does not fit in the queue and overflows.
+        Deno.core.dispatchByName("op_large");
+        "#,
+        expected_len
+      ),
+    ));
+    run_in_task(|cx| {
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+    });
+    js_check(isolate.execute(
+      "check.js",
+      "assert(received.length === 1 && received[0] === 64);",
+    ));
+    assert_eq!(isolate.shared_queue_stats().overflowed, 1);
+  }
+
+  #[test]
+  fn test_register_op_async() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      isolate.register_op_async("op_async", |_state, bufs| {
+        let n = bufs[0][0];
+        async move { vec![n * 2].into_boxed_slice() }
+      });
+      isolate.register_op_async("op_panic", |_state, _bufs| {
+        futures::future::lazy(|_| -> Buf { panic!("boom") })
+      });
+      js_check(isolate.execute(
+        "async_op.js",
+        r#"
+         let result = null;
+         Deno.core.setAsyncHandler(Deno.core.ops()["op_async"], (buf) => {
+           result = buf[0];
+         });
+         Deno.core.dispatchByName("op_async", new Uint8Array([21]));
+         "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute("check.js", "assert(result === 42);"));
+
+      js_check(isolate.execute(
+        "panic.js",
+        r#"Deno.core.dispatchByName("op_panic", new Uint8Array([0]));"#,
+      ));
+      match isolate.poll_unpin(cx) {
+        Poll::Ready(Err(err)) => {
+          assert_eq!(err.to_string(), "op \"op_panic\" panicked: boom")
+        }
+        _ => panic!("expected the panic to be reported"),
+      }
+    });
+  }
+
+  #[test]
+  fn test_recv_replacement() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      js_check(isolate.execute(
+        "recv.js",
+        r#"
+         // Registers core.js's own recv callback.
+         Deno.core.setAsyncHandler(1, () => {
+           throw Error("replaced handler called");
+         });
+         let thrown = null;
+         try {
+           Deno.core.recv(() => {});
+         } catch (e) {
+           thrown = e;
+         }
+         assert(thrown instanceof TypeError);
+         "#,
+      ));
+
+      isolate.set_allow_recv_replacement(true);
+      js_check(isolate.execute(
+        "reload.js",
+        r#"
+         let nrecv = 0;
+         Deno.core.recv(() => {
+           while (Deno.core.sharedQueue.shift() != null) {
+             nrecv++;
+           }
+         });
+         Deno.core.send(1, new Uint8Array([42]));
+         "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute("check.js", "assert(nrecv === 1);"));
+    });
+  }
+
+  #[test]
+  fn test_max_pending_ops() {
+    run_in_task(|cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::Async);
+      isolate.set_max_pending_ops(Some(2));
+      isolate.register_op("op_sync", |_state, _bufs| Op::Sync(Box::new([])));
+      js_check(isolate.execute(
+        "pending_ops.js",
+        r#"
+         let nrecv = 0;
+         Deno.core.setAsyncHandler(1, (buf) => {
+           nrecv++;
+         });
+         let control = new Uint8Array([42]);
+         Deno.core.send(1, control);
+         Deno.core.send(1, control);
+         let thrown = null;
+         try {
+           Deno.core.send(1, control);
+         } catch (e) {
+           thrown = e;
+         }
+         assert(thrown instanceof RangeError);
+         // Sync ops are still dispatched.
+         Deno.core.dispatch(Deno.core.ops()["op_sync"]);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 3);
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+
+      // Once the pending ops complete, new ones are accepted again.
+      js_check(isolate.execute("again.js", "Deno.core.send(1, control);"));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute("check.js", "assert(nrecv === 3);"));
+    });
+  }
+
+  #[test]
+  fn test_shared_queue_full() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      isolate.register_op("op_echo", |_state, bufs| {
+        let buf = bufs[0].to_vec().into_boxed_slice();
+        Op::Async(futures::future::ready(buf).boxed())
+      });
+      js_check(isolate.execute(
+        "burst.js",
+        r#"
+         let received = 0;
+         let recvCalls = 0;
+         let lastSize = -1;
+         Deno.core.setAsyncHandler(Deno.core.ops()["op_echo"], (buf) => {
+           received++;
+           const size = Deno.core.sharedQueue.size();
+           if (size >= lastSize) recvCalls++;
+           lastSize = size;
+         });
+         for (let i = 0; i < 250; i++) {
+           Deno.core.dispatchByName("op_echo", new Uint8Array([i]));
+         }
+         "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute(
+        "check.js",
+        r#"
+         assert(received === 250);
+         // 100 records fit in the queue, so it fills up twice.
+         assert(recvCalls === 3);
+         "#,
+      ));
+
+      let stats = isolate.shared_queue_stats();
+      assert_eq!(stats.pushed, 250);
+      assert_eq!(stats.full, 2);
+      assert_eq!(stats.overflowed, 0);
+    });
+  }
+
+  #[test]
+  fn test_dispatch_no_zero_copy_buf() {
+    let (mut isolate, dispatch_count) = setup(Mode::AsyncZeroCopy(0));
+    js_check(isolate.execute(
+      "filename.js",
+      r#"
+        Deno.core.send(1);
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+  }
+
+  #[test]
+  fn test_dispatch_stack_zero_copy_bufs() {
     let (mut isolate, dispatch_count) = setup(Mode::AsyncZeroCopy(2));
     js_check(isolate.execute(
       "filename.js",
@@ -1084,6 +2601,77 @@ pub mod tests {
     });
   }
 
+  #[test]
+  fn test_op_response_handler() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      let echo = |_state: &mut CoreIsolateState, bufs: &mut [ZeroCopyBuf]| {
+        let buf = bufs[0].to_vec().into_boxed_slice();
+        Op::Async(futures::future::ready(buf).boxed())
+      };
+      isolate.register_op("op_queued", echo);
+      isolate.register_op("op_direct", echo);
+      js_check(isolate.execute(
+        "op_response_handler.js",
+        r#"
+         const received = [];
+         const ops = Deno.core.ops();
+         Deno.core.setAsyncHandler(ops["op_queued"], (buf) => {
+           received.push(["queued", buf[0]]);
+         });
+         Deno.core.setOpResponseHandler(ops["op_direct"], (buf) => {
+           // Direct responses never go through the shared queue.
+           assert(Deno.core.sharedQueue.size() === 0);
+           received.push(["direct", buf[0]]);
+         });
+         Deno.core.dispatchByName("op_queued", new Uint8Array([1]));
+         Deno.core.dispatchByName("op_direct", new Uint8Array([2]));
+         Deno.core.dispatchByName("op_queued", new Uint8Array([3]));
+         "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute(
+        "check.js",
+        r#"
+         const expected = [["queued", 1], ["direct", 2], ["queued", 3]];
+         assert(JSON.stringify(received) === JSON.stringify(expected));
+         "#,
+      ));
+      assert_eq!(isolate.shared_queue_stats().pushed, 2);
+    });
+  }
+
+  #[test]
+  fn test_async_responses_batched() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      isolate.register_op("op_echo", |_state, bufs| {
+        let buf = bufs[0].to_vec().into_boxed_slice();
+        Op::Async(futures::future::ready(buf).boxed())
+      });
+      js_check(isolate.execute(
+        "batch.js",
+        r#"
+         const received = [];
+         Deno.core.setAsyncHandler(Deno.core.ops()["op_echo"], (buf) => {
+           // Responses still queued are part of the same recv delivery.
+           received.push([buf[0], Deno.core.sharedQueue.size()]);
+         });
+         for (const i of [1, 2, 3]) {
+           Deno.core.dispatchByName("op_echo", new Uint8Array([i]));
+         }
+         "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute(
+        "check.js",
+        r#"
+         assert(JSON.stringify(received) === "[[1,2],[2,1],[3,0]]");
+         "#,
+      ));
+    });
+  }
+
   #[test]
   fn test_poll_async_optional_ops() {
     run_in_task(|cx| {
@@ -1106,6 +2694,202 @@ pub mod tests {
     })
   }
 
+  #[test]
+  fn test_idle_callback() {
+    run_in_task(|cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::Async);
+      js_check(isolate.execute(
+        "idle.js",
+        r#"
+         let nrecv = 0;
+         let nidle = 0;
+         Deno.core.setAsyncHandler(1, (buf) => {
+           nrecv++;
+         });
+         Deno.core.setIdleCallback(() => {
+           // Idle is only reached after the pending op has completed.
+           assert(nrecv === 1);
+           nidle++;
+         });
+         Deno.core.send(1, new Uint8Array([42]));
+         assert(nidle === 0);
+         "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute("check1.js", "assert(nidle === 1);"));
+
+      // Staying idle doesn't fire the callback again.
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute(
+        "check2.js",
+        r#"
+         assert(nidle === 1);
+         let thrown;
+         try {
+           Deno.core.setIdleCallback(() => {});
+         } catch (e) {
+           thrown = e;
+         }
+         assert(thrown instanceof TypeError);
+         "#,
+      ));
+    });
+  }
+
+  #[test]
+  fn test_macrotask_callbacks() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      let runs = Rc::new(RefCell::new(Vec::new()));
+      let runs_ = runs.clone();
+      isolate.add_macrotask_callback(move |scope| {
+        let source = v8::String::new(
+          scope,
+          "globalThis.ticks = (globalThis.ticks || 0) + 1",
+        );
+        let script = v8::Script::compile(scope, source.unwrap(), None);
+        let ticks = script.unwrap().run(scope).unwrap();
+        let ticks = ticks.integer_value(scope).unwrap();
+        runs_.borrow_mut().push(("first", ticks));
+        ticks == 3
+      });
+      let runs_ = runs.clone();
+      isolate.add_macrotask_callback(move |_scope| {
+        runs_.borrow_mut().push(("second", 0));
+        true
+      });
+
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert_eq!(
+        *runs.borrow(),
+        vec![("first", 1), ("first", 2), ("first", 3), ("second", 0)]
+      );
+    });
+  }
+
+  #[test]
+  fn test_macrotask_callback_throws() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      let calls = Rc::new(Cell::new(0));
+      let calls_ = calls.clone();
+      isolate.add_macrotask_callback(move |scope| {
+        calls_.set(calls_.get() + 1);
+        if calls_.get() == 1 {
+          let source = v8::String::new(scope, "throw Error('in macrotask')");
+          let script = v8::Script::compile(scope, source.unwrap(), None);
+          assert!(script.unwrap().run(scope).is_none());
+        }
+        true
+      });
+
+      match isolate.poll_unpin(cx) {
+        Poll::Ready(Err(err)) => {
+          assert!(err.to_string().contains("in macrotask"))
+        }
+        _ => panic!("the exception should be reported"),
+      }
+      // The callback is still registered afterwards.
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert_eq!(calls.get(), 2);
+    });
+  }
+
+  #[test]
+  fn test_explicit_microtasks_policy() {
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    isolate.set_microtasks_policy(v8::MicrotasksPolicy::Explicit);
+    js_check(isolate.execute(
+      "queue.js",
+      r#"
+        globalThis.ran = false;
+        Promise.resolve().then(() => {
+          ran = true;
+        });
+      "#,
+    ));
+    js_check(isolate.execute("check.js", "if (ran) throw Error('ran');"));
+    js_check(isolate.run_microtasks());
+    js_check(isolate.execute("check.js", "if (!ran) throw Error('!ran');"));
+
+    js_check(isolate.execute(
+      "reject.js",
+      "Promise.resolve().then(() => { throw Error('in microtask'); });",
+    ));
+    let err = isolate.run_microtasks().unwrap_err();
+    assert!(err.to_string().contains("in microtask"));
+  }
+
+  #[test]
+  fn test_run_macrotask() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    assert_eq!(isolate.run_macrotask().unwrap(), false);
+
+    js_check(isolate.execute(
+      "macrotask.js",
+      r#"
+        let ran = 0;
+        Deno.core.setMacrotaskCallback(() => {
+          ran++;
+          return ran === 2;
+        });
+        let thrown;
+        try {
+          Deno.core.setMacrotaskCallback(() => true);
+        } catch (e) {
+          thrown = e;
+        }
+        assert(thrown instanceof TypeError);
+        "#,
+    ));
+    assert_eq!(isolate.run_macrotask().unwrap(), true);
+    js_check(isolate.execute("check1.js", "assert(ran === 1);"));
+    assert_eq!(isolate.run_macrotask().unwrap(), false);
+    js_check(isolate.execute("check2.js", "assert(ran === 2);"));
+  }
+
+  #[test]
+  fn test_task_hooks() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      js_check(isolate.execute(
+        "task_hooks.js",
+        r#"
+         const events = [];
+         let tasks = 0;
+         Deno.core.setMacrotaskCallback(() => {
+           events.push("task");
+           tasks++;
+           return tasks === 2;
+         });
+         Deno.core.setTaskHooks(
+           (id) => events.push(`before ${id}`),
+           (id) => events.push(`after ${id}`),
+         );
+         "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute(
+        "check.js",
+        r#"
+         const expected = [
+           "before 1", "task", "after 1",
+           "before 2", "task", "after 2",
+         ];
+         assert(events.join() === expected.join());
+         let thrown;
+         try {
+           Deno.core.setTaskHooks(() => {}, () => {});
+         } catch (e) {
+           thrown = e;
+         }
+         assert(thrown instanceof TypeError);
+         "#,
+      ));
+    });
+  }
+
   #[test]
   fn terminate_execution() {
     let (mut isolate, _dispatch_count) = setup(Mode::Async);
@@ -1133,14 +2917,7 @@ pub mod tests {
 
     // Cancel the execution-terminating exception in order to allow script
     // execution again.
-    // TODO(piscisaureus): in rusty_v8, `cancel_terminate_execution()` should
-    // also be implemented on `struct Isolate`.
-    let ok = isolate
-      .v8_isolate
-      .as_mut()
-      .unwrap()
-      .thread_safe_handle()
-      .cancel_terminate_execution();
+    let ok = isolate.cancel_terminate_execution();
     assert!(ok);
 
     // Verify that the isolate usable again.
@@ -1152,18 +2929,91 @@ pub mod tests {
   }
 
   #[test]
-  fn dangling_shared_isolate() {
-    let v8_isolate_handle = {
-      // isolate is dropped at the end of this block
-      let (mut isolate, _dispatch_count) = setup(Mode::Async);
-      // TODO(piscisaureus): in rusty_v8, the `thread_safe_handle()` method
-      // should not require a mutable reference to `struct rusty_v8::Isolate`.
-      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle()
-    };
+  fn terminate_execution_resumable() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
 
-    // this should not SEGFAULT
-    v8_isolate_handle.terminate_execution();
-  }
+    for _ in 0..2 {
+      let handle = isolate.thread_safe_handle();
+      let terminator_thread = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(handle.terminate_execution());
+      });
+
+      let err = isolate
+        .execute("infinite_loop.js", "for(;;) {}")
+        .expect_err("execution should be terminated");
+      assert_eq!(err.to_string(), "Uncaught Error: execution terminated");
+      terminator_thread.join().unwrap();
+      assert!(isolate.cancel_terminate_execution());
+
+      js_check(isolate.execute("simple.js", "if (1 + 1 !== 2) throw Error()"));
+      // Later exceptions are not mistaken for the termination.
+      let err = isolate
+        .execute("throw.js", "throw new Error('boom')")
+        .expect_err("script should throw");
+      assert_eq!(
+        err.downcast::<JSError>().unwrap().message,
+        "Uncaught Error: boom"
+      );
+    }
+  }
+
+  #[test]
+  fn terminate_execution_in_poll() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      isolate.register_op("op_pending", |_state, _bufs| {
+        Op::Async(futures::future::pending().boxed())
+      });
+      js_check(isolate.execute(
+        "loop_on_response.js",
+        r#"
+         Deno.core.setAsyncHandler(1, () => {
+           for (;;) {}
+         });
+         Deno.core.dispatchByName("op_pending", new Uint8Array([0]));
+         Deno.core.send(1, new Uint8Array([42]));
+         "#,
+      ));
+
+      let handle = isolate.thread_safe_handle();
+      let terminator_thread = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(handle.terminate_execution());
+      });
+      match isolate.poll_unpin(cx) {
+        Poll::Ready(Err(err)) => {
+          assert!(err.downcast_ref::<ExecutionTerminated>().is_some());
+          assert_eq!(err.to_string(), "execution terminated");
+        }
+        _ => panic!("execution should be terminated"),
+      }
+      terminator_thread.join().unwrap();
+      {
+        let state_rc = CoreIsolate::state(&isolate);
+        let state = state_rc.borrow();
+        assert!(state.pending_ops.is_empty());
+      }
+
+      assert!(isolate.cancel_terminate_execution());
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute("simple.js", "if (1 + 1 !== 2) throw Error()"));
+    });
+  }
+
+  #[test]
+  fn dangling_shared_isolate() {
+    let v8_isolate_handle = {
+      // isolate is dropped at the end of this block
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      // TODO(piscisaureus): in rusty_v8, the `thread_safe_handle()` method
+      // should not require a mutable reference to `struct rusty_v8::Isolate`.
+      isolate.v8_isolate.as_mut().unwrap().thread_safe_handle()
+    };
+
+    // this should not SEGFAULT
+    v8_isolate_handle.terminate_execution();
+  }
 
   #[test]
   fn overflow_req_sync() {
@@ -1351,63 +3201,1480 @@ pub mod tests {
   }
 
   #[test]
-  fn will_snapshot() {
-    let snapshot = {
-      let mut isolate = CoreIsolate::new(StartupData::None, true);
-      js_check(isolate.execute("a.js", "a = 1 + 2"));
-      isolate.snapshot()
-    };
+  fn test_context_count() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "context_count.js",
+      "assert(Deno.core.contextCount() === 1);",
+    ));
+    assert_eq!(isolate.context_count(), 1);
 
-    let startup_data = StartupData::Snapshot(Snapshot::JustCreated(snapshot));
-    let mut isolate2 = CoreIsolate::new(startup_data, false);
-    js_check(isolate2.execute("check.js", "if (a != 3) throw Error('x')"));
+    let child_a = isolate.new_context();
+    let child_b = isolate.new_context();
+    assert_eq!(isolate.context_count(), 3);
+    js_check(isolate.execute(
+      "context_count.js",
+      "assert(Deno.core.contextCount() === 3);",
+    ));
+
+    js_check(isolate.execute_in_context(
+      &child_a,
+      "child_a.js",
+      r#"
+        globalThis.realm = "a";
+        if (typeof Deno.core.print !== "function") throw Error("no bindings");
+      "#,
+    ));
+    js_check(isolate.execute_in_context(
+      &child_b,
+      "child_b.js",
+      r#"if (typeof realm !== "undefined") throw Error("shared global");"#,
+    ));
+    js_check(
+      isolate.execute("global.js", r#"assert(typeof realm === "undefined");"#),
+    );
+    let err = isolate
+      .execute_in_context(&child_a, "throw.js", "throw new Error(realm)")
+      .unwrap_err();
+    assert!(err.to_string().contains("Error: a"));
+
+    drop(child_a);
+    assert_eq!(isolate.context_count(), 2);
+    drop(child_b);
+    js_check(isolate.execute(
+      "context_count.js",
+      "assert(Deno.core.contextCount() === 1);",
+    ));
   }
 
   #[test]
-  fn test_from_boxed_snapshot() {
-    let snapshot = {
-      let mut isolate = CoreIsolate::new(StartupData::None, true);
-      js_check(isolate.execute("a.js", "a = 1 + 2"));
-      let snap: &[u8] = &*isolate.snapshot();
-      Vec::from(snap).into_boxed_slice()
-    };
+  fn test_realm_op_responses() {
+    run_in_task(|cx| {
+      let (mut isolate, dispatch_count) = setup(Mode::Async);
+      let child = isolate.new_context();
+      js_check(isolate.execute(
+        "main.js",
+        r#"
+          let nrecv = 0;
+          Deno.core.setAsyncHandler(1, (buf) => {
+            assert(buf[0] === 43);
+            nrecv++;
+          });
+          Deno.core.send(1, new Uint8Array([42]));
+        "#,
+      ));
+      js_check(isolate.execute_in_context(
+        &child,
+        "child.js",
+        r#"
+          if (Deno.core.shared.byteLength === 0) throw Error("no queue");
+          globalThis.nrecv = 0;
+          Deno.core.setAsyncHandler(1, (buf) => {
+            if (buf[0] !== 43) throw Error("bad response");
+            nrecv++;
+          });
+          Deno.core.send(1, new Uint8Array([42]));
+          Deno.core.send(1, new Uint8Array([42]));
+        "#,
+      ));
+      assert_eq!(dispatch_count.load(Ordering::Relaxed), 3);
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute("main_check.js", "assert(nrecv === 1);"));
+      js_check(isolate.execute_in_context(
+        &child,
+        "child_check.js",
+        r#"if (nrecv !== 2) throw Error("expected 2 responses");"#,
+      ));
 
-    let startup_data = StartupData::Snapshot(Snapshot::Boxed(snapshot));
-    let mut isolate2 = CoreIsolate::new(startup_data, false);
-    js_check(isolate2.execute("check.js", "if (a != 3) throw Error('x')"));
+      // Responses for a dropped realm are discarded.
+      js_check(isolate.execute_in_context(
+        &child,
+        "child_dispatch.js",
+        "Deno.core.send(1, new Uint8Array([42]));",
+      ));
+      drop(child);
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute("main_check.js", "assert(nrecv === 1);"));
+    });
   }
 
   #[test]
-  fn test_heap_limits() {
-    let heap_limits = HeapLimits {
-      initial: 0,
-      max: 20 * 1024, // 20 kB
-    };
-    let mut isolate =
-      CoreIsolate::with_heap_limits(StartupData::None, heap_limits);
-    let cb_handle = isolate.thread_safe_handle();
+  fn test_view_bounds() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "view_bounds.js",
+      r#"
+        const buf = new ArrayBuffer(8);
+
+        const view = Deno.core.viewBounds(new Uint8Array(buf, 2, 4));
+        assert(view.bufferStart === 2);
+        assert(view.byteOffset === 2);
+        assert(view.byteLength === 4);
+        assert(view.withinBounds);
+
+        const edge = Deno.core.viewBounds(new Uint8Array(buf, 8, 0));
+        assert(edge.bufferStart === 8);
+        assert(edge.byteOffset === 8);
+        assert(edge.byteLength === 0);
+        assert(edge.withinBounds);
+
+        let thrown;
+        try {
+          Deno.core.viewBounds(buf);
+        } catch (e) {
+          thrown = e;
+        }
+        assert(thrown instanceof TypeError);
+        "#,
+    ));
+  }
 
-    let callback_invoke_count = Rc::new(AtomicUsize::default());
-    let inner_invoke_count = Rc::clone(&callback_invoke_count);
+  #[test]
+  fn test_run_with_stack_size() {
+    let stack_size = 256 * 1024 * 1024;
+    std::thread::Builder::new()
+      .stack_size(stack_size + 1024 * 1024)
+      .spawn(move || {
+        let mut isolate = CoreIsolate::new(StartupData::None, false);
+        let recursion = r#"
+          function depth(n) {
+            return n === 0 ? 0 : 1 + depth(n - 1);
+          }
+          depth(200000);
+        "#;
+        let assert_overflows = |isolate: &mut CoreIsolate| {
+          let err = isolate
+            .execute("recursion.js", recursion)
+            .expect_err("recursion should overflow the default stack");
+          assert_eq!(
+            "Uncaught RangeError: Maximum call stack size exceeded",
+            err.downcast::<JSError>().unwrap().message
+          );
+        };
 
-    isolate.add_near_heap_limit_callback(
-      move |current_limit, _initial_limit| {
-        inner_invoke_count.fetch_add(1, Ordering::SeqCst);
-        cb_handle.terminate_execution();
-        current_limit * 2
+        assert_overflows(&mut isolate);
+        // Safety: this thread was spawned with a larger stack.
+        let result = unsafe {
+          isolate.run_with_stack_size(stack_size, |isolate| {
+            isolate.execute("recursion.js", recursion)
+          })
+        };
+        assert!(result.is_ok());
+        // V8's default limit is restored afterwards.
+        assert_overflows(&mut isolate);
+      })
+      .unwrap()
+      .join()
+      .unwrap();
+  }
+
+  #[test]
+  fn test_run_on_thread_with_stack_size() {
+    let ok = CoreIsolate::run_on_thread_with_stack_size(
+      256 * 1024 * 1024,
+      || CoreIsolate::new(StartupData::None, false),
+      |isolate| {
+        isolate
+          .execute(
+            "recursion.js",
+            r#"
+              function depth(n) {
+                return n === 0 ? 0 : 1 + depth(n - 1);
+              }
+              depth(200000);
+            "#,
+          )
+          .is_ok()
       },
     );
-    let err = isolate
-      .execute(
-        "script name",
-        r#"let s = ""; while(true) { s += "Hello"; }"#,
-      )
-      .expect_err("script should fail");
-    assert_eq!(
-      "Uncaught Error: execution terminated",
-      err.downcast::<JSError>().unwrap().message
+    assert!(ok);
+  }
+
+  #[test]
+  fn test_resource_external_memory() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      let resource_table =
+        CoreIsolate::state(&isolate).borrow().resource_table.clone();
+      let before = isolate.heap_statistics().external_memory;
+
+      let size = 64 * 1024 * 1024;
+      let rid = resource_table.borrow_mut().add_with_external_memory(
+        "buffer",
+        Box::new(vec![0u8; 16]),
+        size,
+      );
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert!(isolate.heap_statistics().external_memory >= before + size);
+
+      resource_table.borrow_mut().close(rid);
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert!(isolate.heap_statistics().external_memory < before + size);
+    });
+  }
+
+  #[test]
+  fn test_gc_on_idle() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      isolate.set_gc_on_idle(true);
+      js_check(isolate.execute(
+        "garbage.js",
+        r#"
+        let garbage = new Array(1e6).fill(0).map((_, i) => ({ i }));
+        garbage = null;
+        Deno.core.lowMemoryNotification();
+        globalThis.garbage = new Array(1e6).fill(0).map((_, i) => ({ i }));
+        "#,
+      ));
+      let with_garbage = isolate.heap_statistics().used_heap_size;
+      js_check(isolate.execute("release.js", "globalThis.garbage = null;"));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert!(isolate.heap_statistics().used_heap_size < with_garbage);
+    });
+  }
+
+  #[test]
+  fn test_heap_stats() {
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let before = isolate.heap_statistics();
+    assert!(before.used_heap_size > 0);
+    assert!(before.used_heap_size <= before.total_heap_size);
+    assert!(before.total_heap_size <= before.heap_size_limit);
+    assert!(before.number_of_native_contexts >= 1);
+
+    js_check(isolate.execute(
+      "heap_stats.js",
+      r#"
+        function assert(cond) {
+          if (!cond) {
+            throw Error("assert");
+          }
+        }
+        const before = Deno.core.heapStats();
+        assert(typeof before.totalHeapSize === "number");
+        assert(typeof before.heapSizeLimit === "number");
+        assert(typeof before.externalMemory === "number");
+        assert(before.numberOfNativeContexts >= 1);
+        assert(typeof before.numberOfDetachedContexts === "number");
+        globalThis.retained = new Array(1e6).fill(0).map((_, i) => ({ i }));
+        const after = Deno.core.heapStats();
+        assert(after.usedHeapSize > before.usedHeapSize);
+      "#,
+    ));
+
+    assert!(isolate.heap_statistics().used_heap_size > before.used_heap_size);
+  }
+
+  #[test]
+  fn test_stack_limit() {
+    let stack_size = 8 * 1024 * 1024;
+    std::thread::Builder::new()
+      .stack_size(stack_size + 1024 * 1024)
+      .spawn(move || {
+        let mut isolate = CoreIsolate::new(StartupData::None, false);
+        js_check(isolate.execute(
+          "stack_limit.js",
+          r#"
+            function assert(cond) {
+              if (!cond) {
+                throw Error("assert");
+              }
+            }
+            const limit = Deno.core.stackLimit();
+            assert(limit.size === 984 * 1024);
+            assert(limit.headroom === null);
+          "#,
+        ));
+
+        // Safety: this thread was spawned with a larger stack.
+        unsafe {
+          isolate.run_with_stack_size(stack_size, |isolate| {
+            js_check(isolate.execute(
+              "stack_limit_custom.js",
+              r#"
+                const custom = Deno.core.stackLimit();
+                assert(custom.size === 8 * 1024 * 1024);
+                assert(custom.headroom > 0);
+                assert(custom.headroom < custom.size);
+                function nested() {
+                  return Deno.core.stackLimit().headroom;
+                }
+                assert(nested() < custom.headroom);
+              "#,
+            ));
+          });
+        }
+
+        js_check(isolate.execute(
+          "stack_limit_restored.js",
+          "assert(Deno.core.stackLimit().size === 984 * 1024);",
+        ));
+      })
+      .unwrap()
+      .join()
+      .unwrap();
+  }
+
+  #[test]
+  fn test_bench_context_switch() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "bench_context_switch.js",
+      r#"
+        const before = globalThis;
+        const cost = Deno.core.benchContextSwitch(1000);
+        assert(typeof cost === "number");
+        assert(cost >= 0 && Number.isFinite(cost));
+        // Switching contexts must leave the caller's context entered.
+        assert(globalThis === before);
+
+        let thrown;
+        try {
+          Deno.core.benchContextSwitch(0);
+        } catch (e) {
+          thrown = e;
+        }
+        assert(thrown instanceof TypeError);
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_op_meta() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    isolate.register_op_with_meta(
+      "op_read",
+      OpMetadata {
+        args: Some(json!({ "rid": "number" })),
+        is_async: true,
+        category: Some("io".to_string()),
+      },
+      |_state, _bufs| Op::Sync(Box::new([])),
     );
-    assert!(callback_invoke_count.load(Ordering::SeqCst) > 0)
+    isolate.register_op_with_meta(
+      "op_now",
+      OpMetadata::default(),
+      |_state, _bufs| Op::Sync(Box::new([])),
+    );
+    js_check(isolate.execute(
+      "op_meta.js",
+      r#"
+        const meta = Deno.core.opMeta();
+        // Ops registered without metadata are not listed.
+        assert(!("test" in meta));
+        assert(Object.keys(meta).length === 2);
+
+        const read = meta["op_read"];
+        assert(read.id === Deno.core.ops()["op_read"]);
+        assert(read.async === true);
+        assert(read.category === "io");
+        assert(read.args.rid === "number");
+
+        const now = meta["op_now"];
+        assert(now.async === false);
+        assert(now.category === null);
+        assert(now.args === null);
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_minor_major_gc() {
+    // --expose-gc applies to the whole process, so the tests leave it unset
+    // and the bindings have to refuse rather than let V8 abort.
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "gc.js",
+      r#"
+        let garbage = [];
+        for (let i = 0; i < 1000; i++) {
+          garbage.push({ i });
+        }
+        garbage = null;
+        for (const name of ["minorGc", "majorGc"]) {
+          let error;
+          try {
+            Deno.core[name]();
+          } catch (e) {
+            error = e;
+          }
+          assert(error instanceof Error);
+          assert(error.message === `Deno.core.${name}() requires --expose-gc`);
+        }
+        "#,
+    ));
+
+    // Collecting the garbage needs no flag.
+    let used_before = isolate.heap_statistics().used_heap_size;
+    isolate.low_memory_notification();
+    assert!(isolate.heap_statistics().used_heap_size <= used_before);
+  }
+
+  #[test]
+  fn test_op_latency() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    isolate.register_op("op_sleep", |_state, _bufs| {
+      std::thread::sleep(std::time::Duration::from_millis(2));
+      Op::Sync(Box::new([]))
+    });
+    js_check(isolate.execute(
+      "op_latency_disabled.js",
+      r#"
+        const sleepId = Deno.core.ops()["op_sleep"];
+        Deno.core.dispatch(sleepId);
+        assert(Deno.core.opLatency(sleepId) === null);
+        "#,
+    ));
+    isolate.set_op_metrics_enabled(true);
+    js_check(isolate.execute(
+      "op_latency.js",
+      r#"
+        assert(Deno.core.opLatency(sleepId) === null);
+        for (let i = 0; i < 5; i++) {
+          Deno.core.dispatch(sleepId);
+        }
+        const { p50, p90, p99, max } = Deno.core.opLatency(sleepId);
+        assert(p50 >= 2e6);
+        assert(p50 <= p90);
+        assert(p90 <= p99);
+        assert(p99 <= max);
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_extensions() {
+    fn add(
+      scope: &mut v8::HandleScope,
+      args: v8::FunctionCallbackArguments,
+      mut rv: v8::ReturnValue,
+    ) {
+      let a = args.get(0).number_value(scope).unwrap();
+      let b = args.get(1).number_value(scope).unwrap();
+      rv.set(v8::Number::new(scope, a + b).into());
+    }
+
+    let extension = Extension::new().function("add", add).js(
+      "ext.js",
+      "globalThis.addTwice = (a, b) => 2 * Deno.core.add(a, b);",
+    );
+    let mut isolate =
+      CoreIsolate::with_extensions(StartupData::None, false, vec![extension]);
+    js_check(isolate.execute(
+      "extensions.js",
+      r#"
+        if (Deno.core.add(1, 2) !== 3) throw new Error("add");
+        if (addTwice(1, 2) !== 6) throw new Error("addTwice");
+        "#,
+    ));
+
+    // Child contexts get the native functions, too.
+    let context = isolate.new_context();
+    let scope =
+      &mut v8::HandleScope::with_context(&mut *isolate, context.get());
+    let source = v8::String::new(scope, "Deno.core.add(2, 3)").unwrap();
+    let script = v8::Script::compile(scope, source, None).unwrap();
+    let result = script.run(scope).unwrap();
+    assert_eq!(result.integer_value(scope), Some(5));
+  }
+
+  #[test]
+  fn test_extensions_snapshot() {
+    fn extension() -> Extension {
+      Extension::new().js(
+        "ext.js",
+        r#"
+        let loadCount = (globalThis.loadCount || 0) + 1;
+        globalThis.loadCount = loadCount;
+        class ExtClass {}
+        "#,
+      )
+    }
+
+    let snapshot = {
+      let mut isolate = CoreIsolate::with_extensions(
+        StartupData::None,
+        true,
+        vec![extension()],
+      );
+      js_check(isolate.execute("a.js", "globalThis.fromSnapshot = true;"));
+      isolate.snapshot()
+    };
+
+    let startup_data = StartupData::Snapshot(Snapshot::JustCreated(snapshot));
+    let mut isolate =
+      CoreIsolate::with_extensions(startup_data, false, vec![extension()]);
+    js_check(isolate.execute(
+      "check.js",
+      r#"
+      if (!fromSnapshot) throw Error("not restored");
+      if (loadCount !== 1) throw Error("ext.js ran " + loadCount + " times");
+      if (typeof ExtClass !== "function") throw Error("ExtClass missing");
+      "#,
+    ));
+  }
+
+  #[test]
+  fn test_op_interceptors() {
+    let (mut isolate, dispatch_count) = setup(Mode::Async);
+    let intercepted = Rc::new(RefCell::new(Vec::new()));
+    let intercepted_ = intercepted.clone();
+    isolate.add_op_interceptor(move |state, op_id, bufs| {
+      let name = state.op_registry.name(op_id).unwrap().to_string();
+      intercepted_.borrow_mut().push((name, bufs.len()));
+      OpDecision::Continue
+    });
+    isolate.add_op_interceptor(|state, op_id, bufs| {
+      match state.op_registry.name(op_id) {
+        Some("test") if bufs.is_empty() => {
+          OpDecision::Deny("test needs a buffer".to_string())
+        }
+        Some("op_stubbed") => {
+          OpDecision::Respond(Op::Sync(vec![9].into_boxed_slice()))
+        }
+        _ => OpDecision::Continue,
+      }
+    });
+    isolate.register_op("op_stubbed", |_state, _bufs| unreachable!());
+    js_check(isolate.execute(
+      "intercepted.js",
+      r#"
+        Deno.core.ops();
+        let thrown;
+        try {
+          Deno.core.dispatch(1);
+        } catch (e) {
+          thrown = e;
+        }
+        assert(thrown instanceof TypeError);
+        assert(thrown.message === "test needs a buffer");
+        Deno.core.dispatch(1, new Uint8Array([42]));
+        const res = Deno.core.dispatchByName("op_stubbed");
+        assert(res.length === 1 && res[0] === 9);
+        "#,
+    ));
+    assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
+    assert_eq!(
+      *intercepted.borrow(),
+      vec![
+        ("ops".to_string(), 0),
+        ("test".to_string(), 0),
+        ("test".to_string(), 1),
+        ("op_stubbed".to_string(), 0),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_resources() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    let resource_table =
+      CoreIsolate::state(&isolate).borrow().resource_table.clone();
+    js_check(isolate.execute(
+      "empty.js",
+      "assert(Object.keys(Deno.core.resources()).length === 0);",
+    ));
+    let rid = resource_table.borrow_mut().add("fake", Box::new(42u32));
+    js_check(isolate.execute(
+      "open.js",
+      &format!(
+        r#"
+        const resources = Deno.core.resources();
+        assert(Object.keys(resources).length === 1);
+        assert(resources[{}] === "fake");
+        "#,
+        rid
+      ),
+    ));
+    resource_table.borrow_mut().close(rid);
+    js_check(isolate.execute(
+      "closed.js",
+      "assert(Object.keys(Deno.core.resources()).length === 0);",
+    ));
+  }
+
+  #[test]
+  fn test_op_metrics() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      isolate.register_op("op_sync", |_state, _bufs| {
+        Op::Sync(vec![0; 4].into_boxed_slice())
+      });
+      assert_eq!(isolate.op_metrics(), None);
+      js_check(isolate.execute(
+        "metrics_disabled.js",
+        "assert(Deno.core.metrics() === null);",
+      ));
+
+      isolate.set_op_metrics_enabled(true);
+      js_check(isolate.execute(
+        "metrics.js",
+        r#"
+        const syncId = Deno.core.ops()["op_sync"];
+        Deno.core.dispatch(syncId, new Uint8Array(3));
+        Deno.core.dispatch(syncId);
+        Deno.core.setAsyncHandler(1, () => {});
+        Deno.core.dispatch(1, new Uint8Array([42]));
+        "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+
+      let op_metrics = isolate.op_metrics().unwrap();
+      let sync = op_metrics["op_sync"];
+      assert_eq!(sync.dispatched, 2);
+      assert_eq!(sync.completed_sync, 2);
+      assert_eq!(sync.completed_async, 0);
+      assert_eq!(sync.bytes_received, 3);
+      assert_eq!(sync.bytes_sent, 8);
+      let test_op = op_metrics["test"];
+      assert_eq!(test_op.dispatched, 1);
+      assert_eq!(test_op.completed_async, 1);
+      assert_eq!(test_op.bytes_sent, 1);
+
+      js_check(isolate.execute(
+        "metrics_js.js",
+        r#"
+        const metrics = Deno.core.metrics();
+        assert(metrics["op_sync"].completedSync === 2);
+        assert(metrics["op_sync"].bytesReceived === 3);
+        assert(metrics["op_sync"].bytesSent === 8);
+        assert(metrics["op_sync"].totalTime >= 0);
+        assert(metrics["test"].completedAsync === 1);
+        // Unlike drainOpMetrics(), reading the metrics does not reset them.
+        assert(Deno.core.metrics()["op_sync"].dispatched === 2);
+        "#,
+      ));
+    });
+  }
+
+  #[test]
+  fn test_has_cycle() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "has_cycle.js",
+      r#"
+        const shared = { x: 1 };
+        const acyclic = { a: shared, b: [shared, "s", 2], c: null };
+        assert(Deno.core.hasCycle(acyclic) === false);
+        assert(Deno.core.hasCycle(acyclic, true) === null);
+        assert(Deno.core.hasCycle(42) === false);
+
+        const selfRef = { name: "self" };
+        selfRef.self = selfRef;
+        assert(Deno.core.hasCycle(selfRef) === true);
+        const selfPath = Deno.core.hasCycle(selfRef, true);
+        assert(selfPath.length === 1);
+        assert(selfPath[0] === "self");
+
+        const root = { child: { list: [1, {}] } };
+        root.child.list[1].back = root.child;
+        const path = Deno.core.hasCycle(root, true);
+        assert(path.join(".") === "child.list.1.back");
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_deep_freeze() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "deep_freeze.js",
+      r#"
+        "use strict";
+        const config = { nested: { list: [1, { deep: true }] }, n: 1 };
+        config.nested.parent = config;
+        assert(Deno.core.deepFreeze(config) === config);
+
+        assert(Object.isFrozen(config));
+        assert(Object.isFrozen(config.nested));
+        assert(Object.isFrozen(config.nested.list));
+        assert(Object.isFrozen(config.nested.list[1]));
+
+        function throwsTypeError(fn) {
+          try {
+            fn();
+          } catch (e) {
+            return e instanceof TypeError;
+          }
+          return false;
+        }
+        assert(throwsTypeError(() => { config.n = 2; }));
+        assert(throwsTypeError(() => { config.nested.parent.extra = 1; }));
+        assert(throwsTypeError(() => { config.nested.list.push(2); }));
+        assert(throwsTypeError(() => { config.nested.list[1].deep = false; }));
+        assert(config.n === 1);
+        assert(config.nested.list[1].deep === true);
+
+        assert(Deno.core.deepFreeze(42) === 42);
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_drain_op_metrics() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    isolate.register_op("op_noop", |_state, _bufs| Op::Sync(Box::new([])));
+    js_check(isolate.execute(
+      "drain_op_metrics_disabled.js",
+      "assert(Deno.core.drainOpMetrics() === null);",
+    ));
+    isolate.set_op_metrics_enabled(true);
+    js_check(isolate.execute(
+      "drain_op_metrics.js",
+      r#"
+        const noopId = Deno.core.ops()["op_noop"];
+        for (let i = 0; i < 3; i++) {
+          Deno.core.dispatch(noopId);
+        }
+        const first = Deno.core.drainOpMetrics();
+        assert(first["op_noop"].id === noopId);
+        assert(first["op_noop"].dispatched === 3);
+        assert(first["op_noop"].max >= first["op_noop"].p50);
+        // Draining resets the metrics.
+        assert(Deno.core.opLatency(noopId) === null);
+
+        for (let i = 0; i < 2; i++) {
+          Deno.core.dispatch(noopId);
+        }
+        const second = Deno.core.drainOpMetrics();
+        assert(second["op_noop"].dispatched === 2);
+        assert(Object.keys(Deno.core.drainOpMetrics()).length === 0);
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_eval_context_options() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "eval_context_options.js",
+      r#"
+        const [result, noErr] = Deno.core.evalContext("1 + 1", {});
+        assert(result === 2 && noErr === null);
+
+        const [, errInfo] = Deno.core.evalContext("throw new Error('x');", {
+          filename: "file:///eval.js",
+          lineOffset: 10,
+        });
+        assert(errInfo.isNativeError && !errInfo.isCompileError);
+        assert(errInfo.thrown.stack.includes("file:///eval.js:11:"));
+        globalThis.thrown = errInfo.thrown;
+
+        // The location is reported even for thrown non-Error values.
+        const [, thrownInfo] = Deno.core.evalContext("1;\nthrow 42;", {
+          filename: "file:///eval.js",
+          lineOffset: 10,
+        });
+        assert(thrownInfo.thrown === 42 && !thrownInfo.isNativeError);
+        assert(thrownInfo.fileName === "file:///eval.js");
+        assert(thrownInfo.lineNumber === 12);
+        assert(thrownInfo.columnNumber === 1);
+
+        const [, compileInfo] = Deno.core.evalContext("1 +");
+        assert(compileInfo.isCompileError);
+        assert(compileInfo.fileName === "<unknown>");
+        assert(compileInfo.lineNumber === 1);
+
+        for (const options of [42, { filename: 1 }, { file: "a.js" }]) {
+          let error;
+          try {
+            Deno.core.evalContext("1", options);
+          } catch (e) {
+            error = e;
+          }
+          assert(error instanceof TypeError);
+        }
+        "#,
+    ));
+
+    let err = isolate
+      .execute("rethrow.js", "throw thrown;")
+      .expect_err("should throw");
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(
+      js_error.script_resource_name.as_deref(),
+      Some("file:///eval.js")
+    );
+  }
+
+  #[test]
+  fn test_describe_thrown() {
+    let (mut isolate, _dispatch_count) = setup(Mode::Async);
+    js_check(isolate.execute(
+      "describe_thrown.js",
+      r#"
+        function describe(fn) {
+          try {
+            fn();
+          } catch (e) {
+            return Deno.core.describeThrown(e);
+          }
+          throw Error("did not throw");
+        }
+
+        const str = describe(() => { throw "oops"; });
+        assert(str.isError === false);
+        assert(str.name === null);
+        assert(str.message === "oops");
+        assert(str.stack === null);
+
+        const num = describe(() => { throw 42; });
+        assert(num.isError === false);
+        assert(num.message === "42");
+
+        const obj = describe(() => { throw { message: "custom", code: 1 }; });
+        assert(obj.isError === false);
+        assert(obj.name === null);
+        assert(obj.message === "custom");
+
+        const plain = describe(() => { throw {}; });
+        assert(plain.message === "[object Object]");
+
+        const err = describe(() => { throw new TypeError("bad type"); });
+        assert(err.isError === true);
+        assert(err.name === "TypeError");
+        assert(err.message === "bad type");
+        assert(err.stack.includes("describe_thrown.js"));
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_source_line_mode() {
+    let long_line = format!(
+      "let x = 1; {} throw new Error('boom');",
+      "/**/".repeat(1000)
+    );
+
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let err = isolate.execute("long_line.js", &long_line).unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.source_line.as_deref(), Some(long_line.as_str()));
+
+    isolate.set_source_line_mode(SourceLineMode::Truncated(10));
+    let err = isolate.execute("long_line.js", &long_line).unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.source_line.as_deref(), Some("let x = 1;..."));
+    // Formatting must cope with columns past the end of the truncated line.
+    assert!(js_error.to_string().contains("Uncaught Error: boom"));
+
+    isolate.set_source_line_mode(SourceLineMode::Omitted);
+    let err = isolate.execute("long_line.js", &long_line).unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.source_line, None);
+  }
+
+  #[test]
+  fn test_source_map_fn() {
+    let source = "function f() {\n  throw new Error('boom');\n}\nf();";
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let err = isolate.execute("gen.js", source).unwrap_err();
+    let unmapped = err.downcast::<JSError>().unwrap();
+    assert_eq!(unmapped.line_number, Some(2));
+
+    isolate.set_source_map_fn(|file_name, line_number, column_number| {
+      if file_name == "gen.js" {
+        Some(("orig.ts".to_string(), line_number * 10, column_number + 1))
+      } else {
+        None
+      }
+    });
+
+    let err = isolate.execute("gen.js", source).unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.script_resource_name.as_deref(), Some("orig.ts"));
+    assert_eq!(js_error.line_number, Some(20));
+    assert_eq!(js_error.start_column, unmapped.start_column.map(|c| c + 1));
+    assert_eq!(js_error.end_column, unmapped.end_column.map(|c| c + 1));
+    assert_eq!(js_error.source_line, None);
+
+    js_check(isolate.execute(
+      "apply_source_map.js",
+      r#"
+        const mapped = Deno.core.applySourceMap("gen.js", 2, 3);
+        if (mapped.fileName !== "orig.ts") throw Error(mapped.fileName);
+        if (mapped.lineNumber !== 20 || mapped.columnNumber !== 4) {
+          throw Error(JSON.stringify(mapped));
+        }
+        const unmapped = Deno.core.applySourceMap("other.js", 2, 3);
+        if (unmapped.fileName !== "other.js" || unmapped.lineNumber !== 2) {
+          throw Error(JSON.stringify(unmapped));
+        }
+        "#,
+    ));
+
+    // Formatted frames are rebuilt from the mapped frames.
+    js_check(isolate.execute(
+      "prepare_stack_trace.js",
+      r#"
+        Error.prepareStackTrace = (error, callSites) => {
+          Object.defineProperties(error, {
+            __callSiteEvals: {
+              value: callSites.map((callSite) => ({
+                fileName: callSite.getFileName(),
+                lineNumber: callSite.getLineNumber(),
+                columnNumber: callSite.getColumnNumber(),
+                functionName: callSite.getFunctionName(),
+                isTopLevel: callSite.isToplevel(),
+              })),
+            },
+            __formattedFrames: {
+              value: callSites.map((callSite) => callSite.toString()),
+            },
+          });
+          return "";
+        };
+        "#,
+    ));
+    let err = isolate.execute("gen.js", source).unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(
+      js_error.formatted_frames,
+      vec!["f (orig.ts:20:10)".to_string(), "orig.ts:40:2".to_string()]
+    );
+    let display = js_error.to_string();
+    assert!(
+      display.contains("\n    at f (orig.ts:20:10)"),
+      "{}",
+      display
+    );
+    assert!(display.contains("\n    at orig.ts:40:2"), "{}", display);
+    assert!(!display.contains("gen.js"), "{}", display);
+  }
+
+  #[test]
+  fn test_add_source_map() {
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    assert!(isolate.add_source_map("bundle.js", b"not json").is_err());
+    // Maps the start of line 1 to line 5 of orig.ts.
+    let source_map =
+      br#"{"version":3,"sources":["orig.ts"],"names":[],"mappings":"AAIA"}"#;
+    isolate.add_source_map("bundle.js", source_map).unwrap();
+
+    let err = isolate
+      .execute("bundle.js", "throw new Error('boom');")
+      .unwrap_err();
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.script_resource_name.as_deref(), Some("orig.ts"));
+    assert_eq!(js_error.line_number, Some(5));
+    assert_eq!(js_error.start_column, Some(0));
+
+    js_check(isolate.execute(
+      "apply_source_map.js",
+      r#"
+        const mapped = Deno.core.applySourceMap("bundle.js", 1, 7);
+        if (mapped.fileName !== "orig.ts" || mapped.lineNumber !== 5) {
+          throw Error(JSON.stringify(mapped));
+        }
+        for (const [line, column] of [[0, 7], [1, 0], [-1, -1]]) {
+          const unmapped = Deno.core.applySourceMap("bundle.js", line, column);
+          if (
+            unmapped.fileName !== "bundle.js" ||
+            unmapped.lineNumber !== line ||
+            unmapped.columnNumber !== column
+          ) {
+            throw Error(JSON.stringify(unmapped));
+          }
+        }
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_json_op_error_classes() {
+    run_in_task(|cx| {
+      let mut isolate = CoreIsolate::new(StartupData::None, false);
+      {
+        let state_rc = CoreIsolate::state(&isolate);
+        state_rc.borrow_mut().set_get_error_class_fn(&|err| {
+          if err.to_string() == "missing" {
+            "NotFound"
+          } else {
+            "Unregistered"
+          }
+        });
+      }
+      isolate.register_op_json_sync("fail_sync", |_state, args, _bufs| {
+        Err(ErrBox::error(args["message"].as_str().unwrap().to_string()))
+      });
+      isolate.register_op_json_async("fail_async", |_state, _args, _bufs| {
+        futures::future::ready(Err(ErrBox::error("missing")))
+      });
+
+      js_check(isolate.execute(
+        "error_classes.js",
+        r#"
+        class NotFound extends Error {}
+        Deno.core.registerErrorClass("NotFound", NotFound);
+        Deno.core.ops();
+
+        try {
+          Deno.core.jsonOpSync("fail_sync", { message: "missing" });
+          throw Error("unreachable");
+        } catch (e) {
+          if (!(e instanceof NotFound)) throw e;
+          if (e.message !== "missing") throw Error(e.message);
+        }
+        try {
+          Deno.core.jsonOpSync("fail_sync", { message: "other" });
+          throw Error("unreachable");
+        } catch (e) {
+          if (e.constructor !== Error || e.message !== "other") throw e;
+        }
+
+        globalThis.asyncError = null;
+        Deno.core.jsonOpAsync("fail_async", {}).catch((e) => {
+          asyncError = e;
+        });
+        "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute(
+        "check_async.js",
+        r#"
+        if (!(asyncError instanceof NotFound)) throw Error(String(asyncError));
+        "#,
+      ));
+    });
+  }
+
+  #[test]
+  fn test_json_op() {
+    run_in_task(|cx| {
+      let mut isolate = CoreIsolate::new(StartupData::None, false);
+      isolate.register_op(
+        "op_double",
+        json_op(|_state, args, _bufs| {
+          let n = args["n"].as_i64().unwrap();
+          if args.get("promiseId").is_some() {
+            let fut = futures::future::ready(Ok(json!(n * 2)));
+            Ok(JsonOp::Async(fut.boxed_local()))
+          } else {
+            Ok(JsonOp::Sync(json!(n * 2)))
+          }
+        }),
+      );
+      isolate.register_op_json_sync("op_bad_rid", |_state, _args, _bufs| {
+        Err(ErrBox::bad_resource_id())
+      });
+
+      js_check(isolate.execute(
+        "json_op.js",
+        r#"
+        Deno.core.ops();
+        if (Deno.core.jsonOpSync("op_double", { n: 2 }) !== 4) {
+          throw Error("bad sync result");
+        }
+        globalThis.asyncResult = null;
+        Deno.core.jsonOpAsync("op_double", { n: 3 }).then((n) => {
+          asyncResult = n;
+        });
+
+        // Malformed arguments are reported instead of panicking.
+        const res = Deno.core.dispatchByName(
+          "op_double",
+          new Uint8Array([123]),
+        );
+        const msg = JSON.parse(Deno.core.decode(res));
+        if (msg.err.className !== "Error") throw Error(msg.err.className);
+
+        // So is a missing argument buffer.
+        const missing = JSON.parse(
+          Deno.core.decode(Deno.core.dispatch(Deno.core.ops()["op_double"])),
+        );
+        if (missing.err.className !== "TypeError") {
+          throw Error(missing.err.className);
+        }
+
+        // The class of an `ErrBox::new()` error is kept by default.
+        class BadResource extends Error {}
+        Deno.core.registerErrorClass("BadResource", BadResource);
+        try {
+          Deno.core.jsonOpSync("op_bad_rid", {});
+          throw Error("unreachable");
+        } catch (e) {
+          if (!(e instanceof BadResource)) throw e;
+        }
+        "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(isolate.execute(
+        "check.js",
+        "if (asyncResult !== 6) throw Error(String(asyncResult));",
+      ));
+    });
+  }
+
+  #[test]
+  fn test_print_writers() {
+    #[derive(Clone, Default)]
+    struct Captured(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+      fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+      }
+
+      fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+      }
+    }
+
+    let stdout = Captured::default();
+    let stderr = Captured::default();
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    isolate.set_stdout(Box::new(stdout.clone()));
+    isolate.set_stderr(Box::new(stderr.clone()));
+    js_check(isolate.execute(
+      "print.js",
+      r#"
+        Deno.core.print("hi");
+        Deno.core.print(" there\n");
+        Deno.core.print("oops\n", true);
+        // isErr is only true for `true`; it is never converted.
+        Deno.core.print("sym ", Symbol());
+        Deno.core.print(["a", 1, null], false, true);
+        const bad = { toString() { throw new Error("no string"); } };
+        let thrown = null;
+        try {
+          Deno.core.print(["b", bad], true, true);
+        } catch (e) {
+          thrown = e;
+        }
+        if (!thrown || thrown.message !== "no string") throw Error("bad");
+        const getter = ["c"];
+        Object.defineProperty(getter, 1, {
+          get() { throw new Error("no element"); },
+        });
+        thrown = null;
+        try {
+          Deno.core.print(getter, true, true);
+        } catch (e) {
+          thrown = e;
+        }
+        if (!thrown || thrown.message !== "no element") throw Error("bad");
+        "#,
+    ));
+    assert_eq!(&*stdout.0.lock().unwrap(), b"hi there\nsym a 1 null\n");
+    assert_eq!(&*stderr.0.lock().unwrap(), b"oops\n");
+  }
+
+  #[test]
+  fn test_unhandled_rejection_cb() {
+    run_in_task(|cx| {
+      let (mut isolate, _dispatch_count) = setup(Mode::Async);
+      let rejections = Rc::new(RefCell::new(Vec::new()));
+      let rejections_ = rejections.clone();
+      {
+        let state_rc = CoreIsolate::state(&isolate);
+        let mut state = state_rc.borrow_mut();
+        state.set_unhandled_rejection_cb(move |err| {
+          rejections_.borrow_mut().push(err)
+        });
+      }
+
+      js_check(isolate.execute(
+        "handled_late.js",
+        r#"
+        const late = Promise.reject(new Error("handled late"));
+        Promise.resolve().then(() => late.catch(() => {}));
+        "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert!(rejections.borrow().is_empty());
+
+      js_check(isolate.execute(
+        "never_handled.js",
+        r#"Promise.reject(new Error("never handled"));"#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Err(_))));
+      let rejections = rejections.borrow();
+      assert_eq!(rejections.len(), 1);
+      assert_eq!(rejections[0]["message"], "Uncaught Error: never handled");
+      assert_eq!(rejections[0]["scriptResourceName"], "never_handled.js");
+    });
+  }
+
+  #[test]
+  fn test_unhandled_rejection_js_cb() {
+    run_in_task(|cx| {
+      let mut isolate = CoreIsolate::new(StartupData::None, false);
+      js_check(isolate.execute(
+        "set_cb.js",
+        r#"
+        globalThis.seen = [];
+        Deno.core.setUnhandledRejectionCallback((promise, reason) => {
+          if (!(promise instanceof Promise)) throw Error("not a promise");
+          seen.push(reason.message);
+          return reason.message === "prevented";
+        });
+        let threw = false;
+        try {
+          Deno.core.setUnhandledRejectionCallback(() => true);
+        } catch (e) {
+          threw = e instanceof TypeError;
+        }
+        if (!threw) throw Error("expected TypeError");
+        Promise.reject(new Error("prevented"));
+        "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+
+      js_check(
+        isolate.execute("fatal.js", r#"Promise.reject(new Error("fatal"));"#),
+      );
+      match isolate.poll_unpin(cx) {
+        Poll::Ready(Err(err)) => assert!(err.to_string().contains("fatal")),
+        _ => panic!("expected the rejection to be fatal"),
+      }
+
+      js_check(isolate.execute(
+        "check.js",
+        r#"
+        if (seen.join() !== "prevented,fatal") throw Error(seen.join());
+        "#,
+      ));
+    });
+  }
+
+  #[test]
+  fn test_execute_with_origin() {
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let origin = ScriptOriginOptions {
+      line_offset: 10,
+      column_offset: 4,
+      source_map_url: Some("extracted.js.map".to_string()),
+    };
+    let err = isolate
+      .execute_with_origin("extracted.js", "throw new Error('x');", &origin)
+      .unwrap_err();
+    let first_line = err.downcast::<JSError>().unwrap();
+    assert_eq!(
+      first_line.script_resource_name.as_deref(),
+      Some("extracted.js")
+    );
+    // V8 line numbers are one-based.
+    assert_eq!(first_line.line_number, Some(11));
+
+    let err = isolate
+      .execute_with_origin("extracted.js", "\n  throw new Error('y');", &origin)
+      .unwrap_err();
+    let second_line = err.downcast::<JSError>().unwrap();
+    assert_eq!(second_line.line_number, Some(12));
+    // The column offset only applies to the first line.
+    assert_eq!(
+      first_line.start_column.unwrap() - 4,
+      second_line.start_column.unwrap() - 2
+    );
+  }
+
+  #[test]
+  fn will_snapshot() {
+    let snapshot = {
+      let mut isolate = CoreIsolate::new(StartupData::None, true);
+      js_check(isolate.execute("a.js", "a = 1 + 2"));
+      isolate.snapshot()
+    };
+
+    let startup_data = StartupData::Snapshot(Snapshot::JustCreated(snapshot));
+    let mut isolate2 = CoreIsolate::new(startup_data, false);
+    js_check(isolate2.execute("check.js", "if (a != 3) throw Error('x')"));
+  }
+
+  #[test]
+  fn test_snapshot_restore_ops() {
+    let snapshot = {
+      let mut isolate = CoreIsolate::new(StartupData::None, true);
+      js_check(
+        isolate.execute("bootstrap.js", "globalThis.bootstrapped = 42;"),
+      );
+      isolate.snapshot()
+    };
+
+    run_in_task(|cx| {
+      let startup_data = StartupData::Snapshot(Snapshot::JustCreated(snapshot));
+      let mut isolate = CoreIsolate::new(startup_data, false);
+      isolate.register_op("op_echo", |_state, bufs| {
+        let buf = bufs[0].to_vec().into_boxed_slice();
+        Op::Async(futures::future::ready(buf).boxed())
+      });
+      // The shared queue and recv callback are set up afresh in the restored
+      // isolate, so async ops work as usual.
+      js_check(isolate.execute(
+        "restored.js",
+        r#"
+        if (bootstrapped !== 42) throw Error("global not restored");
+        let received = null;
+        Deno.core.setAsyncHandler(Deno.core.ops()["op_echo"], (buf) => {
+          received = buf[0];
+        });
+        Deno.core.dispatchByName("op_echo", new Uint8Array([7]));
+        "#,
+      ));
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      js_check(
+        isolate
+          .execute("check.js", "if (received !== 7) throw Error(received);"),
+      );
+    });
+  }
+
+  #[test]
+  fn test_from_boxed_snapshot() {
+    let snapshot = {
+      let mut isolate = CoreIsolate::new(StartupData::None, true);
+      js_check(isolate.execute("a.js", "a = 1 + 2"));
+      let snap: &[u8] = &*isolate.snapshot();
+      Vec::from(snap).into_boxed_slice()
+    };
+
+    let startup_data = StartupData::Snapshot(Snapshot::Boxed(snapshot));
+    let mut isolate2 = CoreIsolate::new(startup_data, false);
+    js_check(isolate2.execute("check.js", "if (a != 3) throw Error('x')"));
+  }
+
+  #[test]
+  fn test_heap_limits() {
+    let heap_limits = HeapLimits {
+      initial: 0,
+      max: 20 * 1024, // 20 kB
+    };
+    let mut isolate =
+      CoreIsolate::with_heap_limits(StartupData::None, heap_limits);
+    let cb_handle = isolate.thread_safe_handle();
+
+    let callback_invoke_count = Rc::new(AtomicUsize::default());
+    let inner_invoke_count = Rc::clone(&callback_invoke_count);
+
+    isolate.add_near_heap_limit_callback(
+      move |current_limit, _initial_limit| {
+        inner_invoke_count.fetch_add(1, Ordering::SeqCst);
+        cb_handle.terminate_execution();
+        current_limit * 2
+      },
+    );
+    let err = isolate
+      .execute(
+        "script name",
+        r#"let s = ""; while(true) { s += "Hello"; }"#,
+      )
+      .expect_err("script should fail");
+    assert_eq!(
+      "Uncaught Error: execution terminated",
+      err.downcast::<JSError>().unwrap().message
+    );
+    assert!(callback_invoke_count.load(Ordering::SeqCst) > 0)
+  }
+
+  #[test]
+  fn test_stack_trace_limit() {
+    // Core has no Error.prepareStackTrace() of its own; install a minimal one
+    // that populates the fields JSError::from_v8_exception() reads frames from.
+    let source = r#"
+      Error.prepareStackTrace = (error, callSites) => {
+        Object.defineProperties(error, {
+          __callSiteEvals: {
+            value: callSites.map((callSite) => ({
+              typeName: null,
+              functionName: callSite.getFunctionName(),
+              methodName: null,
+              fileName: callSite.getFileName(),
+              lineNumber: callSite.getLineNumber(),
+              columnNumber: callSite.getColumnNumber(),
+              evalOrigin: null,
+              isTopLevel: callSite.isToplevel(),
+              isEval: callSite.isEval(),
+              isNative: callSite.isNative(),
+              isConstructor: callSite.isConstructor(),
+              isAsync: false,
+              isPromiseAll: false,
+            })),
+          },
+          __formattedFrames: { value: callSites.map(String) },
+        });
+        return "";
+      };
+      function recurse(n) {
+        if (n === 0) throw new Error("deep");
+        recurse(n - 1);
+      }
+      recurse(30);
+    "#;
+
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let err = isolate
+      .execute("deep.js", source)
+      .expect_err("should throw");
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert_eq!(js_error.frames.len(), 10);
+
+    let mut isolate =
+      CoreIsolate::with_stack_trace_limit(StartupData::None, false, 50);
+    let err = isolate
+      .execute("deep.js", source)
+      .expect_err("should throw");
+    let js_error = err.downcast::<JSError>().unwrap();
+    let frames = js_error.to_json()["frames"].as_array().unwrap().len();
+    assert!(frames > 10);
+    assert_eq!(frames, js_error.frames.len());
+    assert_eq!(js_error.frames[0].function_name.as_deref(), Some("recurse"));
+
+    // The limit combines with the other settings through with_options().
+    let extension = Extension::new().js("ext.js", "globalThis.ext = true;");
+    let mut isolate = CoreIsolate::with_options(IsolateOptions {
+      stack_trace_limit: 50,
+      heap_limits: Some(HeapLimits {
+        initial: 0,
+        max: 20 * 1024 * 1024,
+      }),
+      extensions: vec![extension],
+      ..Default::default()
+    });
+    js_check(isolate.execute("ext_check.js", "if (!ext) throw Error();"));
+    let err = isolate
+      .execute("deep.js", source)
+      .expect_err("should throw");
+    let js_error = err.downcast::<JSError>().unwrap();
+    assert!(js_error.frames.len() > 10);
+    // V8's default limit is well above a gigabyte.
+    let heap_size_limit = isolate.heap_statistics().heap_size_limit;
+    assert!(heap_size_limit < 100 * 1024 * 1024);
+  }
+
+  #[test]
+  fn test_malformed_error_fields() {
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let err = isolate
+      .execute(
+        "malformed.js",
+        r#"
+        Error.prepareStackTrace = (error, _callSites) => {
+          Object.defineProperties(error, {
+            __callSiteEvals: {
+              value: [null, {}, { isEval: "yes", lineNumber: "1" }],
+            },
+            __formattedFrames: { value: [1, "formatted"] },
+          });
+          return "";
+        };
+        // Thrown from a native builtin.
+        JSON.parse("{");
+        "#,
+      )
+      .expect_err("should throw");
+    let mut js_error = err.downcast::<JSError>().unwrap();
+    assert!(js_error.message.starts_with("Uncaught SyntaxError"));
+    assert_eq!(js_error.frames.len(), 2);
+    assert_eq!(js_error.frames[1].is_eval, false);
+    assert_eq!(js_error.frames[1].line_number, None);
+    assert_eq!(js_error.formatted_frames, vec!["formatted", ""]);
+
+    // Display copes with V8 not reporting a position.
+    js_error.start_column = None;
+    js_error.end_column = None;
+    js_error.source_line = Some("JSON.parse(\"{\");".to_string());
+    assert!(js_error.to_string().contains("Uncaught SyntaxError"));
   }
 
   #[test]
@@ -1,6 +1,7 @@
 // Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
 
 use rusty_v8 as v8;
+use serde_json::json;
 use std::any::Any;
 use std::any::TypeId;
 use std::borrow::Cow;
@@ -66,6 +67,15 @@ impl ErrBox {
     Self::from(io::Error::last_os_error())
   }
 
+  /// Returns the JavaScript error class given to `ErrBox::new()` or one of
+  /// the constructors above, or `None` for an error boxed with `From`.
+  pub fn class(&self) -> Option<&'static str> {
+    match self {
+      Self::Simple { class, .. } => Some(*class),
+      Self::Boxed(_) => None,
+    }
+  }
+
   pub fn downcast<T: AnyError>(self) -> Result<T, Self> {
     match self {
       Self::Boxed(error) if Any::type_id(&*error) == TypeId::of::<T>() => {
@@ -110,6 +120,27 @@ impl From<Box<dyn AnyError>> for ErrBox {
   }
 }
 
+/// Returned by `CoreIsolate::poll()` when the isolate's execution was
+/// terminated with `terminate_execution()` while running the event loop.
+#[derive(Debug)]
+pub struct ExecutionTerminated;
+
+impl fmt::Display for ExecutionTerminated {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("execution terminated")
+  }
+}
+
+impl Error for ExecutionTerminated {}
+
+/// The default `GetErrorClassFn`. Errors created with `ErrBox::new()` keep
+/// their class, everything else is reported as a plain `Error`. Embedders
+/// that box their own error types should install a function that maps them
+/// and falls back to this one.
+pub fn get_error_class(error: &ErrBox) -> &'static str {
+  error.class().unwrap_or("Error")
+}
+
 /// A `JSError` represents an exception coming from V8, with stack frames and
 /// line numbers. The deno_cli crate defines another `JSError` type, which wraps
 /// the one defined here, that adds source map support and colorful formatting.
@@ -125,6 +156,44 @@ pub struct JSError {
   pub formatted_frames: Vec<String>,
 }
 
+/// Maps a position in generated code, given as file name and 1-based line
+/// and column numbers, to the position in the original source it was
+/// compiled from, e.g. by looking it up in a source map. Returns `None` if
+/// the position has no mapping.
+pub type SourceMapFn = dyn Fn(&str, i64, i64) -> Option<(String, i64, i64)>;
+
+/// Controls how much of the offending source line is kept in
+/// `JSError::source_line`. Minified sources can have very long lines, which
+/// bloat error payloads that are shipped elsewhere.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourceLineMode {
+  /// Keep the whole line. This is the default.
+  Full,
+  /// Keep at most this many characters, followed by `...` if truncated.
+  Truncated(usize),
+  /// Don't include the source line at all.
+  Omitted,
+}
+
+impl Default for SourceLineMode {
+  fn default() -> Self {
+    Self::Full
+  }
+}
+
+impl SourceLineMode {
+  pub(crate) fn apply(self, source_line: String) -> Option<String> {
+    match self {
+      Self::Full => Some(source_line),
+      Self::Truncated(max) => match source_line.char_indices().nth(max) {
+        Some((end, _)) => Some(format!("{}...", &source_line[..end])),
+        None => Some(source_line),
+      },
+      Self::Omitted => None,
+    }
+  }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct JSStackFrame {
   pub type_name: Option<String>,
@@ -143,7 +212,28 @@ pub struct JSStackFrame {
   pub promise_index: Option<i64>,
 }
 
-fn get_property<'a>(
+impl JSStackFrame {
+  pub fn to_json(&self) -> serde_json::Value {
+    json!({
+      "typeName": self.type_name,
+      "functionName": self.function_name,
+      "methodName": self.method_name,
+      "fileName": self.file_name,
+      "lineNumber": self.line_number,
+      "columnNumber": self.column_number,
+      "evalOrigin": self.eval_origin,
+      "isTopLevel": self.is_top_level,
+      "isEval": self.is_eval,
+      "isNative": self.is_native,
+      "isConstructor": self.is_constructor,
+      "isAsync": self.is_async,
+      "isPromiseAll": self.is_promise_all,
+      "promiseIndex": self.promise_index,
+    })
+  }
+}
+
+pub(crate) fn get_property<'a>(
   scope: &mut v8::HandleScope<'a>,
   object: v8::Local<v8::Object>,
   key: &str,
@@ -152,6 +242,36 @@ fn get_property<'a>(
   object.get(scope, key.into())
 }
 
+fn get_string_property(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  key: &str,
+) -> Option<String> {
+  get_property(scope, object, key)
+    .and_then(|v| v8::Local::<v8::String>::try_from(v).ok())
+    .map(|s| s.to_rust_string_lossy(scope))
+}
+
+fn get_integer_property(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  key: &str,
+) -> Option<i64> {
+  get_property(scope, object, key)
+    .and_then(|v| v8::Local::<v8::Integer>::try_from(v).ok())
+    .map(|n| n.value())
+}
+
+fn get_bool_property(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  key: &str,
+) -> Option<bool> {
+  get_property(scope, object, key)
+    .and_then(|v| v8::Local::<v8::Boolean>::try_from(v).ok())
+    .map(|b| b.is_true())
+}
+
 impl JSError {
   pub(crate) fn create(js_error: Self) -> ErrBox {
     js_error.into()
@@ -160,6 +280,18 @@ impl JSError {
   pub fn from_v8_exception(
     scope: &mut v8::HandleScope,
     exception: v8::Local<v8::Value>,
+  ) -> Self {
+    Self::from_v8_exception_with_source_line_mode(
+      scope,
+      exception,
+      SourceLineMode::Full,
+    )
+  }
+
+  pub(crate) fn from_v8_exception_with_source_line_mode(
+    scope: &mut v8::HandleScope,
+    exception: v8::Local<v8::Value>,
+    source_line_mode: SourceLineMode,
   ) -> Self {
     // Create a new HandleScope because we're creating a lot of new local
     // handles below.
@@ -205,115 +337,58 @@ impl JSError {
         (frames_v8, formatted_frames_v8)
       {
         for i in 0..frames_v8.length() {
-          let call_site: v8::Local<v8::Object> =
-            frames_v8.get_index(scope, i).unwrap().try_into().unwrap();
-          let type_name: Option<v8::Local<v8::String>> =
-            get_property(scope, call_site, "typeName")
-              .unwrap()
-              .try_into()
-              .ok();
-          let type_name = type_name.map(|s| s.to_rust_string_lossy(scope));
-          let function_name: Option<v8::Local<v8::String>> =
-            get_property(scope, call_site, "functionName")
-              .unwrap()
-              .try_into()
-              .ok();
-          let function_name =
-            function_name.map(|s| s.to_rust_string_lossy(scope));
-          let method_name: Option<v8::Local<v8::String>> =
-            get_property(scope, call_site, "methodName")
-              .unwrap()
-              .try_into()
-              .ok();
-          let method_name = method_name.map(|s| s.to_rust_string_lossy(scope));
-          let file_name: Option<v8::Local<v8::String>> =
-            get_property(scope, call_site, "fileName")
-              .unwrap()
-              .try_into()
-              .ok();
-          let file_name = file_name.map(|s| s.to_rust_string_lossy(scope));
-          let line_number: Option<v8::Local<v8::Integer>> =
-            get_property(scope, call_site, "lineNumber")
-              .unwrap()
-              .try_into()
-              .ok();
-          let line_number = line_number.map(|n| n.value());
-          let column_number: Option<v8::Local<v8::Integer>> =
-            get_property(scope, call_site, "columnNumber")
-              .unwrap()
-              .try_into()
-              .ok();
-          let column_number = column_number.map(|n| n.value());
-          let eval_origin: Option<v8::Local<v8::String>> =
-            get_property(scope, call_site, "evalOrigin")
-              .unwrap()
-              .try_into()
-              .ok();
-          let eval_origin = eval_origin.map(|s| s.to_rust_string_lossy(scope));
-          let is_top_level: Option<v8::Local<v8::Boolean>> =
-            get_property(scope, call_site, "isTopLevel")
-              .unwrap()
-              .try_into()
-              .ok();
-          let is_top_level = is_top_level.map(|b| b.is_true());
-          let is_eval: v8::Local<v8::Boolean> =
-            get_property(scope, call_site, "isEval")
-              .unwrap()
-              .try_into()
-              .unwrap();
-          let is_eval = is_eval.is_true();
-          let is_native: v8::Local<v8::Boolean> =
-            get_property(scope, call_site, "isNative")
-              .unwrap()
-              .try_into()
-              .unwrap();
-          let is_native = is_native.is_true();
-          let is_constructor: v8::Local<v8::Boolean> =
-            get_property(scope, call_site, "isConstructor")
-              .unwrap()
-              .try_into()
-              .unwrap();
-          let is_constructor = is_constructor.is_true();
-          let is_async: v8::Local<v8::Boolean> =
-            get_property(scope, call_site, "isAsync")
-              .unwrap()
-              .try_into()
-              .unwrap();
-          let is_async = is_async.is_true();
-          let is_promise_all: v8::Local<v8::Boolean> =
-            get_property(scope, call_site, "isPromiseAll")
-              .unwrap()
-              .try_into()
-              .unwrap();
-          let is_promise_all = is_promise_all.is_true();
-          let promise_index: Option<v8::Local<v8::Integer>> =
-            get_property(scope, call_site, "columnNumber")
-              .unwrap()
-              .try_into()
-              .ok();
-          let promise_index = promise_index.map(|n| n.value());
+          // Call sites are produced by Error.prepareStackTrace(), which user
+          // code can override, so tolerate malformed entries rather than
+          // panicking while reporting an error.
+          let call_site = match frames_v8
+            .get_index(scope, i)
+            .and_then(|v| v8::Local::<v8::Object>::try_from(v).ok())
+          {
+            Some(call_site) => call_site,
+            None => continue,
+          };
           frames.push(JSStackFrame {
-            type_name,
-            function_name,
-            method_name,
-            file_name,
-            line_number,
-            column_number,
-            eval_origin,
-            is_top_level,
-            is_eval,
-            is_native,
-            is_constructor,
-            is_async,
-            is_promise_all,
-            promise_index,
+            type_name: get_string_property(scope, call_site, "typeName"),
+            function_name: get_string_property(
+              scope,
+              call_site,
+              "functionName",
+            ),
+            method_name: get_string_property(scope, call_site, "methodName"),
+            file_name: get_string_property(scope, call_site, "fileName"),
+            line_number: get_integer_property(scope, call_site, "lineNumber"),
+            column_number: get_integer_property(
+              scope,
+              call_site,
+              "columnNumber",
+            ),
+            eval_origin: get_string_property(scope, call_site, "evalOrigin"),
+            is_top_level: get_bool_property(scope, call_site, "isTopLevel"),
+            is_eval: get_bool_property(scope, call_site, "isEval")
+              .unwrap_or(false),
+            is_native: get_bool_property(scope, call_site, "isNative")
+              .unwrap_or(false),
+            is_constructor: get_bool_property(
+              scope,
+              call_site,
+              "isConstructor",
+            )
+            .unwrap_or(false),
+            is_async: get_bool_property(scope, call_site, "isAsync")
+              .unwrap_or(false),
+            is_promise_all: get_bool_property(scope, call_site, "isPromiseAll")
+              .unwrap_or(false),
+            promise_index: get_integer_property(
+              scope,
+              call_site,
+              "promiseIndex",
+            ),
           });
-          let formatted_frame: v8::Local<v8::String> = formatted_frames_v8
+          let formatted_frame = formatted_frames_v8
             .get_index(scope, i)
-            .unwrap()
-            .try_into()
-            .unwrap();
-          let formatted_frame = formatted_frame.to_rust_string_lossy(scope);
+            .and_then(|v| v8::Local::<v8::String>::try_from(v).ok())
+            .map(|s| s.to_rust_string_lossy(scope))
+            .unwrap_or_default();
           formatted_frames.push(formatted_frame)
         }
       }
@@ -333,7 +408,8 @@ impl JSError {
         .map(|v| v.to_rust_string_lossy(scope)),
       source_line: msg
         .get_source_line(scope)
-        .map(|v| v.to_rust_string_lossy(scope)),
+        .map(|v| v.to_rust_string_lossy(scope))
+        .and_then(|line| source_line_mode.apply(line)),
       line_number: msg.get_line_number(scope).and_then(|v| v.try_into().ok()),
       start_column: msg.get_start_column().try_into().ok(),
       end_column: msg.get_end_column().try_into().ok(),
@@ -341,6 +417,66 @@ impl JSError {
       formatted_frames,
     }
   }
+
+  /// Rewrites the location of the error and of its frames to positions in
+  /// the original source. The source line is dropped if the location is
+  /// mapped, as it was taken from the generated code. The formatted frame of
+  /// each mapped frame is rebuilt from it, in the format V8 uses for stack
+  /// traces, so that `Display` prints mapped locations throughout. The
+  /// error's `stack` property in JavaScript is not changed.
+  pub fn apply_source_map(&mut self, source_map_fn: &SourceMapFn) {
+    if let (Some(file_name), Some(line_number), Some(start_column)) = (
+      &self.script_resource_name,
+      self.line_number,
+      self.start_column,
+    ) {
+      // start_column is 0-based, the mapping is 1-based.
+      if let Some((file_name, line_number, column_number)) =
+        source_map_fn(file_name, line_number, start_column + 1)
+      {
+        let new_start_column = column_number - 1;
+        // Keep the highlighted range the same width.
+        self.end_column = self
+          .end_column
+          .map(|end_column| end_column - start_column + new_start_column);
+        self.script_resource_name = Some(file_name);
+        self.line_number = Some(line_number);
+        self.start_column = Some(new_start_column);
+        self.source_line = None;
+      }
+    }
+
+    for (i, frame) in self.frames.iter_mut().enumerate() {
+      if let (Some(file_name), Some(line_number), Some(column_number)) =
+        (&frame.file_name, frame.line_number, frame.column_number)
+      {
+        if let Some((file_name, line_number, column_number)) =
+          source_map_fn(file_name, line_number, column_number)
+        {
+          frame.file_name = Some(file_name);
+          frame.line_number = Some(line_number);
+          frame.column_number = Some(column_number);
+          if let Some(formatted_frame) = self.formatted_frames.get_mut(i) {
+            *formatted_frame = format_frame(frame);
+          }
+        }
+      }
+    }
+  }
+
+  /// Encodes the error as JSON, e.g. to hand it to embedder callbacks.
+  pub fn to_json(&self) -> serde_json::Value {
+    json!({
+      "message": self.message,
+      "sourceLine": self.source_line,
+      "scriptResourceName": self.script_resource_name,
+      "lineNumber": self.line_number,
+      "startColumn": self.start_column,
+      "endColumn": self.end_column,
+      "frames": self.frames.iter().map(JSStackFrame::to_json).collect::<Vec<_>>(),
+      "formattedFrames": self.formatted_frames,
+    })
+  }
 }
 
 impl Error for JSError {}
@@ -355,33 +491,82 @@ fn format_source_loc(
   format!("{}:{}:{}", file_name, line_number, column_number)
 }
 
+/// Formats a stack frame the way V8's `CallSite.prototype.toString()` does,
+/// without the `    at ` prefix.
+fn format_frame(frame: &JSStackFrame) -> String {
+  let location = if frame.is_native {
+    "native".to_string()
+  } else {
+    match (&frame.file_name, frame.line_number, frame.column_number) {
+      (Some(file_name), Some(line_number), Some(column_number)) => {
+        format_source_loc(file_name, line_number, column_number)
+      }
+      (Some(file_name), _, _) => file_name.clone(),
+      _ => "<anonymous>".to_string(),
+    }
+  };
+
+  if frame.is_promise_all {
+    return format!("Promise.all (index {})", frame.promise_index.unwrap_or(0));
+  }
+
+  let is_method_call =
+    !(frame.is_top_level.unwrap_or(false) || frame.is_constructor);
+  let name = if frame.is_constructor {
+    let function_name = frame.function_name.as_deref();
+    Some(format!("new {}", function_name.unwrap_or("<anonymous>")))
+  } else if is_method_call {
+    let function_name =
+      frame.function_name.as_ref().or(frame.method_name.as_ref());
+    match (&frame.type_name, function_name) {
+      (Some(type_name), Some(function_name))
+        if !function_name.starts_with(type_name.as_str()) =>
+      {
+        Some(format!("{}.{}", type_name, function_name))
+      }
+      (_, Some(function_name)) => Some(function_name.clone()),
+      (Some(type_name), None) => Some(format!("{}.<anonymous>", type_name)),
+      (None, None) => None,
+    }
+  } else {
+    frame.function_name.clone()
+  };
+
+  let prefix = if frame.is_async { "async " } else { "" };
+  match name {
+    Some(name) => format!("{}{} ({})", prefix, name, location),
+    None => format!("{}{}", prefix, location),
+  }
+}
+
 impl fmt::Display for JSError {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     if let Some(script_resource_name) = &self.script_resource_name {
-      if self.line_number.is_some() && self.start_column.is_some() {
-        assert!(self.line_number.is_some());
-        assert!(self.start_column.is_some());
-        let source_loc = format_source_loc(
-          script_resource_name,
-          self.line_number.unwrap(),
-          self.start_column.unwrap(),
-        );
+      if let (Some(line_number), Some(start_column)) =
+        (self.line_number, self.start_column)
+      {
+        let source_loc =
+          format_source_loc(script_resource_name, line_number, start_column);
         write!(f, "{}", source_loc)?;
       }
-      if self.source_line.is_some() {
-        let source_line = self.source_line.as_ref().unwrap();
+      if let Some(source_line) = &self.source_line {
         write!(f, "\n{}\n", source_line)?;
-        let mut s = String::new();
-        for i in 0..self.end_column.unwrap() {
-          if i >= self.start_column.unwrap() {
-            s.push('^');
-          } else if source_line.chars().nth(i as usize).unwrap() == '\t' {
-            s.push('\t');
-          } else {
-            s.push(' ');
+        // The caret line is only drawn if V8 reported where the error is.
+        if let (Some(start_column), Some(end_column)) =
+          (self.start_column, self.end_column)
+        {
+          let mut s = String::new();
+          for i in 0..end_column {
+            if i >= start_column {
+              s.push('^');
+            } else if source_line.chars().nth(i as usize) == Some('\t') {
+              s.push('\t');
+            } else {
+              s.push(' ');
+            }
           }
+          writeln!(f, "{}", s)?;
         }
-        writeln!(f, "{}", s)?;
       }
     }
 
@@ -446,19 +631,29 @@ impl fmt::Debug for ErrWithV8Handle {
   }
 }
 
-#[cfg(tests)]
+#[cfg(test)]
 mod tests {
+  use super::*;
+
   #[test]
   fn test_bad_resource() {
     let err = ErrBox::bad_resource("Resource has been closed");
-    assert_eq!(err.1, "BadResource");
+    assert_eq!(err.class(), Some("BadResource"));
     assert_eq!(err.to_string(), "Resource has been closed");
   }
 
   #[test]
   fn test_bad_resource_id() {
     let err = ErrBox::bad_resource_id();
-    assert_eq!(err.1, "BadResource");
+    assert_eq!(err.class(), Some("BadResource"));
     assert_eq!(err.to_string(), "Bad resource ID");
   }
+
+  #[test]
+  fn test_get_error_class() {
+    assert_eq!(get_error_class(&ErrBox::new("Custom", "msg")), "Custom");
+    assert_eq!(get_error_class(&ErrBox::type_error("msg")), "TypeError");
+    let io_err = io::Error::new(io::ErrorKind::NotFound, "msg");
+    assert_eq!(get_error_class(&ErrBox::from(io_err)), "Error");
+  }
 }
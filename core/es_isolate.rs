@@ -10,6 +10,7 @@ use crate::bindings;
 use crate::errors::ErrBox;
 use crate::errors::ErrWithV8Handle;
 use crate::futures::FutureExt;
+use futures::future::poll_fn;
 use futures::ready;
 use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
@@ -19,6 +20,7 @@ use futures::Future;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::num::NonZeroUsize;
 use std::ops::{Deref, DerefMut};
 use std::option::Option;
 use std::pin::Pin;
@@ -27,9 +29,12 @@ use std::task::Context;
 use std::task::Poll;
 
 use crate::core_isolate::exception_to_err_result;
+use crate::core_isolate::RealmId;
 use crate::errors::attach_handle_to_error;
+use crate::extensions::Extension;
 use crate::module_specifier::ModuleSpecifier;
 use crate::modules::LoadState;
+use crate::modules::ModuleGraphEntry;
 use crate::modules::ModuleId;
 use crate::modules::ModuleLoadId;
 use crate::modules::ModuleLoader;
@@ -37,7 +42,9 @@ use crate::modules::ModuleSource;
 use crate::modules::Modules;
 use crate::modules::PrepareLoadFuture;
 use crate::modules::RecursiveModuleLoad;
+use crate::ChildContext;
 use crate::CoreIsolate;
+use crate::IsolateOptions;
 use crate::StartupData;
 
 /// More specialized version of `CoreIsolate` that provides loading
@@ -48,11 +55,28 @@ use crate::StartupData;
 /// loading of modules can be customized by the implementor.
 pub struct EsIsolate(CoreIsolate);
 
+/// A dynamic import whose promise has not been settled yet.
+pub(crate) struct PendingDynImport {
+  /// The specifier passed to `import()`, as written.
+  specifier: String,
+  resolver: v8::Global<v8::PromiseResolver>,
+}
+
+/// Decides whether a dynamic import of a specifier from a referrer may go
+/// ahead. Returning an error rejects the import with a `TypeError` carrying
+/// its message.
+type DynImportPolicyFn = dyn Fn(&str, &str) -> Result<(), String>;
+
 pub struct EsIsolateState {
   loader: Rc<dyn ModuleLoader>,
   pub modules: Modules,
-  pub(crate) dyn_import_map:
-    HashMap<ModuleLoadId, v8::Global<v8::PromiseResolver>>,
+  /// Modules created with `EsIsolate::mod_new_in_context()`, keyed by the
+  /// realm they belong to.
+  realm_modules: HashMap<RealmId, Modules>,
+  pub(crate) dyn_import_map: HashMap<ModuleLoadId, PendingDynImport>,
+  dyn_import_policy: Option<Box<DynImportPolicyFn>>,
+  allow_dyn_import: bool,
+  max_concurrent_module_loads: Option<NonZeroUsize>,
 
   preparing_dyn_imports: FuturesUnordered<Pin<Box<PrepareLoadFuture>>>,
   pending_dyn_imports: FuturesUnordered<StreamFuture<RecursiveModuleLoad>>,
@@ -79,7 +103,34 @@ impl EsIsolate {
     startup_data: StartupData,
     will_snapshot: bool,
   ) -> Self {
-    let mut core_isolate = CoreIsolate::new(startup_data, will_snapshot);
+    Self::with_extensions(loader, startup_data, will_snapshot, Vec::new())
+  }
+
+  /// Like `new()`, but installs the given extensions in addition to the
+  /// built-in `Deno.core` bindings.
+  pub fn with_extensions(
+    loader: Rc<dyn ModuleLoader>,
+    startup_data: StartupData,
+    will_snapshot: bool,
+    extensions: Vec<Extension>,
+  ) -> Self {
+    Self::with_options(
+      loader,
+      IsolateOptions {
+        startup_data,
+        will_snapshot,
+        extensions,
+        ..Default::default()
+      },
+    )
+  }
+
+  /// Like `CoreIsolate::with_options()`, with module support.
+  pub fn with_options(
+    loader: Rc<dyn ModuleLoader>,
+    options: IsolateOptions,
+  ) -> Self {
+    let mut core_isolate = CoreIsolate::with_options(options);
     {
       core_isolate.set_host_initialize_import_meta_object_callback(
         bindings::host_initialize_import_meta_object_callback,
@@ -91,8 +142,12 @@ impl EsIsolate {
 
     core_isolate.set_slot(Rc::new(RefCell::new(EsIsolateState {
       modules: Modules::new(),
+      realm_modules: HashMap::new(),
       loader,
       dyn_import_map: HashMap::new(),
+      dyn_import_policy: None,
+      allow_dyn_import: true,
+      max_concurrent_module_loads: None,
       preparing_dyn_imports: FuturesUnordered::new(),
       pending_dyn_imports: FuturesUnordered::new(),
       waker: AtomicWaker::new(),
@@ -109,18 +164,55 @@ impl EsIsolate {
     main: bool,
     name: &str,
     source: &str,
+  ) -> Result<ModuleId, ErrBox> {
+    self.mod_new_in(None, main, name, source)
+  }
+
+  /// Compiles an ES module in a context created with `new_context()` and
+  /// registers it in that realm's own module map. Its static imports only
+  /// resolve to modules registered in the same realm, so each module the
+  /// realm imports has to be created with this function before the module
+  /// importing it is evaluated. The loader is only used to resolve
+  /// specifiers, and dynamic imports are not supported in child realms.
+  pub fn mod_new_in_context(
+    &mut self,
+    context: &ChildContext,
+    main: bool,
+    name: &str,
+    source: &str,
+  ) -> Result<ModuleId, ErrBox> {
+    // Drop the module maps of realms that no longer exist.
+    let core_state_rc = CoreIsolate::state(self);
+    Self::state(self)
+      .borrow_mut()
+      .realm_modules
+      .retain(|realm_id, _| core_state_rc.borrow().has_realm(*realm_id));
+    self.mod_new_in(Some(context), main, name, source)
+  }
+
+  fn mod_new_in(
+    &mut self,
+    context: Option<&ChildContext>,
+    main: bool,
+    name: &str,
+    source: &str,
   ) -> Result<ModuleId, ErrBox> {
     let state_rc = Self::state(self);
     let core_state_rc = CoreIsolate::state(self);
-    let scope = &mut v8::HandleScope::with_context(
-      &mut *self.0,
-      core_state_rc.borrow().global_context.as_ref().unwrap(),
-    );
+    let scope = &mut match context {
+      Some(context) => {
+        v8::HandleScope::with_context(&mut *self.0, context.get())
+      }
+      None => v8::HandleScope::with_context(
+        &mut *self.0,
+        core_state_rc.borrow().global_context.as_ref().unwrap(),
+      ),
+    };
 
     let name_str = v8::String::new(scope, name).unwrap();
     let source_str = v8::String::new(scope, source).unwrap();
 
-    let origin = bindings::module_origin(scope, name_str);
+    let origin = bindings::module_origin(scope, name_str, &Default::default());
     let source = v8::script_compiler::Source::new(source_str, &origin);
 
     let tc_scope = &mut v8::TryCatch::new(scope);
@@ -137,16 +229,22 @@ impl EsIsolate {
     let id = module.get_identity_hash();
 
     let mut import_specifiers: Vec<ModuleSpecifier> = vec![];
-    for i in 0..module.get_module_requests_length() {
-      let import_specifier =
-        module.get_module_request(i).to_rust_string_lossy(tc_scope);
+    for import_specifier in module_requests(tc_scope, module) {
       let state = state_rc.borrow();
       let module_specifier =
         state.loader.resolve(&import_specifier, name, false)?;
       import_specifiers.push(module_specifier);
     }
 
-    state_rc.borrow_mut().modules.register(
+    let mut state = state_rc.borrow_mut();
+    let modules = match context {
+      Some(context) => state
+        .realm_modules
+        .entry(context.realm_id())
+        .or_insert_with(Modules::new),
+      None => &mut state.modules,
+    };
+    modules.register(
       id,
       name,
       main,
@@ -163,19 +261,32 @@ impl EsIsolate {
   /// the V8 exception. By default this type is JSError, however it may be a
   /// different type if CoreIsolate::set_js_error_create_fn() has been used.
   fn mod_instantiate(&mut self, id: ModuleId) -> Result<(), ErrBox> {
+    self.mod_instantiate_in(None, id)
+  }
+
+  fn mod_instantiate_in(
+    &mut self,
+    context: Option<&ChildContext>,
+    id: ModuleId,
+  ) -> Result<(), ErrBox> {
     let core_state_rc = CoreIsolate::state(self);
-    let core_state = core_state_rc.borrow();
-    let scope = &mut v8::HandleScope::with_context(
-      &mut *self.0,
-      core_state.global_context.as_ref().unwrap(),
-    );
+    let scope = &mut match context {
+      Some(context) => {
+        v8::HandleScope::with_context(&mut *self.0, context.get())
+      }
+      None => v8::HandleScope::with_context(
+        &mut *self.0,
+        core_state_rc.borrow().global_context.as_ref().unwrap(),
+      ),
+    };
     let tc_scope = &mut v8::TryCatch::new(scope);
 
-    let module = match Self::state(tc_scope).borrow().modules.get_info(id) {
-      Some(info) => v8::Local::new(tc_scope, &info.handle),
-      None if id == 0 => return Ok(()),
-      _ => panic!("module id {} not found in module table", id),
-    };
+    let module =
+      match Self::state(tc_scope).borrow().modules_of(id).get_info(id) {
+        Some(info) => v8::Local::new(tc_scope, &info.handle),
+        None if id == 0 => return Ok(()),
+        _ => panic!("module id {} not found in module table", id),
+      };
 
     if module.get_status() == v8::ModuleStatus::Errored {
       exception_to_err_result(tc_scope, module.get_exception())?
@@ -194,26 +305,126 @@ impl EsIsolate {
 
   /// Evaluates an already instantiated ES module.
   ///
+  /// If the module uses top-level await, this returns as soon as the module
+  /// first suspends, and a later rejection surfaces as an unhandled promise
+  /// rejection when the isolate is polled. Use `mod_evaluate_async()` to wait
+  /// for the evaluation to finish instead.
+  ///
   /// ErrBox can be downcast to a type that exposes additional information about
   /// the V8 exception. By default this type is JSError, however it may be a
   /// different type if CoreIsolate::set_js_error_create_fn() has been used.
   pub fn mod_evaluate(&mut self, id: ModuleId) -> Result<(), ErrBox> {
-    self.shared_init();
+    self.evaluate_module(None, id).map(|_| ())
+  }
 
-    let core_state_rc = CoreIsolate::state(self);
+  /// Instantiates and evaluates a module created with
+  /// `mod_new_in_context()`, in the context it was created in. Like
+  /// `mod_evaluate()`, this returns as soon as a module using top-level await
+  /// first suspends.
+  pub fn mod_evaluate_in_context(
+    &mut self,
+    context: &ChildContext,
+    id: ModuleId,
+  ) -> Result<(), ErrBox> {
+    self.mod_instantiate_in(Some(context), id)?;
+    self.evaluate_module(Some(context), id).map(|_| ())
+  }
+
+  /// Evaluates an already instantiated ES module, driving the event loop
+  /// until its evaluation has finished if it uses top-level await. A
+  /// rejection of the evaluation is returned as an error, like an exception
+  /// thrown synchronously by the module.
+  ///
+  /// Fails if the event loop runs out of work while the module is still
+  /// suspended, i.e. if it awaits a promise that can never settle.
+  pub async fn mod_evaluate_async(
+    &mut self,
+    id: ModuleId,
+  ) -> Result<(), ErrBox> {
+    let promise = match self.evaluate_module(None, id)? {
+      Some(promise) => promise,
+      None => return Ok(()),
+    };
+    poll_fn(|cx| {
+      if let Some(result) = self.evaluation_result(&promise) {
+        return Poll::Ready(result);
+      }
+      let poll_result = self.poll_unpin(cx);
+      // The rejection of the evaluation takes precedence over the error
+      // poll() reports for it as an unhandled rejection.
+      if let Some(result) = self.evaluation_result(&promise) {
+        return Poll::Ready(result);
+      }
+      match poll_result {
+        Poll::Ready(Ok(())) => Poll::Ready(Err(ErrBox::error(
+          "Module evaluation is still pending but there are no pending ops",
+        ))),
+        Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+        Poll::Pending => Poll::Pending,
+      }
+    })
+    .await
+  }
 
+  /// Returns the outcome of a module evaluation promise, or `None` while it
+  /// is pending.
+  fn evaluation_result(
+    &mut self,
+    promise: &v8::Global<v8::Promise>,
+  ) -> Option<Result<(), ErrBox>> {
+    let core_state_rc = CoreIsolate::state(self);
     let scope = &mut v8::HandleScope::with_context(
       &mut *self.0,
       core_state_rc.borrow().global_context.as_ref().unwrap(),
     );
+    let promise = v8::Local::new(scope, promise);
+    match promise.state() {
+      v8::PromiseState::Pending => None,
+      v8::PromiseState::Fulfilled => Some(Ok(())),
+      v8::PromiseState::Rejected => {
+        let promise_id = promise.get_identity_hash();
+        core_state_rc
+          .borrow_mut()
+          .pending_promise_exceptions
+          .remove(&promise_id);
+        let exception = promise.result(scope);
+        Some(
+          exception_to_err_result(scope, exception)
+            .map_err(|err| attach_handle_to_error(scope, err, exception)),
+        )
+      }
+    }
+  }
+
+  /// Evaluates the module and returns its evaluation promise if the module
+  /// suspended at a top-level await.
+  fn evaluate_module(
+    &mut self,
+    context: Option<&ChildContext>,
+    id: ModuleId,
+  ) -> Result<Option<v8::Global<v8::Promise>>, ErrBox> {
+    self.shared_init();
+
+    let core_state_rc = CoreIsolate::state(self);
+
+    let scope = &mut match context {
+      Some(context) => {
+        v8::HandleScope::with_context(&mut *self.0, context.get())
+      }
+      None => v8::HandleScope::with_context(
+        &mut *self.0,
+        core_state_rc.borrow().global_context.as_ref().unwrap(),
+      ),
+    };
 
     let module = Self::state(scope)
       .borrow()
-      .modules
+      .modules_of(id)
       .get_info(id)
       .map(|info| v8::Local::new(scope, &info.handle))
       .expect("ModuleInfo not found");
     let mut status = module.get_status();
+    let mut maybe_pending_promise = None;
 
     if status == v8::ModuleStatus::Instantiated {
       // IMPORTANT: Top-level-await is enabled, which means that return value
@@ -247,13 +458,16 @@ impl EsIsolate {
         let promise_id = promise.get_identity_hash();
         let mut core_state = core_state_rc.borrow_mut();
         core_state.pending_promise_exceptions.remove(&promise_id);
+        if let v8::PromiseState::Pending = promise.state() {
+          maybe_pending_promise = Some(v8::Global::new(scope, promise));
+        }
       } else {
         assert!(status == v8::ModuleStatus::Errored);
       }
     }
 
     match status {
-      v8::ModuleStatus::Evaluated => Ok(()),
+      v8::ModuleStatus::Evaluated => Ok(maybe_pending_promise),
       v8::ModuleStatus::Errored => {
         let exception = module.get_exception();
         exception_to_err_result(scope, exception)
@@ -280,7 +494,8 @@ impl EsIsolate {
       .borrow_mut()
       .dyn_import_map
       .remove(&id)
-      .expect("Invalid dyn import id");
+      .expect("Invalid dyn import id")
+      .resolver;
     let resolver = resolver_handle.get(scope);
 
     let exception = err
@@ -317,7 +532,8 @@ impl EsIsolate {
       .borrow_mut()
       .dyn_import_map
       .remove(&id)
-      .expect("Invalid dyn import id");
+      .expect("Invalid dyn import id")
+      .resolver;
     let resolver = resolver_handle.get(scope);
 
     let module = {
@@ -357,6 +573,15 @@ impl EsIsolate {
           let dyn_import_id = prepare_poll.0;
           let prepare_result = prepare_poll.1;
 
+          if !state_rc
+            .borrow()
+            .dyn_import_map
+            .contains_key(&dyn_import_id)
+          {
+            // Rejected with reject_dyn_import(); abandon the load.
+            continue;
+          }
+
           match prepare_result {
             Ok(load) => {
               let state = state_rc.borrow_mut();
@@ -389,6 +614,15 @@ impl EsIsolate {
           let mut load = load_stream_poll.1;
           let dyn_import_id = load.id;
 
+          if !state_rc
+            .borrow()
+            .dyn_import_map
+            .contains_key(&dyn_import_id)
+          {
+            // Rejected with reject_dyn_import(); abandon the load.
+            continue;
+          }
+
           if let Some(load_stream_result) = maybe_result {
             match load_stream_result {
               Ok(info) => {
@@ -521,13 +755,14 @@ impl EsIsolate {
     code: Option<String>,
   ) -> Result<ModuleId, ErrBox> {
     self.shared_init();
-    let loader = {
+    let (loader, max_concurrent_loads) = {
       let state_rc = Self::state(self);
       let state = state_rc.borrow();
-      state.loader.clone()
+      (state.loader.clone(), state.max_concurrent_module_loads)
     };
 
-    let load = RecursiveModuleLoad::main(&specifier.to_string(), code, loader);
+    let load = RecursiveModuleLoad::main(&specifier.to_string(), code, loader)
+      .with_max_concurrent_loads(max_concurrent_loads);
     let (_load_id, prepare_result) = load.prepare().await;
 
     let mut load = prepare_result?;
@@ -541,6 +776,99 @@ impl EsIsolate {
     self.mod_instantiate(root_id).map(|_| root_id)
   }
 
+  /// Returns the specifiers imported by module `id` as written in its source,
+  /// each with the id of the module it resolves to if that module has been
+  /// registered already. Returns an empty list if `id` is unknown.
+  pub fn module_dependencies(
+    &mut self,
+    id: ModuleId,
+  ) -> Vec<(String, Option<ModuleId>)> {
+    let state_rc = Self::state(self);
+    let core_state_rc = CoreIsolate::state(self);
+    let scope = &mut v8::HandleScope::with_context(
+      &mut *self.0,
+      core_state_rc.borrow().global_context.as_ref().unwrap(),
+    );
+    let state = state_rc.borrow();
+    state.module_dependencies(scope, id)
+  }
+
+  /// Returns every registered module, ordered by id, with its dependencies
+  /// and V8 status.
+  pub fn module_graph(&mut self) -> Vec<ModuleGraphEntry> {
+    let state_rc = Self::state(self);
+    let core_state_rc = CoreIsolate::state(self);
+    let scope = &mut v8::HandleScope::with_context(
+      &mut *self.0,
+      core_state_rc.borrow().global_context.as_ref().unwrap(),
+    );
+    let state = state_rc.borrow();
+    state.module_graph(scope)
+  }
+
+  /// Sets a policy consulted with the specifier and referrer of every dynamic
+  /// import before the module loader sees it, e.g. to only allow imports of
+  /// certain origins. Imports the policy rejects fail with a `TypeError`
+  /// carrying the policy's message.
+  pub fn set_dyn_import_policy(
+    &mut self,
+    policy: impl Fn(&str, &str) -> Result<(), String> + 'static,
+  ) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().dyn_import_policy = Some(Box::new(policy));
+  }
+
+  /// Enables or disables `import()`. While disabled, every dynamic import is
+  /// rejected with a `TypeError` before the policy or the module loader are
+  /// consulted, e.g. to keep the module graph of a snapshot fully static.
+  /// Dynamic imports are allowed by default.
+  pub fn set_allow_dyn_import(&mut self, allow: bool) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().allow_dyn_import = allow;
+  }
+
+  /// Limits how many modules a static or dynamic import graph fetches from
+  /// the `ModuleLoader` at once, e.g. to avoid opening hundreds of
+  /// connections for a deep remote dependency tree. `None`, the default,
+  /// fetches every discovered import right away.
+  pub fn set_max_concurrent_module_loads(
+    &mut self,
+    limit: Option<NonZeroUsize>,
+  ) {
+    let state_rc = Self::state(self);
+    state_rc.borrow_mut().max_concurrent_module_loads = limit;
+  }
+
+  /// Returns the id and specifier of every dynamic import whose promise has
+  /// not settled yet, ordered by id.
+  pub fn pending_dyn_imports(&self) -> Vec<(ModuleLoadId, String)> {
+    let state_rc = Self::state(self);
+    let state = state_rc.borrow();
+    let mut pending: Vec<_> = state
+      .dyn_import_map
+      .iter()
+      .map(|(id, import)| (*id, import.specifier.clone()))
+      .collect();
+    pending.sort_by_key(|(id, _)| *id);
+    pending
+  }
+
+  /// Rejects the promise of a pending dynamic import with a `TypeError`
+  /// carrying `message`, e.g. to time out a stuck import. Loading of the
+  /// module is abandoned. Returns false if the import is no longer pending.
+  pub fn reject_dyn_import(
+    &mut self,
+    id: ModuleLoadId,
+    message: &str,
+  ) -> Result<bool, ErrBox> {
+    let state_rc = Self::state(self);
+    if !state_rc.borrow().dyn_import_map.contains_key(&id) {
+      return Ok(false);
+    }
+    self.dyn_import_error(id, ErrBox::error(message.to_string()))?;
+    Ok(true)
+  }
+
   pub fn snapshot(&mut self) -> v8::StartupData {
     let state_rc = Self::state(self);
     std::mem::take(&mut state_rc.borrow_mut().modules);
@@ -607,12 +935,110 @@ impl EsIsolateState {
     specifier: &str,
     referrer_id: ModuleId,
   ) -> ModuleId {
-    let referrer = self.modules.get_name(referrer_id).unwrap();
-    let specifier = self
-      .loader
-      .resolve(specifier, referrer, false)
-      .expect("Module should have been already resolved");
-    self.modules.get_id(specifier.as_str()).unwrap_or(0)
+    self
+      .resolve_registered(specifier, referrer_id)
+      .expect("Module should have been already resolved")
+      .unwrap_or(0)
+  }
+
+  /// Resolves `specifier` against the module `referrer_id` with the
+  /// isolate's loader. Used by `import.meta.resolve()`, which can outlive
+  /// the registration of its module, so an unknown referrer is an error.
+  pub(crate) fn resolve(
+    &self,
+    specifier: &str,
+    referrer_id: ModuleId,
+  ) -> Result<ModuleSpecifier, ErrBox> {
+    let referrer = self
+      .modules_of(referrer_id)
+      .get_name(referrer_id)
+      .ok_or_else(|| {
+        ErrBox::error(format!(
+          "Cannot resolve \"{}\": referrer module {} is not registered",
+          specifier, referrer_id
+        ))
+      })?;
+    self.loader.resolve(specifier, referrer, false)
+  }
+
+  /// Resolves `specifier` as imported by module `referrer_id` and looks up
+  /// the id of the module it refers to, if that module is registered.
+  fn resolve_registered(
+    &self,
+    specifier: &str,
+    referrer_id: ModuleId,
+  ) -> Result<Option<ModuleId>, ErrBox> {
+    let specifier = self.resolve(specifier, referrer_id)?;
+    Ok(self.modules_of(referrer_id).get_id(specifier.as_str()))
+  }
+
+  /// Returns the module map module `id` is registered in: that of the child
+  /// realm it was created in, or the global context's.
+  pub(crate) fn modules_of(&self, id: ModuleId) -> &Modules {
+    self
+      .realm_modules
+      .values()
+      .find(|modules| modules.get_info(id).is_some())
+      .unwrap_or(&self.modules)
+  }
+
+  pub(crate) fn module_dependencies(
+    &self,
+    scope: &mut v8::HandleScope,
+    id: ModuleId,
+  ) -> Vec<(String, Option<ModuleId>)> {
+    let module = match self.modules.get_info(id) {
+      Some(info) => v8::Local::new(scope, &info.handle),
+      None => return vec![],
+    };
+    module_requests(scope, module)
+      .into_iter()
+      .map(|specifier| {
+        let dep_id = self.resolve_registered(&specifier, id).ok().flatten();
+        (specifier, dep_id)
+      })
+      .collect()
+  }
+
+  pub(crate) fn module_graph(
+    &self,
+    scope: &mut v8::HandleScope,
+  ) -> Vec<ModuleGraphEntry> {
+    let mut ids: Vec<ModuleId> = self.modules.info.keys().copied().collect();
+    ids.sort_unstable();
+    ids
+      .into_iter()
+      .map(|id| {
+        let info = &self.modules.info[&id];
+        let module = v8::Local::new(scope, &info.handle);
+        ModuleGraphEntry {
+          id,
+          name: info.name.clone(),
+          main: info.main,
+          dependencies: self.module_dependencies(scope, id),
+          status: module.get_status(),
+        }
+      })
+      .collect()
+  }
+
+  /// Checks a dynamic import against `EsIsolate::set_allow_dyn_import()` and
+  /// the policy set with `EsIsolate::set_dyn_import_policy()`, if any.
+  pub(crate) fn check_dyn_import(
+    &self,
+    specifier: &str,
+    referrer: &str,
+  ) -> Result<(), String> {
+    if !self.allow_dyn_import {
+      return Err(format!(
+        "Cannot import \"{}\" from \"{}\": dynamic import is disabled",
+        specifier, referrer
+      ));
+    }
+    match &self.dyn_import_policy {
+      Some(policy) => policy(specifier, referrer),
+      None => Ok(()),
+    }
   }
 
   // Called by V8 during `Isolate::mod_instantiate`.
@@ -628,14 +1054,32 @@ impl EsIsolateState {
       specifier,
       referrer,
       self.loader.clone(),
+    )
+    .with_max_concurrent_loads(self.max_concurrent_module_loads);
+    self.dyn_import_map.insert(
+      load.id,
+      PendingDynImport {
+        specifier: specifier.to_string(),
+        resolver: resolver_handle,
+      },
     );
-    self.dyn_import_map.insert(load.id, resolver_handle);
     self.waker.wake();
     let fut = load.prepare().boxed_local();
     self.preparing_dyn_imports.push(fut);
   }
 }
 
+/// Returns the specifiers of a module's static imports, as written in its
+/// source.
+fn module_requests(
+  scope: &mut v8::HandleScope,
+  module: v8::Local<v8::Module>,
+) -> Vec<String> {
+  (0..module.get_module_requests_length())
+    .map(|i| module.get_module_request(i).to_rust_string_lossy(scope))
+    .collect()
+}
+
 #[cfg(test)]
 pub mod tests {
   use super::*;
@@ -757,6 +1201,231 @@ pub mod tests {
     assert_eq!(dispatch_count.load(Ordering::Relaxed), 1);
   }
 
+  /// Resolves specifiers against their referrer, but never finishes loading
+  /// a module. For tests that only register modules with `mod_new()`.
+  struct ResolveOnlyLoader;
+
+  impl ModuleLoader for ResolveOnlyLoader {
+    fn resolve(
+      &self,
+      specifier: &str,
+      referrer: &str,
+      _is_main: bool,
+    ) -> Result<ModuleSpecifier, ErrBox> {
+      let s = ModuleSpecifier::resolve_import(specifier, referrer)?;
+      Ok(s)
+    }
+
+    fn load(
+      &self,
+      _module_specifier: &ModuleSpecifier,
+      _maybe_referrer: Option<ModuleSpecifier>,
+      _is_dyn_import: bool,
+    ) -> Pin<Box<ModuleSourceFuture>> {
+      futures::future::pending().boxed()
+    }
+  }
+
+  #[test]
+  fn test_module_dependencies() {
+    let mut isolate =
+      EsIsolate::new(Rc::new(ResolveOnlyLoader), StartupData::None, false);
+    let mod_a = isolate
+      .mod_new(
+        true,
+        "file:///a.js",
+        r#"
+        import { b } from './b.js';
+        import { c } from './c.js';
+        "#,
+      )
+      .unwrap();
+    assert_eq!(
+      isolate.module_dependencies(mod_a),
+      vec![("./b.js".to_string(), None), ("./c.js".to_string(), None)]
+    );
+
+    let mod_b = isolate
+      .mod_new(false, "file:///b.js", "export const b = 'b';")
+      .unwrap();
+    assert_eq!(
+      isolate.module_dependencies(mod_a),
+      vec![
+        ("./b.js".to_string(), Some(mod_b)),
+        ("./c.js".to_string(), None)
+      ]
+    );
+    assert_eq!(isolate.module_dependencies(mod_b), vec![]);
+
+    js_check(isolate.execute(
+      "module_dependencies.js",
+      &format!(
+        r#"
+        const deps = Deno.core.moduleDependencies({});
+        if (deps.length !== 2) throw Error("length");
+        if (deps[0].specifier !== "./b.js" || deps[0].id !== {}) {{
+          throw Error("b");
+        }}
+        if (deps[1].specifier !== "./c.js" || deps[1].id !== null) {{
+          throw Error("c");
+        }}
+        "#,
+        mod_a, mod_b
+      ),
+    ));
+  }
+
+  #[test]
+  fn test_module_graph() {
+    let mut isolate =
+      EsIsolate::new(Rc::new(ResolveOnlyLoader), StartupData::None, false);
+    assert!(isolate.module_graph().is_empty());
+
+    let mod_a = isolate
+      .mod_new(true, "file:///a.js", "import { b } from './b.js';")
+      .unwrap();
+    let mod_b = isolate
+      .mod_new(false, "file:///b.js", "export const b = 'b';")
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_a));
+
+    let graph = isolate.module_graph();
+    assert_eq!(graph.len(), 2);
+    assert_eq!(graph[0].id, mod_a);
+    assert_eq!(graph[0].name, "file:///a.js");
+    assert!(graph[0].main);
+    assert_eq!(
+      graph[0].dependencies,
+      vec![("./b.js".to_string(), Some(mod_b))]
+    );
+    assert_eq!(graph[0].status, v8::ModuleStatus::Instantiated);
+    assert_eq!(graph[1].id, mod_b);
+    assert!(!graph[1].main);
+    assert!(graph[1].dependencies.is_empty());
+
+    js_check(isolate.execute(
+      "modules.js",
+      &format!(
+        r#"
+        const modules = Deno.core.modules();
+        if (modules.length !== 2) throw Error("length");
+        const [a, b] = modules;
+        if (a.id !== {} || a.name !== "file:///a.js" || !a.main) {{
+          throw Error("a");
+        }}
+        if (a.status !== "instantiated") throw Error(a.status);
+        if (a.dependencies[0].id !== {} || b.dependencies.length !== 0) {{
+          throw Error("dependencies");
+        }}
+        "#,
+        mod_a, mod_b
+      ),
+    ));
+  }
+
+  #[test]
+  fn test_import_meta_resolve() {
+    let mut isolate =
+      EsIsolate::new(Rc::new(ResolveOnlyLoader), StartupData::None, false);
+    let mod_a = isolate
+      .mod_new(
+        true,
+        "file:///dir/a.js",
+        r#"
+        globalThis.resolved = import.meta.resolve("./sibling.js");
+        const { resolve } = import.meta;
+        globalThis.resolve = resolve;
+        globalThis.detached = resolve("../up.js");
+        try {
+          import.meta.resolve("bare");
+        } catch (e) {
+          globalThis.resolveError = e;
+        }
+        "#,
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_a));
+    js_check(isolate.mod_evaluate(mod_a));
+
+    js_check(isolate.execute(
+      "check.js",
+      r#"
+        if (resolved !== "file:///dir/sibling.js") throw Error(resolved);
+        if (detached !== "file:///up.js") throw Error(detached);
+        if (!(resolveError instanceof TypeError)) throw Error("no TypeError");
+        "#,
+    ));
+
+    // A resolve() that outlives its module's registration throws.
+    std::mem::take(&mut EsIsolate::state(&isolate).borrow_mut().modules);
+    js_check(isolate.execute(
+      "unregistered.js",
+      r#"
+        let error;
+        try {
+          resolve("./sibling.js");
+        } catch (e) {
+          error = e;
+        }
+        if (!(error instanceof TypeError)) throw Error("no TypeError");
+        if (!error.message.includes("not registered")) throw error;
+        "#,
+    ));
+  }
+
+  #[test]
+  fn test_realm_modules() {
+    let mut isolate =
+      EsIsolate::new(Rc::new(ResolveOnlyLoader), StartupData::None, false);
+    let main_b = isolate
+      .mod_new(false, "file:///b.js", "export const b = 'main';")
+      .unwrap();
+    js_check(isolate.mod_instantiate(main_b));
+
+    let child = isolate.new_context();
+    let mod_c = isolate
+      .mod_new_in_context(&child, false, "file:///c.js", "import './b.js';")
+      .unwrap();
+    // The global context's "file:///b.js" is not visible to the realm.
+    let err = isolate.mod_evaluate_in_context(&child, mod_c).unwrap_err();
+    assert!(err.to_string().contains("Cannot resolve module"));
+
+    isolate
+      .mod_new_in_context(
+        &child,
+        false,
+        "file:///b.js",
+        "export const b = 'child';",
+      )
+      .unwrap();
+    let mod_a = isolate
+      .mod_new_in_context(
+        &child,
+        true,
+        "file:///a.js",
+        r#"
+        import { b } from "./b.js";
+        globalThis.b = b;
+        import("./b.js").catch((e) => globalThis.dynImportError = e);
+        "#,
+      )
+      .unwrap();
+    js_check(isolate.mod_evaluate_in_context(&child, mod_a));
+    js_check(isolate.execute_in_context(
+      &child,
+      "check.js",
+      r#"
+        if (b !== "child") throw Error(b);
+        if (!(dynImportError instanceof TypeError)) throw Error("imported");
+        "#,
+    ));
+
+    let state_rc = EsIsolate::state(&isolate);
+    let state = state_rc.borrow();
+    assert_eq!(state.modules.get_id("file:///b.js"), Some(main_b));
+    assert_eq!(state.modules.get_id("file:///a.js"), None);
+  }
+
   #[test]
   fn dyn_import_err() {
     #[derive(Clone, Default)]
@@ -813,6 +1482,149 @@ pub mod tests {
     })
   }
 
+  #[test]
+  fn dyn_import_reject_pending() {
+    run_in_task(|cx| {
+      let mut isolate =
+        EsIsolate::new(Rc::new(ResolveOnlyLoader), StartupData::None, false);
+
+      js_check(isolate.execute(
+        "file:///dyn_import_stuck.js",
+        r#"
+        let outcome = "pending";
+        import("./stuck.js").then(
+          () => { outcome = "resolved"; },
+          (e) => { outcome = e.message; },
+        );
+        "#,
+      ));
+
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Pending));
+      let pending = isolate.pending_dyn_imports();
+      assert_eq!(pending.len(), 1);
+      let (id, specifier) = &pending[0];
+      assert_eq!(specifier, "./stuck.js");
+
+      assert!(isolate.reject_dyn_import(*id, "timed out").unwrap());
+      assert!(isolate.pending_dyn_imports().is_empty());
+      assert!(!isolate.reject_dyn_import(*id, "timed out").unwrap());
+      js_check(isolate.execute(
+        "check.js",
+        "if (outcome !== 'timed out') throw Error(outcome);",
+      ));
+    })
+  }
+
+  #[test]
+  fn dyn_import_invalid_specifier() {
+    run_in_task(|cx| {
+      let loader = Rc::new(DynImportOkLoader::default());
+      let resolve_count = loader.resolve_count.clone();
+      let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+
+      js_check(isolate.execute(
+        "file:///dyn_import_invalid.js",
+        r#"
+        let rejection;
+        // A lone surrogate can't be converted to UTF-8.
+        import("./\uD800.js").catch((e) => {
+          rejection = e;
+        });
+        "#,
+      ));
+
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      // The loader is never consulted.
+      assert_eq!(resolve_count.load(Ordering::Relaxed), 0);
+      js_check(isolate.execute(
+        "file:///dyn_import_invalid_check.js",
+        r#"
+        if (!(rejection instanceof TypeError)) {
+          throw Error("expected a TypeError");
+        }
+        if (!rejection.message.includes("not valid UTF-16")) {
+          throw Error(rejection.message);
+        }
+        "#,
+      ));
+    })
+  }
+
+  #[test]
+  fn dyn_import_policy() {
+    run_in_task(|cx| {
+      let loader = Rc::new(DynImportOkLoader::default());
+      let resolve_count = loader.resolve_count.clone();
+      let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+      isolate.set_dyn_import_policy(|specifier, referrer| {
+        assert_eq!(referrer, "file:///dyn_import_policy.js");
+        if specifier.starts_with("https://") {
+          Err(format!("Remote import of {} denied", specifier))
+        } else {
+          Ok(())
+        }
+      });
+
+      js_check(isolate.execute(
+        "file:///dyn_import_policy.js",
+        r#"
+        let rejection;
+        import("https://example.com/mod.js").catch((e) => {
+          rejection = e;
+        });
+        "#,
+      ));
+
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert_eq!(resolve_count.load(Ordering::Relaxed), 0);
+      js_check(isolate.execute(
+        "file:///dyn_import_policy_check.js",
+        r#"
+        if (!(rejection instanceof TypeError)) {
+          throw Error("expected a TypeError");
+        }
+        if (rejection.message !== "Remote import of https://example.com/mod.js denied") {
+          throw Error(rejection.message);
+        }
+        "#,
+      ));
+    })
+  }
+
+  #[test]
+  fn dyn_import_disabled() {
+    run_in_task(|cx| {
+      let loader = Rc::new(DynImportOkLoader::default());
+      let resolve_count = loader.resolve_count.clone();
+      let mut isolate = EsIsolate::new(loader, StartupData::None, false);
+      isolate.set_allow_dyn_import(false);
+
+      js_check(isolate.execute(
+        "file:///dyn_import_disabled.js",
+        r#"
+        let rejection;
+        import("./b.js").catch((e) => {
+          rejection = e;
+        });
+        "#,
+      ));
+
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+      assert_eq!(resolve_count.load(Ordering::Relaxed), 0);
+      js_check(isolate.execute(
+        "file:///dyn_import_disabled_check.js",
+        r#"
+        if (!(rejection instanceof TypeError)) {
+          throw Error("expected a TypeError");
+        }
+        if (!rejection.message.includes("dynamic import is disabled")) {
+          throw Error(rejection.message);
+        }
+        "#,
+      ));
+    })
+  }
+
   #[derive(Clone, Default)]
   struct DynImportOkLoader {
     pub prepare_load_count: Arc<AtomicUsize>,
@@ -862,6 +1674,92 @@ pub mod tests {
     }
   }
 
+  #[test]
+  fn test_mod_evaluate_async() {
+    struct TlaLoader;
+
+    impl ModuleLoader for TlaLoader {
+      fn resolve(
+        &self,
+        specifier: &str,
+        referrer: &str,
+        _is_main: bool,
+      ) -> Result<ModuleSpecifier, ErrBox> {
+        let s = ModuleSpecifier::resolve_import(specifier, referrer).unwrap();
+        Ok(s)
+      }
+
+      fn load(
+        &self,
+        specifier: &ModuleSpecifier,
+        _maybe_referrer: Option<ModuleSpecifier>,
+        _is_dyn_import: bool,
+      ) -> Pin<Box<ModuleSourceFuture>> {
+        let info = ModuleSource {
+          module_url_specified: specifier.to_string(),
+          module_url_found: specifier.to_string(),
+          code: "export const b = 'b';".to_owned(),
+        };
+        async move { Ok(info) }.boxed()
+      }
+    }
+
+    let mut isolate =
+      EsIsolate::new(Rc::new(TlaLoader), StartupData::None, false);
+
+    // The dynamic import only settles once the event loop runs.
+    let mod_ok = isolate
+      .mod_new(
+        true,
+        "file:///tla_ok.js",
+        r#"
+        const { b } = await import("./b.js");
+        globalThis.imported = b;
+        "#,
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_ok));
+    js_check(futures::executor::block_on(
+      isolate.mod_evaluate_async(mod_ok),
+    ));
+    js_check(
+      isolate
+        .execute("check.js", "if (imported !== 'b') throw Error(imported);"),
+    );
+
+    let mod_err = isolate
+      .mod_new(
+        false,
+        "file:///tla_err.js",
+        r#"
+        await import("./b.js");
+        throw new Error("after await");
+        "#,
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_err));
+    let err = futures::executor::block_on(isolate.mod_evaluate_async(mod_err))
+      .expect_err("should reject");
+    assert!(err.to_string().starts_with("Uncaught Error: after await"));
+    // The rejection is not reported again as an unhandled one.
+    run_in_task(|cx| {
+      assert!(matches!(isolate.poll_unpin(cx), Poll::Ready(Ok(_))));
+    });
+
+    let mod_stuck = isolate
+      .mod_new(
+        false,
+        "file:///tla_stuck.js",
+        "await new Promise(() => {});",
+      )
+      .unwrap();
+    js_check(isolate.mod_instantiate(mod_stuck));
+    assert!(
+      futures::executor::block_on(isolate.mod_evaluate_async(mod_stuck))
+        .is_err()
+    );
+  }
+
   #[test]
   fn dyn_import_ok() {
     run_in_task(|cx| {
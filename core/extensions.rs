@@ -0,0 +1,90 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+// Extensions let embedders add their own native functions and JavaScript to
+// every isolate they create, next to the built-in `Deno.core` bindings,
+// without having to fork bindings.rs.
+
+use rusty_v8 as v8;
+use v8::MapFnTo;
+
+type NewTemplateFn = dyn for<'s> Fn(
+  &mut v8::HandleScope<'s>,
+) -> v8::Local<'s, v8::FunctionTemplate>;
+
+struct ExtensionFunction {
+  name: &'static str,
+  callback: v8::FunctionCallback,
+  new_template: Box<NewTemplateFn>,
+}
+
+/// A bundle of native functions and JavaScript source files, passed to
+/// `CoreIsolate::with_extensions()` or `EsIsolate::with_extensions()`.
+///
+/// The functions are installed on `Deno.core` of every context the isolate
+/// creates, and the JavaScript files are executed once, right after core.js
+/// and before the startup script. This makes the files a runtime layer that
+/// always runs before user code and, when snapshotting, before the snapshot
+/// is taken. An isolate restored from a snapshot does not execute them
+/// again, since their effects are part of the snapshot.
+///
+/// Snapshots record the functions by their position in the isolate's list of
+/// external references, so an isolate restored from a snapshot must be given
+/// the same extensions, in the same order, as the one that took it.
+#[derive(Default)]
+pub struct Extension {
+  functions: Vec<ExtensionFunction>,
+  js_files: Vec<(&'static str, &'static str)>,
+}
+
+impl Extension {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Installs `callback` as `Deno.core[name]`.
+  pub fn function<F>(mut self, name: &'static str, callback: F) -> Self
+  where
+    F: MapFnTo<v8::FunctionCallback> + Copy + 'static,
+  {
+    self.functions.push(ExtensionFunction {
+      name,
+      callback: callback.map_fn_to(),
+      new_template: Box::new(move |scope| {
+        v8::FunctionTemplate::new(scope, callback)
+      }),
+    });
+    self
+  }
+
+  /// Executes `source` as a classic script named `filename` when the isolate
+  /// is initialized.
+  pub fn js(mut self, filename: &'static str, source: &'static str) -> Self {
+    self.js_files.push((filename, source));
+    self
+  }
+
+  pub(crate) fn js_files(&self) -> &[(&'static str, &'static str)] {
+    &self.js_files
+  }
+
+  pub(crate) fn external_references(
+    &self,
+  ) -> impl Iterator<Item = v8::ExternalReference<'static>> + '_ {
+    self.functions.iter().map(|f| v8::ExternalReference {
+      function: f.callback,
+    })
+  }
+
+  pub(crate) fn install<'s>(
+    &self,
+    scope: &mut v8::HandleScope<'s>,
+    target: v8::Local<'s, v8::Object>,
+  ) {
+    for f in &self.functions {
+      let key = v8::String::new(scope, f.name).unwrap();
+      let tmpl = (f.new_template)(scope);
+      let val = tmpl.get_function(scope).unwrap();
+      target.set(scope, key.into(), val.into());
+    }
+  }
+}
@@ -1,7 +1,57 @@
 // Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
 use rusty_v8 as v8;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// Whether `--expose-gc` was passed to V8 through `v8_set_flags()`.
+static EXPOSE_GC: AtomicBool = AtomicBool::new(false);
+
 /// Pass the command line arguments to v8.
 /// Returns a vector of command line arguments that V8 did not understand.
 pub fn v8_set_flags(args: Vec<String>) -> Vec<String> {
+  // The first argument is the program name, which V8 skips as well.
+  for arg in args.iter().skip(1) {
+    if let Some(enabled) = parse_expose_gc(arg) {
+      EXPOSE_GC.store(enabled, Ordering::SeqCst);
+    }
+  }
   v8::V8::set_flags_from_command_line(args)
 }
+
+/// Returns true if V8 was told to allow garbage collection requests for
+/// testing, which abort the process otherwise.
+pub(crate) fn is_gc_exposed() -> bool {
+  EXPOSE_GC.load(Ordering::SeqCst)
+}
+
+fn parse_expose_gc(arg: &str) -> Option<bool> {
+  if !arg.starts_with('-') {
+    return None;
+  }
+  // V8 accepts one or two leading dashes and treats '_' and '-' alike.
+  let flag = arg.trim_start_matches('-').replace('_', "-");
+  match flag.as_str() {
+    "expose-gc" => Some(true),
+    "no-expose-gc" | "noexpose-gc" => Some(false),
+    // --expose-gc-as implies --expose-gc.
+    _ if flag.starts_with("expose-gc-as=") => Some(true),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_expose_gc() {
+    assert_eq!(parse_expose_gc("--expose-gc"), Some(true));
+    assert_eq!(parse_expose_gc("--expose_gc"), Some(true));
+    assert_eq!(parse_expose_gc("-expose-gc"), Some(true));
+    assert_eq!(parse_expose_gc("--expose-gc-as=collect"), Some(true));
+    assert_eq!(parse_expose_gc("--no-expose-gc"), Some(false));
+    assert_eq!(parse_expose_gc("--noexpose_gc"), Some(false));
+    assert_eq!(parse_expose_gc("expose-gc"), None);
+    assert_eq!(parse_expose_gc("--gc-stats=1"), None);
+  }
+}
@@ -0,0 +1,241 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+// Host objects are plain JS objects created from an ObjectTemplate with
+// internal fields. Internal fields are invisible to JavaScript, which makes
+// them the place to keep a pointer to the native resource an object wraps.
+//
+// Host data is stored as a `v8::External` wrapping a pointer to it, and the
+// pointer is also recorded in the isolate state. A field is only treated as
+// holding host data if it holds an `External` whose pointer is recorded, so
+// neither an unset field, a JS value, nor a foreign `External` is ever
+// dereferenced. A field holds either host data or a JS value, never both:
+// `get_internal_field()` and `set_internal_field()` refuse fields holding
+// host data.
+//
+// Lifetime contract: data stored with `set_host_data()` is owned by the
+// object's internal field until it is taken back with `take_host_data()`.
+// There are no finalizers yet, so if the object is garbage collected first,
+// the data is leaked rather than dropped. The data must not be borrowed
+// across calls into JavaScript, which could replace or take it.
+
+use crate::CoreIsolate;
+use rusty_v8 as v8;
+use std::any::Any;
+use std::convert::TryFrom;
+use std::ffi::c_void;
+
+type HostData = Box<dyn Any>;
+
+/// Creates a new object with `field_count` internal fields, all initialized
+/// to `undefined`.
+pub fn new_host_object<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  field_count: usize,
+) -> v8::Local<'s, v8::Object> {
+  let template = v8::ObjectTemplate::new(scope);
+  template.set_internal_field_count(field_count);
+  template
+    .new_instance(scope)
+    .expect("Failed to create host object")
+}
+
+/// Returns the JS value stored in internal field `index`, or `None` if the
+/// object has no such field or the field holds host data.
+pub fn get_internal_field<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  object: v8::Local<v8::Object>,
+  index: usize,
+) -> Option<v8::Local<'s, v8::Value>> {
+  if get_raw(scope, object, index).is_some() {
+    return None;
+  }
+  get_field(scope, object, index)
+}
+
+/// Stores a JS value in internal field `index`. Returns false if the object
+/// has no such field or the field holds host data, which must be taken with
+/// `take_host_data()` first.
+pub fn set_internal_field(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  index: usize,
+  value: v8::Local<v8::Value>,
+) -> bool {
+  if index >= object.internal_field_count()
+    || get_raw(scope, object, index).is_some()
+  {
+    return false;
+  }
+  object.set_internal_field(index, value)
+}
+
+/// Moves `data` into internal field `index`, replacing the JS value stored
+/// there. Any data previously stored in that field is returned to the
+/// caller.
+///
+/// Panics if the object has no such field.
+pub fn set_host_data<T: Any>(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  index: usize,
+  data: T,
+) -> Option<Box<dyn Any>> {
+  assert!(index < object.internal_field_count());
+  let prev = take_raw(scope, object, index);
+  let data: Box<HostData> = Box::new(Box::new(data));
+  let ptr = Box::into_raw(data);
+  CoreIsolate::state(scope)
+    .borrow_mut()
+    .host_data
+    .insert(ptr as usize);
+  let external = v8::External::new(scope, ptr as *mut c_void);
+  object.set_internal_field(index, external.into());
+  prev.map(|data| *data)
+}
+
+/// Calls `f` with a reference to the data of type `T` stored in internal
+/// field `index`. Returns `None` if the field holds no data or data of a
+/// different type.
+pub fn with_host_data<T: Any, R>(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  index: usize,
+  f: impl FnOnce(&mut T) -> R,
+) -> Option<R> {
+  let ptr = get_raw(scope, object, index)?;
+  // Safety: `get_raw()` only returns pointers created by `set_host_data()`
+  // that have not been taken back, and `f` cannot reach the isolate.
+  let data = unsafe { &mut *ptr };
+  data.downcast_mut::<T>().map(f)
+}
+
+/// Removes the data of type `T` from internal field `index` and returns it.
+/// The field is left untouched if it holds data of a different type.
+pub fn take_host_data<T: Any>(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  index: usize,
+) -> Option<Box<T>> {
+  let ptr = get_raw(scope, object, index)?;
+  if !unsafe { &*ptr }.is::<T>() {
+    return None;
+  }
+  let data = take_raw(scope, object, index).unwrap();
+  Some((*data).downcast::<T>().unwrap())
+}
+
+fn get_field<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  object: v8::Local<v8::Object>,
+  index: usize,
+) -> Option<v8::Local<'s, v8::Value>> {
+  if index >= object.internal_field_count() {
+    return None;
+  }
+  object.get_internal_field(scope, index)
+}
+
+/// Returns the pointer to the host data stored in internal field `index`, if
+/// the field holds an `External` created by `set_host_data()` whose data has
+/// not been taken yet.
+fn get_raw(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  index: usize,
+) -> Option<*mut HostData> {
+  let value = get_field(scope, object, index)?;
+  let external = v8::Local::<v8::External>::try_from(value).ok()?;
+  let ptr = external.value() as *mut HostData;
+  let state_rc = CoreIsolate::state(scope);
+  let is_host_data = state_rc.borrow().host_data.contains(&(ptr as usize));
+  if is_host_data {
+    Some(ptr)
+  } else {
+    None
+  }
+}
+
+fn take_raw(
+  scope: &mut v8::HandleScope,
+  object: v8::Local<v8::Object>,
+  index: usize,
+) -> Option<Box<HostData>> {
+  let ptr = get_raw(scope, object, index)?;
+  CoreIsolate::state(scope)
+    .borrow_mut()
+    .host_data
+    .remove(&(ptr as usize));
+  let undefined = v8::undefined(scope);
+  object.set_internal_field(index, undefined.into());
+  // Safety: the pointer was created by `Box::into_raw()` in
+  // `set_host_data()` and has just been unregistered, so it is only
+  // reclaimed once.
+  Some(unsafe { Box::from_raw(ptr) })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::StartupData;
+
+  struct Resource {
+    value: u32,
+  }
+
+  #[test]
+  fn host_data_round_trip() {
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let state_rc = CoreIsolate::state(&isolate);
+    let scope = &mut v8::HandleScope::with_context(
+      &mut *isolate,
+      state_rc.borrow().global_context.as_ref().unwrap(),
+    );
+
+    let object = new_host_object(scope, 2);
+    assert_eq!(object.internal_field_count(), 2);
+
+    assert!(set_host_data(scope, object, 0, Resource { value: 42 }).is_none());
+    assert_eq!(
+      with_host_data(scope, object, 0, |r: &mut Resource| r.value),
+      Some(42)
+    );
+    with_host_data(scope, object, 0, |r: &mut Resource| r.value += 1);
+
+    // Wrong type or empty field.
+    assert!(with_host_data(scope, object, 0, |_: &mut String| ()).is_none());
+    assert!(take_host_data::<String>(scope, object, 0).is_none());
+    assert!(with_host_data(scope, object, 1, |_: &mut Resource| ()).is_none());
+
+    // A field holding host data is not exposed or overwritten as a JS value.
+    let value = v8::Integer::new(scope, 7);
+    assert!(get_internal_field(scope, object, 0).is_none());
+    assert!(!set_internal_field(scope, object, 0, value.into()));
+
+    let resource = take_host_data::<Resource>(scope, object, 0).unwrap();
+    assert_eq!(resource.value, 43);
+    assert!(take_host_data::<Resource>(scope, object, 0).is_none());
+
+    assert!(set_internal_field(scope, object, 1, value.into()));
+    let field = get_internal_field(scope, object, 1).unwrap();
+    assert!(field.strict_equals(value.into()));
+    assert!(get_internal_field(scope, object, 2).is_none());
+  }
+
+  #[test]
+  fn foreign_external_is_not_host_data() {
+    let mut isolate = CoreIsolate::new(StartupData::None, false);
+    let state_rc = CoreIsolate::state(&isolate);
+    let scope = &mut v8::HandleScope::with_context(
+      &mut *isolate,
+      state_rc.borrow().global_context.as_ref().unwrap(),
+    );
+
+    let object = new_host_object(scope, 1);
+    let mut other = 0u64;
+    let external =
+      v8::External::new(scope, &mut other as *mut u64 as *mut c_void);
+    assert!(set_internal_field(scope, object, 0, external.into()));
+    assert!(with_host_data(scope, object, 0, |_: &mut u64| ()).is_none());
+    assert!(take_host_data::<u64>(scope, object, 0).is_none());
+  }
+}
@@ -0,0 +1,138 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+// The envelope shared by JSON ops: arguments are a JSON object in the first
+// buffer, async calls carry a `promiseId` in it, and results come back as
+// `{ ok, promiseId }` or `{ err: { className, message }, promiseId }`.
+// `Deno.core.jsonOpSync()` and `Deno.core.jsonOpAsync()` in core.js speak
+// this protocol.
+
+use crate::CoreIsolateState;
+use crate::ErrBox;
+use crate::GetErrorClassFn;
+use crate::Op;
+use crate::ZeroCopyBuf;
+use futures::future::FutureExt;
+use serde_json::json;
+use serde_json::Value;
+use std::future::Future;
+use std::pin::Pin;
+
+pub type JsonResult = Result<Value, ErrBox>;
+
+pub type AsyncJsonOp = Pin<Box<dyn Future<Output = JsonResult>>>;
+
+pub enum JsonOp {
+  Sync(Value),
+  Async(AsyncJsonOp),
+  /// AsyncUnref is the variation of Async, which doesn't block the program
+  /// exiting.
+  AsyncUnref(AsyncJsonOp),
+}
+
+pub fn serialize_result(
+  promise_id: Option<u64>,
+  result: JsonResult,
+  get_error_class_fn: GetErrorClassFn,
+) -> Box<[u8]> {
+  let value = match result {
+    Ok(v) => json!({ "ok": v, "promiseId": promise_id }),
+    Err(err) => json!({
+      "err": {
+        "className": (get_error_class_fn)(&err),
+        "message": err.to_string()
+      },
+      "promiseId": promise_id
+    }),
+  };
+  serde_json::to_vec(&value).unwrap().into_boxed_slice()
+}
+
+/// Serializes an error for an op called in a way it cannot handle. Its class
+/// is always `TypeError`, whatever the isolate's `get_error_class_fn` says.
+fn serialize_type_error(promise_id: Option<u64>, message: &str) -> Box<[u8]> {
+  let value = json!({
+    "err": {
+      "className": "TypeError",
+      "message": message
+    },
+    "promiseId": promise_id
+  });
+  serde_json::to_vec(&value).unwrap().into_boxed_slice()
+}
+
+/// Adapts a function taking parsed JSON arguments into an op that can be
+/// passed to `CoreIsolate::register_op()`.
+///
+/// Arguments that are not valid JSON, and errors returned by `d`, are
+/// reported to JavaScript using the isolate's `get_error_class_fn` rather
+/// than panicking. A missing argument buffer, or a sync result for an async
+/// call and vice versa, is reported as a `TypeError`.
+pub fn json_op<D>(
+  d: D,
+) -> impl Fn(&mut CoreIsolateState, &mut [ZeroCopyBuf]) -> Op
+where
+  D: Fn(
+    &mut CoreIsolateState,
+    Value,
+    &mut [ZeroCopyBuf],
+  ) -> Result<JsonOp, ErrBox>,
+{
+  move |isolate_state: &mut CoreIsolateState, zero_copy: &mut [ZeroCopyBuf]| {
+    let get_error_class_fn = isolate_state.get_error_class_fn;
+
+    if zero_copy.is_empty() {
+      let buf =
+        serialize_type_error(None, "Expected JSON string at position 0");
+      return Op::Sync(buf);
+    }
+    let args: Value = match serde_json::from_slice(&zero_copy[0]) {
+      Ok(args) => args,
+      Err(e) => {
+        let buf = serialize_result(None, Err(e.into()), get_error_class_fn);
+        return Op::Sync(buf);
+      }
+    };
+    let promise_id = args.get("promiseId").and_then(Value::as_u64);
+    let is_sync = promise_id.is_none();
+
+    let result = d(isolate_state, args, &mut zero_copy[1..]);
+
+    // Convert to Op
+    match result {
+      Ok(JsonOp::Sync(_)) if !is_sync => {
+        let buf =
+          serialize_type_error(promise_id, "Sync op called with a promiseId");
+        Op::Async(futures::future::ready(buf).boxed_local())
+      }
+      Ok(JsonOp::Async(_)) | Ok(JsonOp::AsyncUnref(_)) if is_sync => Op::Sync(
+        serialize_type_error(None, "Async op called without a promiseId"),
+      ),
+      Ok(JsonOp::Sync(sync_value)) => Op::Sync(serialize_result(
+        promise_id,
+        Ok(sync_value),
+        get_error_class_fn,
+      )),
+      Ok(JsonOp::Async(fut)) => {
+        let fut2 = fut.map(move |result| {
+          serialize_result(promise_id, result, get_error_class_fn)
+        });
+        Op::Async(fut2.boxed_local())
+      }
+      Ok(JsonOp::AsyncUnref(fut)) => {
+        let fut2 = fut.map(move |result| {
+          serialize_result(promise_id, result, get_error_class_fn)
+        });
+        Op::AsyncUnref(fut2.boxed_local())
+      }
+      Err(sync_err) => {
+        let buf =
+          serialize_result(promise_id, Err(sync_err), get_error_class_fn);
+        if is_sync {
+          Op::Sync(buf)
+        } else {
+          Op::Async(futures::future::ready(buf).boxed_local())
+        }
+      }
+    }
+  }
+}
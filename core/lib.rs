@@ -12,12 +12,18 @@ mod bindings;
 mod core_isolate;
 mod errors;
 mod es_isolate;
+mod extensions;
 mod flags;
+mod host_object;
+mod json_op;
+mod metrics;
 mod module_specifier;
 mod modules;
 mod normalize_path;
+mod object_graph;
 mod ops;
 pub mod plugin_api;
+mod print;
 mod resources;
 mod shared_queue;
 mod zero_copy_buf;
@@ -25,21 +31,45 @@ mod zero_copy_buf;
 pub use rusty_v8 as v8;
 
 pub use crate::core_isolate::js_check;
+pub use crate::core_isolate::ChildContext;
 pub use crate::core_isolate::CoreIsolate;
 pub use crate::core_isolate::CoreIsolateState;
 pub use crate::core_isolate::GetErrorClassFn;
 pub use crate::core_isolate::HeapLimits;
+pub use crate::core_isolate::HeapStats;
+pub use crate::core_isolate::IsolateOptions;
+pub use crate::core_isolate::RealmId;
 pub use crate::core_isolate::Script;
+pub use crate::core_isolate::ScriptOriginOptions;
 pub use crate::core_isolate::Snapshot;
 pub use crate::core_isolate::StartupData;
+pub use crate::errors::get_error_class;
 pub use crate::errors::ErrBox;
+pub use crate::errors::ExecutionTerminated;
 pub use crate::errors::JSError;
+pub use crate::errors::SourceLineMode;
+pub use crate::errors::SourceMapFn;
 pub use crate::es_isolate::EsIsolate;
 pub use crate::es_isolate::EsIsolateState;
+pub use crate::extensions::Extension;
 pub use crate::flags::v8_set_flags;
+pub use crate::host_object::get_internal_field;
+pub use crate::host_object::new_host_object;
+pub use crate::host_object::set_host_data;
+pub use crate::host_object::set_internal_field;
+pub use crate::host_object::take_host_data;
+pub use crate::host_object::with_host_data;
+pub use crate::json_op::json_op;
+pub use crate::json_op::serialize_result;
+pub use crate::json_op::AsyncJsonOp;
+pub use crate::json_op::JsonOp;
+pub use crate::json_op::JsonResult;
+pub use crate::metrics::LatencyPercentiles;
+pub use crate::metrics::OpCounters;
 pub use crate::module_specifier::ModuleResolutionError;
 pub use crate::module_specifier::ModuleSpecifier;
 pub use crate::modules::Deps;
+pub use crate::modules::ModuleGraphEntry;
 pub use crate::modules::ModuleId;
 pub use crate::modules::ModuleLoadId;
 pub use crate::modules::ModuleLoader;
@@ -50,8 +80,12 @@ pub use crate::normalize_path::normalize_path;
 pub use crate::ops::Buf;
 pub use crate::ops::Op;
 pub use crate::ops::OpAsyncFuture;
+pub use crate::ops::OpDecision;
 pub use crate::ops::OpId;
+pub use crate::ops::OpMetadata;
+pub use crate::print::PrintEncoding;
 pub use crate::resources::ResourceTable;
+pub use crate::shared_queue::SharedQueueStats;
 pub use crate::zero_copy_buf::BufVec;
 pub use crate::zero_copy_buf::ZeroCopyBuf;
 pub use serde_json;
@@ -0,0 +1,280 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+// Per-op metrics collected by CoreIsolate when enabled with
+// `CoreIsolate::set_op_metrics_enabled()`. Nothing here is touched while
+// metrics are disabled, so dispatching ops stays zero-cost by default.
+
+use crate::ops::OpId;
+use crate::ops::OpRegistry;
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Values below this are recorded exactly, one bucket per nanosecond.
+const LINEAR_BUCKETS: u64 = 16;
+/// Every power of two above `LINEAR_BUCKETS` is split into this many
+/// sub-buckets, bounding the relative error of a percentile to 1/16.
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+const BUCKET_COUNT: usize =
+  (LINEAR_BUCKETS + (64 - SUB_BUCKET_BITS as u64) * SUB_BUCKETS) as usize;
+
+/// A log-linear histogram of durations in nanoseconds, similar in spirit to
+/// HdrHistogram but with a fixed precision and range.
+pub struct LatencyHistogram {
+  buckets: Box<[u64]>,
+  count: u64,
+  max: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LatencyPercentiles {
+  pub p50: u64,
+  pub p90: u64,
+  pub p99: u64,
+  pub max: u64,
+}
+
+impl Default for LatencyHistogram {
+  fn default() -> Self {
+    Self {
+      buckets: vec![0; BUCKET_COUNT].into_boxed_slice(),
+      count: 0,
+      max: 0,
+    }
+  }
+}
+
+impl LatencyHistogram {
+  pub fn record(&mut self, nanos: u64) {
+    self.buckets[bucket_index(nanos)] += 1;
+    self.count += 1;
+    self.max = self.max.max(nanos);
+  }
+
+  pub fn count(&self) -> u64 {
+    self.count
+  }
+
+  /// Returns the upper bound of the bucket holding the value at quantile `q`
+  /// (between 0 and 1), clamped to the largest recorded value.
+  pub fn value_at_quantile(&self, q: f64) -> u64 {
+    if self.count == 0 {
+      return 0;
+    }
+    let rank = ((q * self.count as f64).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (index, count) in self.buckets.iter().enumerate() {
+      seen += count;
+      if seen >= rank {
+        return bucket_upper_bound(index).min(self.max);
+      }
+    }
+    self.max
+  }
+
+  pub fn percentiles(&self) -> LatencyPercentiles {
+    LatencyPercentiles {
+      p50: self.value_at_quantile(0.5),
+      p90: self.value_at_quantile(0.9),
+      p99: self.value_at_quantile(0.99),
+      max: self.max,
+    }
+  }
+}
+
+fn bucket_index(nanos: u64) -> usize {
+  if nanos < LINEAR_BUCKETS {
+    return nanos as usize;
+  }
+  let exponent = 63 - nanos.leading_zeros();
+  let sub_bucket = (nanos >> (exponent - SUB_BUCKET_BITS)) & (SUB_BUCKETS - 1);
+  let octave = (exponent - SUB_BUCKET_BITS) as u64;
+  (LINEAR_BUCKETS + octave * SUB_BUCKETS + sub_bucket) as usize
+}
+
+fn bucket_upper_bound(index: usize) -> u64 {
+  let index = index as u64;
+  if index < LINEAR_BUCKETS {
+    return index;
+  }
+  let octave = (index - LINEAR_BUCKETS) / SUB_BUCKETS;
+  let sub_bucket = (index - LINEAR_BUCKETS) % SUB_BUCKETS;
+  let lower = (SUB_BUCKETS + sub_bucket) << octave;
+  lower.saturating_add((1 << octave) - 1)
+}
+
+/// Counters kept for every op while metrics are enabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct OpCounters {
+  pub dispatched: u64,
+  pub completed_sync: u64,
+  pub completed_async: u64,
+  /// Total length of the zero-copy buffers passed to the op.
+  pub bytes_received: u64,
+  /// Total length of the op's responses.
+  pub bytes_sent: u64,
+  /// Time spent in the op's dispatcher. For async ops the time until their
+  /// future resolves is not included.
+  pub total_time: Duration,
+}
+
+#[derive(Default)]
+struct PerOpMetrics {
+  counters: OpCounters,
+  latency: LatencyHistogram,
+}
+
+#[derive(Default)]
+pub struct OpMetrics {
+  ops: HashMap<OpId, PerOpMetrics>,
+}
+
+impl OpMetrics {
+  pub fn record_dispatch(
+    &mut self,
+    op_id: OpId,
+    elapsed: Duration,
+    bytes_received: usize,
+  ) {
+    let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+    let op = self.ops.entry(op_id).or_default();
+    op.latency.record(nanos);
+    op.counters.dispatched += 1;
+    op.counters.bytes_received += bytes_received as u64;
+    op.counters.total_time += elapsed;
+  }
+
+  pub fn record_completion(
+    &mut self,
+    op_id: OpId,
+    is_async: bool,
+    bytes_sent: usize,
+  ) {
+    let counters = &mut self.ops.entry(op_id).or_default().counters;
+    if is_async {
+      counters.completed_async += 1;
+    } else {
+      counters.completed_sync += 1;
+    }
+    counters.bytes_sent += bytes_sent as u64;
+  }
+
+  /// Returns `None` if the op has not been dispatched since metrics were
+  /// enabled.
+  pub fn latency(&self, op_id: OpId) -> Option<LatencyPercentiles> {
+    self.ops.get(&op_id).map(|op| op.latency.percentiles())
+  }
+
+  pub fn counters(&self, op_id: OpId) -> Option<OpCounters> {
+    self.ops.get(&op_id).map(|op| op.counters)
+  }
+
+  /// Returns the counters of every op dispatched at least once, keyed by op
+  /// name.
+  pub fn counters_by_name(
+    &self,
+    op_registry: &OpRegistry,
+  ) -> HashMap<String, OpCounters> {
+    self
+      .ops
+      .iter()
+      .map(|(op_id, op)| (op_name(op_registry, *op_id), op.counters))
+      .collect()
+  }
+
+  /// Returns the metrics collected so far and resets them. Since the isolate
+  /// is single threaded no dispatch can be recorded in between, so every
+  /// dispatch is reported by exactly one drain.
+  pub fn drain(&mut self) -> Self {
+    std::mem::take(self)
+  }
+
+  /// Returns the metrics of every op dispatched at least once, keyed by op
+  /// name.
+  pub fn to_json(&self, op_registry: &OpRegistry) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (op_id, op) in &self.ops {
+      let latency = op.latency.percentiles();
+      let counters = &op.counters;
+      map.insert(
+        op_name(op_registry, *op_id),
+        json!({
+          "id": op_id,
+          "dispatched": counters.dispatched,
+          "completedSync": counters.completed_sync,
+          "completedAsync": counters.completed_async,
+          "bytesReceived": counters.bytes_received,
+          "bytesSent": counters.bytes_sent,
+          "totalTime": counters.total_time.as_nanos() as u64,
+          "p50": latency.p50,
+          "p90": latency.p90,
+          "p99": latency.p99,
+          "max": latency.max,
+        }),
+      );
+    }
+    serde_json::Value::Object(map)
+  }
+}
+
+fn op_name(op_registry: &OpRegistry, op_id: OpId) -> String {
+  op_registry
+    .name(op_id)
+    .map_or_else(|| op_id.to_string(), str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bucket_bounds_contain_value() {
+    for &nanos in &[0, 1, 15, 16, 17, 31, 32, 1000, 123_456_789, u64::MAX] {
+      let index = bucket_index(nanos);
+      assert!(index < BUCKET_COUNT);
+      let upper = bucket_upper_bound(index);
+      assert!(upper >= nanos);
+      // Precision is bounded to one sub-bucket.
+      assert!(upper - nanos <= nanos / SUB_BUCKETS);
+    }
+  }
+
+  #[test]
+  fn percentiles() {
+    let mut histogram = LatencyHistogram::default();
+    assert_eq!(histogram.percentiles(), LatencyPercentiles::default());
+    for nanos in 1..=1000 {
+      histogram.record(nanos);
+    }
+    assert_eq!(histogram.count(), 1000);
+    let p = histogram.percentiles();
+    assert!(p.p50 >= 500 && p.p50 <= 500 + 500 / SUB_BUCKETS);
+    assert!(p.p90 >= 900 && p.p90 <= 900 + 900 / SUB_BUCKETS);
+    assert!(p.p99 >= 990 && p.p99 <= 1000);
+    assert_eq!(p.max, 1000);
+  }
+
+  #[test]
+  fn counters() {
+    let mut metrics = OpMetrics::default();
+    assert_eq!(metrics.counters(1), None);
+    metrics.record_dispatch(1, Duration::from_micros(3), 10);
+    metrics.record_completion(1, false, 4);
+    metrics.record_dispatch(1, Duration::from_micros(5), 0);
+    metrics.record_completion(1, true, 6);
+    assert_eq!(
+      metrics.counters(1),
+      Some(OpCounters {
+        dispatched: 2,
+        completed_sync: 1,
+        completed_async: 1,
+        bytes_received: 10,
+        bytes_sent: 10,
+        total_time: Duration::from_micros(8),
+      })
+    );
+    assert_eq!(metrics.drain().counters(1).unwrap().dispatched, 2);
+    assert_eq!(metrics.counters(1), None);
+  }
+}
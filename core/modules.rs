@@ -8,10 +8,13 @@ use futures::future::FutureExt;
 use futures::stream::FuturesUnordered;
 use futures::stream::Stream;
 use futures::stream::TryStreamExt;
+use serde_json::json;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
+use std::num::NonZeroUsize;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::sync::atomic::AtomicI32;
@@ -124,6 +127,11 @@ pub struct RecursiveModuleLoad {
   pub loader: Rc<dyn ModuleLoader>,
   pub pending: FuturesUnordered<Pin<Box<ModuleSourceFuture>>>,
   pub is_pending: HashSet<ModuleSpecifier>,
+  /// Imports discovered while `max_concurrent_loads` fetches were already in
+  /// flight, as (specifier, referrer) pairs. They are started in order as
+  /// earlier fetches complete.
+  queued: VecDeque<(ModuleSpecifier, ModuleSpecifier)>,
+  max_concurrent_loads: Option<NonZeroUsize>,
 }
 
 impl RecursiveModuleLoad {
@@ -162,9 +170,21 @@ impl RecursiveModuleLoad {
       loader,
       pending: FuturesUnordered::new(),
       is_pending: HashSet::new(),
+      queued: VecDeque::new(),
+      max_concurrent_loads: None,
     }
   }
 
+  /// Limits the number of module fetches in flight at once. All imports are
+  /// fetched concurrently by default.
+  pub fn with_max_concurrent_loads(
+    mut self,
+    limit: Option<NonZeroUsize>,
+  ) -> Self {
+    self.max_concurrent_loads = limit;
+    self
+  }
+
   pub async fn prepare(self) -> (ModuleLoadId, Result<Self, ErrBox>) {
     let (module_specifier, maybe_referrer) = match self.state {
       LoadState::ResolveMain(ref specifier, _) => {
@@ -239,12 +259,26 @@ impl RecursiveModuleLoad {
     referrer: ModuleSpecifier,
   ) {
     if !self.is_pending.contains(&specifier) {
+      self.is_pending.insert(specifier.clone());
+      self.queued.push_back((specifier, referrer));
+      self.start_queued();
+    }
+  }
+
+  fn start_queued(&mut self) {
+    while self
+      .max_concurrent_loads
+      .map_or(true, |limit| self.pending.len() < limit.get())
+    {
+      let (specifier, referrer) = match self.queued.pop_front() {
+        Some(import) => import,
+        None => break,
+      };
       let fut =
         self
           .loader
           .load(&specifier, Some(referrer), self.is_dynamic_import());
       self.pending.push(fut.boxed_local());
-      self.is_pending.insert(specifier);
     }
   }
 }
@@ -265,9 +299,13 @@ impl Stream for RecursiveModuleLoad {
         inner.try_poll_next_unpin(cx)
       }
       LoadState::LoadingRoot | LoadState::LoadingImports => {
-        match inner.pending.try_poll_next_unpin(cx)? {
+        match inner.pending.try_poll_next_unpin(cx) {
           Poll::Ready(None) => unreachable!(),
-          Poll::Ready(Some(info)) => Poll::Ready(Some(Ok(info))),
+          Poll::Ready(Some(result)) => {
+            // A fetch completed, so a queued import can take its place.
+            inner.start_queued();
+            Poll::Ready(Some(result))
+          }
           Poll::Pending => Poll::Pending,
         }
       }
@@ -283,6 +321,45 @@ pub struct ModuleInfo {
   pub import_specifiers: Vec<ModuleSpecifier>,
 }
 
+/// A registered module and its place in the module graph, as returned by
+/// `EsIsolate::module_graph()`.
+#[derive(Debug)]
+pub struct ModuleGraphEntry {
+  pub id: ModuleId,
+  pub name: String,
+  pub main: bool,
+  /// The specifiers imported by the module as written in its source, each
+  /// with the id of the module it resolves to if that module has been
+  /// registered already.
+  pub dependencies: Vec<(String, Option<ModuleId>)>,
+  pub status: v8::ModuleStatus,
+}
+
+impl ModuleGraphEntry {
+  pub fn to_json(&self) -> serde_json::Value {
+    let status = match self.status {
+      v8::ModuleStatus::Uninstantiated => "uninstantiated",
+      v8::ModuleStatus::Instantiating => "instantiating",
+      v8::ModuleStatus::Instantiated => "instantiated",
+      v8::ModuleStatus::Evaluating => "evaluating",
+      v8::ModuleStatus::Evaluated => "evaluated",
+      v8::ModuleStatus::Errored => "errored",
+    };
+    let dependencies: Vec<_> = self
+      .dependencies
+      .iter()
+      .map(|(specifier, id)| json!({ "specifier": specifier, "id": id }))
+      .collect();
+    json!({
+      "id": self.id,
+      "name": self.name,
+      "main": self.main,
+      "status": status,
+      "dependencies": dependencies,
+    })
+  }
+}
+
 /// A symbolic module entity.
 enum SymbolicModule {
   /// This module is an alias to another module.
@@ -695,6 +772,39 @@ mod tests {
     if (import.meta.url != 'file:///d.js') throw Error();
   "#;
 
+  #[test]
+  fn test_max_concurrent_loads() {
+    let loader = MockLoader::new();
+    let loads = loader.loads.clone();
+    let mut load = RecursiveModuleLoad::main("/a.js", None, Rc::new(loader))
+      .with_max_concurrent_loads(NonZeroUsize::new(1));
+    let referrer = ModuleSpecifier::resolve_url("file:///a.js").unwrap();
+    for name in &["file:///b.js", "file:///c.js", "file:///b.js"] {
+      let specifier = ModuleSpecifier::resolve_url(name).unwrap();
+      load.add_import(specifier, referrer.clone());
+    }
+    assert_eq!(load.pending.len(), 1);
+    assert_eq!(loads.lock().unwrap().to_vec(), vec!["file:///b.js"]);
+
+    let loader = MockLoader::new();
+    let loads = loader.loads.clone();
+    let mut isolate = EsIsolate::new(Rc::new(loader), StartupData::None, false);
+    isolate.set_max_concurrent_module_loads(NonZeroUsize::new(1));
+    let spec = ModuleSpecifier::resolve_url("file:///a.js").unwrap();
+    let a_id_fut = isolate.load_module(&spec, None);
+    let a_id = futures::executor::block_on(a_id_fut).expect("Failed to load");
+    js_check(isolate.mod_evaluate(a_id));
+    assert_eq!(
+      loads.lock().unwrap().to_vec(),
+      vec![
+        "file:///a.js",
+        "file:///b.js",
+        "file:///c.js",
+        "file:///d.js"
+      ]
+    );
+  }
+
   #[test]
   fn test_recursive_load() {
     let loader = MockLoader::new();
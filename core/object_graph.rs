@@ -0,0 +1,137 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+// Helpers for walking the graph of objects reachable from a JavaScript value,
+// used by bindings such as Deno.core.hasCycle() and Deno.core.deepFreeze().
+
+use rusty_v8 as v8;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// A set of objects compared by identity. Objects are bucketed by their V8
+/// identity hash, which is stable for the lifetime of the object but not
+/// unique, so members of a bucket are compared with `strict_equals()`.
+#[derive(Default)]
+pub struct IdentitySet<'s> {
+  buckets: HashMap<i32, Vec<v8::Local<'s, v8::Object>>>,
+}
+
+impl<'s> IdentitySet<'s> {
+  /// Returns false if the object was already in the set.
+  pub fn insert(&mut self, object: v8::Local<'s, v8::Object>) -> bool {
+    let bucket = self.buckets.entry(object.get_identity_hash()).or_default();
+    if bucket.iter().any(|o| o.strict_equals(object.into())) {
+      return false;
+    }
+    bucket.push(object);
+    true
+  }
+
+  pub fn contains(&self, object: v8::Local<'s, v8::Object>) -> bool {
+    match self.buckets.get(&object.get_identity_hash()) {
+      Some(bucket) => bucket.iter().any(|o| o.strict_equals(object.into())),
+      None => false,
+    }
+  }
+
+  pub fn remove(&mut self, object: v8::Local<'s, v8::Object>) {
+    if let Some(bucket) = self.buckets.get_mut(&object.get_identity_hash()) {
+      bucket.retain(|o| !o.strict_equals(object.into()));
+    }
+  }
+}
+
+struct Frame<'s> {
+  object: v8::Local<'s, v8::Object>,
+  keys: v8::Local<'s, v8::Array>,
+  next_key: u32,
+  /// The property key through which this object was reached, `None` for
+  /// the root.
+  key: Option<v8::Local<'s, v8::Value>>,
+}
+
+/// Walks every object reachable from `root` through own enumerable properties
+/// in depth-first order, calling `visit` exactly once per object. The walk is
+/// iterative, so deeply nested graphs do not overflow the native stack.
+///
+/// Returns the property keys leading from `root` to the first property that
+/// points back at one of its ancestors, or `Ok(None)` if the graph is acyclic.
+/// When `stop_at_cycle` is set the walk ends as soon as a cycle is found.
+///
+/// Returns `Err(())` if `visit` fails or a property getter throws; an
+/// exception is pending in that case.
+#[allow(clippy::type_complexity)]
+pub fn walk<'s>(
+  scope: &mut v8::HandleScope<'s>,
+  root: v8::Local<'s, v8::Value>,
+  stop_at_cycle: bool,
+  visit: &mut dyn FnMut(
+    &mut v8::HandleScope<'s>,
+    v8::Local<'s, v8::Object>,
+  ) -> Result<(), ()>,
+) -> Result<Option<Vec<v8::Local<'s, v8::Value>>>, ()> {
+  let root = match v8::Local::<v8::Object>::try_from(root) {
+    Ok(root) => root,
+    Err(_) => return Ok(None),
+  };
+
+  let mut visited = IdentitySet::default();
+  let mut ancestors = IdentitySet::default();
+  let mut stack: Vec<Frame<'s>> = vec![];
+  let mut cycle_path = None;
+
+  visited.insert(root);
+  visit(scope, root)?;
+  ancestors.insert(root);
+  let keys = root.get_own_property_names(scope).ok_or(())?;
+  stack.push(Frame {
+    object: root,
+    keys,
+    next_key: 0,
+    key: None,
+  });
+
+  while let Some(frame) = stack.last_mut() {
+    if frame.next_key >= frame.keys.length() {
+      let done = stack.pop().unwrap();
+      ancestors.remove(done.object);
+      continue;
+    }
+
+    let object = frame.object;
+    let index = v8::Integer::new(scope, frame.next_key as i32);
+    frame.next_key += 1;
+    let key = frame.keys.get(scope, index.into()).ok_or(())?;
+    let value = object.get(scope, key).ok_or(())?;
+    let child = match v8::Local::<v8::Object>::try_from(value) {
+      Ok(child) => child,
+      Err(_) => continue,
+    };
+
+    if ancestors.contains(child) {
+      if cycle_path.is_none() {
+        let mut path: Vec<_> = stack.iter().filter_map(|f| f.key).collect();
+        path.push(key);
+        cycle_path = Some(path);
+        if stop_at_cycle {
+          break;
+        }
+      }
+      continue;
+    }
+    if !visited.insert(child) {
+      continue;
+    }
+
+    visit(scope, child)?;
+    ancestors.insert(child);
+    let keys = child.get_own_property_names(scope).ok_or(())?;
+    stack.push(Frame {
+      object: child,
+      keys,
+      next_key: 0,
+      key: Some(key),
+    });
+  }
+
+  Ok(cycle_path)
+}
@@ -2,6 +2,7 @@
 use crate::core_isolate::CoreIsolateState;
 use crate::ZeroCopyBuf;
 use futures::Future;
+use serde_json::json;
 use std::collections::HashMap;
 use std::pin::Pin;
 use std::rc::Rc;
@@ -24,10 +25,38 @@ pub enum Op {
 pub type OpDispatcher =
   dyn Fn(&mut CoreIsolateState, &mut [ZeroCopyBuf]) -> Op + 'static;
 
+/// What an op interceptor decides to do with a dispatched op.
+pub enum OpDecision {
+  /// Pass the op on to the next interceptor, or to its dispatcher if this was
+  /// the last one.
+  Continue,
+  /// Skip the dispatcher and throw a `TypeError` with the given message in
+  /// JavaScript instead.
+  Deny(String),
+  /// Skip the dispatcher and complete the op with the given `Op` instead.
+  Respond(Op),
+}
+
+/// Called with the op id and the zero copy buffers (the first one being the
+/// control buffer, if any) before an op is dispatched.
+pub type OpInterceptor =
+  dyn Fn(&mut CoreIsolateState, OpId, &[ZeroCopyBuf]) -> OpDecision + 'static;
+
+/// Describes an op for tooling, e.g. to generate typed client wrappers or
+/// documentation. Metadata is never consulted when an op is dispatched.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpMetadata {
+  /// Free-form description of the op's arguments, e.g. a JSON schema.
+  pub args: Option<serde_json::Value>,
+  pub is_async: bool,
+  pub category: Option<String>,
+}
+
 #[derive(Default)]
 pub struct OpRegistry {
   dispatchers: Vec<Rc<OpDispatcher>>,
   name_to_id: HashMap<String, OpId>,
+  metadata: HashMap<OpId, OpMetadata>,
 }
 
 impl OpRegistry {
@@ -56,17 +85,60 @@ impl OpRegistry {
     op_id
   }
 
+  pub fn register_with_meta<F>(
+    &mut self,
+    name: &str,
+    meta: OpMetadata,
+    op: F,
+  ) -> OpId
+  where
+    F: Fn(&mut CoreIsolateState, &mut [ZeroCopyBuf]) -> Op + 'static,
+  {
+    let op_id = self.register(name, op);
+    self.metadata.insert(op_id, meta);
+    op_id
+  }
+
+  /// Returns the metadata of all ops registered with metadata, keyed by op
+  /// name.
+  pub fn meta_json(&self) -> serde_json::Value {
+    let mut map = serde_json::Map::new();
+    for (name, op_id) in &self.name_to_id {
+      if let Some(meta) = self.metadata.get(op_id) {
+        map.insert(
+          name.clone(),
+          json!({
+            "id": op_id,
+            "args": meta.args,
+            "async": meta.is_async,
+            "category": meta.category,
+          }),
+        );
+      }
+    }
+    serde_json::Value::Object(map)
+  }
+
   fn json_map(&self) -> Buf {
     let op_map_json = serde_json::to_string(&self.name_to_id).unwrap();
     op_map_json.as_bytes().to_owned().into_boxed_slice()
   }
 
+  pub fn name(&self, op_id: OpId) -> Option<&str> {
+    self
+      .name_to_id
+      .iter()
+      .find(|(_, id)| **id == op_id)
+      .map(|(name, _)| name.as_str())
+  }
+
   pub fn get(&self, op_id: OpId) -> Option<Rc<OpDispatcher>> {
     self.dispatchers.get(op_id as usize).map(Rc::clone)
   }
 
   pub fn unregister_op(&mut self, name: &str) {
     let id = self.name_to_id.remove(name).unwrap();
+    self.metadata.remove(&id);
     drop(self.dispatchers.remove(id as usize));
   }
 }
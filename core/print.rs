@@ -0,0 +1,223 @@
+// Copyright 2018-2020 the Deno authors. All rights reserved. MIT license.
+
+// Output path of Deno.core.print(). Text is transcoded to the isolate's
+// configured `PrintEncoding` before it is written, for embedders whose
+// terminals or pipes do not expect UTF-8, and optionally throttled by a
+// `PrintRateLimit` so runaway print loops cannot flood the host's logs.
+
+use std::borrow::Cow;
+use std::io::Write;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Byte encoding used when `Deno.core.print()` writes to stdout or stderr.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PrintEncoding {
+  /// Write UTF-8 unchanged. This is the default.
+  Utf8,
+  /// Write ISO-8859-1; characters outside of it are replaced with `?`.
+  Latin1,
+  /// Write 7-bit ASCII; characters outside of it are replaced with `?`.
+  Ascii,
+}
+
+impl Default for PrintEncoding {
+  fn default() -> Self {
+    Self::Utf8
+  }
+}
+
+const REPLACEMENT: u8 = b'?';
+
+pub fn encode(text: &str, encoding: PrintEncoding) -> Cow<[u8]> {
+  let max = match encoding {
+    PrintEncoding::Utf8 => return Cow::Borrowed(text.as_bytes()),
+    PrintEncoding::Latin1 => 0xff,
+    PrintEncoding::Ascii => 0x7f,
+  };
+  if text.is_ascii() {
+    return Cow::Borrowed(text.as_bytes());
+  }
+  let bytes = text
+    .chars()
+    .map(|c| {
+      if c as u32 <= max {
+        c as u8
+      } else {
+        REPLACEMENT
+      }
+    })
+    .collect();
+  Cow::Owned(bytes)
+}
+
+/// How often a notice is written while output is being suppressed.
+const NOTICE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Token bucket capping the number of bytes per second written by
+/// `Deno.core.print()`. Bursts of up to one second worth of bytes are allowed;
+/// output beyond that is dropped and reported with a `[N bytes suppressed]`
+/// notice, written once output is allowed again or at most once per second
+/// while it is not. A single print larger than one second worth of bytes is
+/// written when the bucket is full, and the bucket goes into debt for it.
+pub struct PrintRateLimit {
+  bytes_per_second: f64,
+  tokens: f64,
+  last_refill: Instant,
+  suppressed: usize,
+  last_notice: Instant,
+}
+
+impl PrintRateLimit {
+  pub fn new(bytes_per_second: usize) -> Self {
+    let now = Instant::now();
+    Self {
+      bytes_per_second: bytes_per_second as f64,
+      tokens: bytes_per_second as f64,
+      last_refill: now,
+      suppressed: 0,
+      last_notice: now,
+    }
+  }
+
+  fn admit(&mut self, len: usize, now: Instant) -> bool {
+    let elapsed = now.saturating_duration_since(self.last_refill);
+    self.last_refill = now;
+    self.tokens = (self.tokens + elapsed.as_secs_f64() * self.bytes_per_second)
+      .min(self.bytes_per_second);
+    let bucket_full = self.tokens >= self.bytes_per_second;
+    if self.tokens >= len as f64 || bucket_full {
+      self.tokens -= len as f64;
+      true
+    } else {
+      self.suppressed += len;
+      false
+    }
+  }
+
+  fn write_notice(
+    &mut self,
+    out: &mut dyn Write,
+    now: Instant,
+  ) -> std::io::Result<()> {
+    if self.suppressed > 0 {
+      writeln!(out, "[{} bytes suppressed]", self.suppressed)?;
+      self.suppressed = 0;
+      self.last_notice = now;
+    }
+    Ok(())
+  }
+}
+
+pub fn write(
+  out: &mut dyn Write,
+  text: &str,
+  encoding: PrintEncoding,
+  rate_limit: Option<&mut PrintRateLimit>,
+) -> std::io::Result<()> {
+  write_at(out, text, encoding, rate_limit, Instant::now())?;
+  out.flush()
+}
+
+fn write_at(
+  out: &mut dyn Write,
+  text: &str,
+  encoding: PrintEncoding,
+  rate_limit: Option<&mut PrintRateLimit>,
+  now: Instant,
+) -> std::io::Result<()> {
+  let bytes = encode(text, encoding);
+  let rate_limit = match rate_limit {
+    Some(rate_limit) => rate_limit,
+    None => return out.write_all(&bytes),
+  };
+  if rate_limit.admit(bytes.len(), now) {
+    rate_limit.write_notice(out, now)?;
+    out.write_all(&bytes)
+  } else if now.saturating_duration_since(rate_limit.last_notice)
+    >= NOTICE_INTERVAL
+  {
+    rate_limit.write_notice(out, now)
+  } else {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn utf8_passes_through() {
+    let text = "héllo ✓";
+    assert!(matches!(
+      encode(text, PrintEncoding::Utf8),
+      Cow::Borrowed(_)
+    ));
+    assert_eq!(encode(text, PrintEncoding::Utf8), text.as_bytes());
+  }
+
+  #[test]
+  fn transcodes_written_output() {
+    let mut out = Vec::new();
+    write(&mut out, "héllo ✓\n", PrintEncoding::Latin1, None).unwrap();
+    assert_eq!(out, b"h\xe9llo ?\n");
+
+    let mut out = Vec::new();
+    write(&mut out, "héllo ✓\n", PrintEncoding::Ascii, None).unwrap();
+    assert_eq!(out, b"h?llo ?\n");
+
+    let mut out = Vec::new();
+    write(&mut out, "plain\n", PrintEncoding::Ascii, None).unwrap();
+    assert_eq!(out, b"plain\n");
+  }
+
+  #[test]
+  fn rate_limit_suppresses_excess_output() {
+    let mut rate_limit = PrintRateLimit::new(10);
+    let start = rate_limit.last_refill;
+    let mut out = Vec::new();
+    let mut print = |text: &str, millis: u64, out: &mut Vec<u8>| {
+      let now = start + Duration::from_millis(millis);
+      write_at(out, text, PrintEncoding::Utf8, Some(&mut rate_limit), now)
+        .unwrap();
+    };
+
+    // The initial burst fits in the bucket, the rest is dropped.
+    print("0123456789", 0, &mut out);
+    print("abcde", 0, &mut out);
+    print("fghijklmno", 500, &mut out);
+    assert_eq!(out, b"0123456789");
+
+    // The bucket refills; output resumes after a notice.
+    out.clear();
+    print("pq", 800, &mut out);
+    assert_eq!(out, b"[15 bytes suppressed]\npq");
+  }
+
+  #[test]
+  fn rate_limit_admits_oversized_output() {
+    let mut rate_limit = PrintRateLimit::new(10);
+    let start = rate_limit.last_refill;
+    let mut out = Vec::new();
+    let mut print = |text: &str, millis: u64, out: &mut Vec<u8>| {
+      let now = start + Duration::from_millis(millis);
+      write_at(out, text, PrintEncoding::Utf8, Some(&mut rate_limit), now)
+        .unwrap();
+    };
+
+    // A print larger than the bucket is written while the bucket is full.
+    print("0123456789abcdefghijklmno", 0, &mut out);
+    assert_eq!(out, b"0123456789abcdefghijklmno");
+
+    // The debt is paid off before output resumes. After a second of
+    // suppression a notice is written.
+    out.clear();
+    print("abcde", 1000, &mut out);
+    assert_eq!(out, b"[5 bytes suppressed]\n");
+
+    out.clear();
+    print("12345", 2000, &mut out);
+    assert_eq!(out, b"12345");
+  }
+}
@@ -22,6 +22,11 @@ type ResourceMap = HashMap<ResourceId, (String, Box<dyn Resource>)>;
 pub struct ResourceTable {
   map: ResourceMap,
   next_id: u32,
+  /// Bytes held outside the V8 heap by resources added with
+  /// `add_with_external_memory()`.
+  external_memory: HashMap<ResourceId, usize>,
+  /// Change in external memory not yet reported to V8.
+  external_memory_change: i64,
 }
 
 impl ResourceTable {
@@ -59,6 +64,34 @@ impl ResourceTable {
     rid
   }
 
+  /// Same as `add()`, but the resource is reported to V8 as holding
+  /// `external_memory` bytes outside of the JS heap until it is closed or
+  /// removed, so that garbage collection is scheduled with that memory in
+  /// mind. Useful for resources backing large buffers that JS holds on to.
+  pub fn add_with_external_memory(
+    &mut self,
+    name: &str,
+    resource: Box<dyn Resource>,
+    external_memory: usize,
+  ) -> ResourceId {
+    let rid = self.add(name, resource);
+    self.external_memory.insert(rid, external_memory);
+    self.external_memory_change += external_memory as i64;
+    rid
+  }
+
+  /// Returns the change in external memory since the last call, to be passed
+  /// to V8's `adjust_amount_of_external_allocated_memory()`.
+  pub(crate) fn take_external_memory_change(&mut self) -> i64 {
+    std::mem::take(&mut self.external_memory_change)
+  }
+
+  fn release_external_memory(&mut self, rid: ResourceId) {
+    if let Some(external_memory) = self.external_memory.remove(&rid) {
+      self.external_memory_change -= external_memory as i64;
+    }
+  }
+
   pub fn entries(&self) -> Vec<(ResourceId, String)> {
     self
       .map
@@ -70,11 +103,13 @@ impl ResourceTable {
   // close(2) is done by dropping the value. Therefore we just need to remove
   // the resource from the resource table.
   pub fn close(&mut self, rid: ResourceId) -> Option<()> {
+    self.release_external_memory(rid);
     self.map.remove(&rid).map(|(_name, _resource)| ())
   }
 
   pub fn remove<T: Resource>(&mut self, rid: ResourceId) -> Option<Box<T>> {
     if let Some((_name, resource)) = self.map.remove(&rid) {
+      self.release_external_memory(rid);
       let res = match resource.downcast::<T>() {
         Ok(res) => Some(res),
         Err(_e) => None,
@@ -163,4 +198,25 @@ mod tests {
     assert_eq!(table.map.len(), 0);
     assert!(res2.is_some());
   }
+
+  #[test]
+  fn test_external_memory() {
+    let mut table = ResourceTable::default();
+    let rid1 = table.add_with_external_memory(
+      "fake1",
+      Box::new(FakeResource::new(1)),
+      10,
+    );
+    let rid2 = table.add_with_external_memory(
+      "fake2",
+      Box::new(FakeResource::new(2)),
+      5,
+    );
+    table.add("fake3", Box::new(FakeResource::new(3)));
+    assert_eq!(table.take_external_memory_change(), 15);
+    assert_eq!(table.take_external_memory_change(), 0);
+    table.close(rid1);
+    assert!(table.remove::<FakeResource>(rid2).is_some());
+    assert_eq!(table.take_external_memory_change(), -15);
+  }
 }
@@ -35,8 +35,20 @@ const HEAD_INIT: usize = 4 * INDEX_RECORDS;
 /// A rough guess at how big we should make the shared buffer in bytes.
 pub const RECOMMENDED_SIZE: usize = 128 * MAX_RECORDS;
 
+/// Counters kept by the shared queue, see `CoreIsolate::shared_queue_stats()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SharedQueueStats {
+  /// Responses delivered through the queue.
+  pub pushed: u64,
+  /// Times the queue was handed to JavaScript early because it was full.
+  pub full: u64,
+  /// Responses too large for the empty queue, delivered individually.
+  pub overflowed: u64,
+}
+
 pub struct SharedQueue {
   buf: v8::SharedRef<v8::BackingStore>,
+  stats: SharedQueueStats,
 }
 
 impl SharedQueue {
@@ -47,6 +59,7 @@ impl SharedQueue {
     let buf = v8::SharedArrayBuffer::new_backing_store_from_boxed_slice(buf);
     let mut q = Self {
       buf: buf.make_shared(),
+      stats: SharedQueueStats::default(),
     };
     q.reset();
     q
@@ -72,6 +85,18 @@ impl SharedQueue {
     }
   }
 
+  pub fn stats(&self) -> SharedQueueStats {
+    self.stats
+  }
+
+  pub(crate) fn record_full(&mut self) {
+    self.stats.full += 1;
+  }
+
+  pub(crate) fn record_overflow(&mut self) {
+    self.stats.overflowed += 1;
+  }
+
   fn reset(&mut self) {
     debug!("rust:shared_queue:reset");
     let s: &mut [u32] = self.as_u32_slice_mut();
@@ -205,6 +230,7 @@ impl SharedQueue {
     let u32_slice = self.as_u32_slice_mut();
     u32_slice[INDEX_NUM_RECORDS] += 1;
     u32_slice[INDEX_HEAD] = aligned_end as u32;
+    self.stats.pushed += 1;
     debug!(
       "rust:shared_queue:push: num_records={}, num_shifted_off={}, head={}",
       self.num_records(),